@@ -1,2 +1,4 @@
 pub mod agent;
 pub mod chat;
+pub mod config;
+pub mod model_selection;
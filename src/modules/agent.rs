@@ -1,19 +1,36 @@
 // src/modules/agent.rs
 pub mod api;
+pub mod approval;
+pub mod context;
+pub mod limits;
+#[cfg(test)]
+pub mod testing;
+pub mod tool_parser;
 pub mod tools;
 
-use crate::modules::agent::api::{AIApi, AIProvider, ApiError, ChatMessage, ChatRole};
+use crate::modules::agent::api::{
+    AIApi, AIProvider, ApiError, ChatMessage, ChatRole, ChatStreamChunk, GenerationOptions,
+    NativeToolCall, PullProgress, ResponseFormat, TokenUsage,
+};
+use crate::modules::agent::approval::{ApprovalConfig, ApprovalDecision, ApprovalPolicy, ApprovalRequest};
+use crate::modules::agent::context::{ContextConfig, ContextStrategy};
+use crate::modules::agent::limits::{LimitReached, LoopGuard, LoopLimits};
+use crate::modules::agent::tool_parser::{MalformedToolCall, ToolCallParser};
+use crate::modules::config::AppConfig;
 use anyhow::Result;
 use futures_util::stream::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::boxed::Box;
+use std::collections::{HashMap, HashSet};
 use std::pin::Pin;
+use std::time::Instant;
 
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-use tools::ToolManager;
+use tools::delegate::{DELEGATE_TOOL_NAME, DEFAULT_MAX_STEPS, DelegateRequest};
+use tools::{ToolError, ToolManager};
 
 // ログファイル保存のために追加
 use chrono::Local;
@@ -27,8 +44,35 @@ use std::path::PathBuf; // `dirs` クレートが必要です。Cargo.tomlに`di
 pub struct AiToolCall {
     pub tool_name: String,
     pub parameters: Value,
+    /// ネイティブのツール呼び出しのID (YAMLには書かれないため、同じ呼び出しの比較にも使わない)
+    #[serde(skip)]
+    pub id: Option<String>,
 }
 
+impl From<NativeToolCall> for AiToolCall {
+    fn from(call: NativeToolCall) -> Self {
+        AiToolCall {
+            tool_name: call.name,
+            parameters: call.arguments,
+            id: call.id,
+        }
+    }
+}
+
+impl AiToolCall {
+    /// 履歴に残すためのネイティブのツール呼び出しの形式に変換する
+    fn to_native(&self) -> NativeToolCall {
+        NativeToolCall {
+            id: self.id.clone(),
+            name: self.tool_name.clone(),
+            arguments: self.parameters.clone(),
+        }
+    }
+}
+
+/// エージェントのイベントのストリーム
+pub type AgentEventStream = Pin<Box<dyn Stream<Item = Result<AgentEvent, ApiError>> + Send>>;
+
 /// エージェントからチャットセッションに送られるイベントの種類
 // #[derive(Debug)] // デバッグ出力が冗長になるためコメントアウト
 #[allow(dead_code)] // 使用されていないバリアントがあっても警告を出さない
@@ -48,6 +92,20 @@ pub enum AgentEvent {
     ToolError(String, String), // tool_name, error_message
     /// AIが思考中であることを示すメッセージ
     Thinking(String),
+    /// 推論モデルの思考過程のチャンク (履歴には追加しない)
+    Reasoning(String),
+    /// 1回のAI応答で消費したトークン数と処理時間
+    Usage(TokenUsage),
+    /// フォールバックチェーンで実際に応答したバックエンド
+    BackendSelected(String, bool), // backend label, whether a fallback backend answered
+    /// モデルのダウンロードの進捗
+    PullProgress(PullProgress),
+    /// 応答が指定したJSONの形式に従わなかったため、モデルに再度回答させる (理由)
+    ResponseFormatMismatch(String),
+    /// コンテキスト長に収まるように履歴を縮めた (行った処理の説明)
+    ContextCompacted(String),
+    /// ツールループの上限に達したため、最終的な回答を待たずにターンを止めた (`/continue` で続行できる)
+    LimitReached(LimitReached),
     /// ユーザーメッセージが追加されたことを示す (UIでは特に表示しない)
     #[allow(dead_code)]
     UserMessageAdded,
@@ -56,84 +114,90 @@ pub enum AgentEvent {
     /// 通常のAIのテキストとして表示する保留中のコンテンツ (現在未使用)
     #[allow(dead_code)]
     PendingDisplayContent(String),
-    /// ツールブロックをパースできなかった場合の警告 (現在未使用)
+    /// `delegate` で起動した子エージェントのイベント
+    SubAgent(Box<AgentEvent>),
+    /// ツールを実行する前にユーザーの許可が必要 (UIは `ApprovalRequest::respond` で答える)
+    ApprovalRequired(ApprovalRequest),
+    /// ツールブロックをパースできなかったため、モデルに書き直させる (または諦める) ことの警告
     ToolBlockParseWarning(String),
-    /// YAMLツール呼び出しのパースに失敗したエラー (現在未使用、Noneを返しているため)
+    /// YAMLツール呼び出しのパースに失敗したエラー
     YamlParseError(String, String), // error message, yaml content
 }
 
-/// AIの応答からツール呼び出しを抽出・パースするヘルパー関数。
-/// `---` または ` ``` ` で囲まれたブロックを検索します。
-/// ブロックに `tool_call` という言語指定子があるか、ブロックの内容に `tool_name:` または `tool_call:` が含まれている場合に、
-/// そのブロックをツール呼び出しの候補と見なします。
-/// YAMLのパースは、`tool_call`キーを持つオブジェクトと、持たないオブジェクトの両方の形式に対応しています。
-fn extract_tool_call_from_response(response_content: &str) -> Option<(AiToolCall, String)> {
-    let lines: Vec<&str> = response_content.lines().collect();
-    let mut i = 0;
-
-    while i < lines.len() {
-        let trimmed_line = lines[i].trim();
-
-        // 開始マーカーの検出: "---" または "```"
-        let is_start_marker = trimmed_line.starts_with("---") || trimmed_line.starts_with("```");
-
-        if is_start_marker {
-            let mut block_content = String::new();
-            let mut current_line_idx = i + 1;
+/// 応答がJSONの形式に従わなかった場合に再プロンプトする最大回数
+const MAX_FORMAT_RETRIES: usize = 2;
+/// ツール呼び出しのブロックをパースできなかった場合に書き直させる最大回数
+const MAX_TOOL_CALL_CORRECTIONS: usize = 2;
 
-            // 開始マーカーの言語指定子を確認
-            let lang_specifier = if trimmed_line.starts_with("---") {
-                trimmed_line.strip_prefix("---").unwrap_or("").trim()
-            } else {
-                trimmed_line.strip_prefix("```").unwrap_or("").trim()
-            };
-            // `tool_call`で始まる、または`yaml`,`json`と完全一致する場合にフラグを立てる
-            let is_explicit_tool_call = lang_specifier.starts_with("tool_call") || lang_specifier == "yaml" || lang_specifier == "json";
-
-            // ブロックの本体を抽出
-            while current_line_idx < lines.len() {
-                let inner_line = lines[current_line_idx];
-                let inner_trimmed_line = inner_line.trim();
-
-                // 終了マーカーの検出
-                if inner_trimmed_line == "---" || inner_trimmed_line == "```" {
-                    // ブロックがツール呼び出しかどうかを判断
-                    // 明示的な指定、または内容に `tool_name:` か `tool_call:` が含まれる場合
-                    let is_potential_tool_call = is_explicit_tool_call
-                        || block_content.contains("tool_name:")
-                        || block_content.contains("tool_call:");
-
-                    if is_potential_tool_call {
-                        // ツール呼び出しのパースを試みる
-                        // 最初に {"tool_call": ...} の形式を試す
-                        #[derive(Debug, Deserialize)]
-                        struct ToolCallWrapper {
-                            tool_call: AiToolCall,
-                        }
-
-                        let call = serde_yaml::from_str::<ToolCallWrapper>(&block_content)
-                            .map(|wrapper| wrapper.tool_call)
-                            .or_else(|_| serde_yaml::from_str::<AiToolCall>(&block_content))
-                            .ok();
-
-                        if let Some(parsed_call) = call {
-                            // パース成功
-                            let pre_tool_content = lines[0..i].join("\n");
-                            return Some((parsed_call, pre_tool_content));
-                        }
+/// ネイティブのツール呼び出しを使わないリクエストのために、ツールの結果をユーザーメッセージに変換する。
+/// 連続するツールの結果は1つのメッセージにまとめ、ツール呼び出しの記録は取り除く
+/// (モデルが書いたYAMLのブロックは履歴に残していないため、呼び出しだけのメッセージは送らない)
+fn tool_messages_as_text(messages: &[ChatMessage]) -> Vec<ChatMessage> {
+    let mut converted: Vec<ChatMessage> = Vec::with_capacity(messages.len());
+    let mut previous_was_tool = false;
+    for message in messages {
+        match message.role {
+            ChatRole::Tool => {
+                match converted.last_mut() {
+                    Some(last) if previous_was_tool => {
+                        last.content.push_str("\n\n");
+                        last.content.push_str(&tool_result_text(message));
                     }
-                    // パース失敗またはツール呼び出しではない場合、このブロックは無視して次の行から検索を続ける
-                    break;
+                    _ => converted.push(ChatMessage::user(tool_result_text(message))),
                 }
-
-                block_content.push_str(inner_line);
-                block_content.push('\n');
-                current_line_idx += 1;
+                previous_was_tool = true;
+                continue;
             }
+            ChatRole::Assistant if !message.tool_calls.is_empty() && message.content.is_empty() => {}
+            _ => converted.push(ChatMessage {
+                tool_calls: Vec::new(),
+                ..message.clone()
+            }),
         }
-        i += 1;
+        previous_was_tool = false;
     }
-    None
+    converted
+}
+
+/// `tool` ロールのメッセージを、YAMLプロトコルでツールの結果を返すテキストにする。
+/// 内容が `{"error": ...}` だけのオブジェクトであれば、ツールのエラーとして返す
+fn tool_result_text(message: &ChatMessage) -> String {
+    let tool_name = message.tool_call.as_ref().map(|call| call.name.as_str()).unwrap_or_default();
+    // 省略や切り詰めでJSONでなくなった内容は文字列のまま返す
+    let output: Value =
+        serde_json::from_str(&message.content).unwrap_or_else(|_| Value::String(message.content.clone()));
+    let error = output
+        .as_object()
+        .filter(|object| object.len() == 1)
+        .and_then(|object| object.get("error"));
+    let (header, body) = match error {
+        Some(error) => (
+            format!("Error from tool '{}'", tool_name),
+            serde_json::json!({ "tool_error": { "tool_name": tool_name, "error": error } }),
+        ),
+        None => (
+            format!("Tool result for '{}'", tool_name),
+            serde_json::json!({ "tool_result": { "tool_name": tool_name, "result": output } }),
+        ),
+    };
+    let body_yaml = serde_yaml::to_string(&body).unwrap_or_else(|_| "Failed to serialize tool result.".to_string());
+    format!("{}:\n---\n{}\n---", header, body_yaml)
+}
+
+/// パースできなかったツール呼び出しのブロックを、理由を添えてモデルに伝えるメッセージ
+fn tool_call_correction_message(malformed: &[MalformedToolCall]) -> String {
+    let errors: Vec<Value> = malformed
+        .iter()
+        .map(|call| serde_json::json!({ "error": call.error, "block": call.block }))
+        .collect();
+    let errors_yaml = serde_yaml::to_string(&serde_json::json!({ "tool_call_errors": errors }))
+        .unwrap_or_else(|_| "Failed to serialize tool call errors.".to_string());
+    format!(
+        "Some of your tool call blocks could not be parsed, so those tools were not run.\n---\n{}---\n\
+        Write each of them again as a ```yaml tool block containing `tool_call:` with `tool_name` and `parameters` (a mapping), \
+        or answer without tools.",
+        errors_yaml
+    )
 }
 
 /// AIエージェントのメイン構造体
@@ -143,69 +207,200 @@ pub struct AIAgent {
     pub tool_manager: ToolManager,   // ツール管理
     default_prompt_template: String, // デフォルトのシステムプロンプトテンプレート
     log_file_path: Option<PathBuf>,  // ログファイルのパス
+    native_tool_support: HashMap<String, bool>, // モデルごとのネイティブツール呼び出し対応状況
+    response_format: Option<ResponseFormat>,    // 最終的な応答に求める形式 (`/json`)
+    context_config: ContextConfig,              // コンテキスト長の管理方法
+    context_lengths: HashMap<String, Option<usize>>, // モデルごとのコンテキスト長
+    loop_limits: LoopLimits,                         // 1ターンのツールループの上限
+    tool_approval: ApprovalConfig,                   // ツールを実行する前に許可を求めるかの方針
+    approved_tools: HashSet<String>,                 // このセッションでは確認せずに実行するツール
 }
 
 impl AIAgent {
     /// 新しいAIAgentインスタンスを作成
-    pub fn new(provider: AIProvider, base_url: String, default_model: String) -> Self {
-        let api = AIApi::new(provider, base_url, default_model);
-        let mut tool_manager = ToolManager::new();
+    pub fn new(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+        config: &AppConfig,
+    ) -> Self {
+        Self::build(
+            provider,
+            base_url,
+            default_model,
+            config,
+            Self::default_tool_manager(),
+            Self::initialize_log_file(),
+        )
+    }
 
-        // 利用可能なツールを登録
-        tool_manager.register_tool(tools::shell::ShellTool);
-        tool_manager.register_tool(tools::www::search::SearchEngineTool);
-        tool_manager.register_tool(tools::www::browse::WebPageBrowser);
-        tool_manager.register_tool(tools::files::info::InfoTool);
-        tool_manager.register_tool(tools::files::read::ReadTool);
-        tool_manager.register_tool(tools::files::write::WriteTool);
-        tool_manager.register_tool(tools::utils::weather::WeatherTool);
+    /// 使うツールとログファイルを指定してAIAgentを作成する (`log_file_path` が `None` ならログを書かない)
+    fn build(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+        config: &AppConfig,
+        tool_manager: ToolManager,
+        log_file_path: Option<PathBuf>,
+    ) -> Self {
+        // 設定ファイルに予備のバックエンドがあればフォールバックチェーンを構成する
+        let mut api = AIApi::with_fallbacks(provider, base_url, default_model, &config.fallback);
+        if let Some(dir) = &config.record {
+            api = api.recording(dir.clone());
+        }
+        api.set_generation_options(config.generation.clone());
+        api.set_network_config(config.network.clone());
+        api.set_embedding_model(config.embedding_model.clone());
 
         // デフォルトのプロンプトテンプレートを読み込む
         let default_prompt_template = include_str!("default-prompt.md").to_string();
 
-        // ログファイルを初期化
-        let log_file_path = Self::initialize_log_file();
-
         let mut agent = AIAgent {
             api,
             messages: vec![],
             tool_manager,
             default_prompt_template,
             log_file_path,
+            native_tool_support: HashMap::new(),
+            response_format: None,
+            context_config: config.context.clone(),
+            context_lengths: HashMap::new(),
+            loop_limits: config.limits.clone(),
+            tool_approval: config.tool_approval.clone(),
+            approved_tools: HashSet::new(),
         };
 
         // システムプロンプトを初期化時に追加
-        let tool_manager_schemas = agent.tool_manager.get_tool_yaml_schemas();
-        let formatted_prompt = agent.default_prompt_template.replace(
-            "{{TOOLS_YAML_SCHEMA}}",
-            &serde_yaml::to_string(&tool_manager_schemas).unwrap_or_default(),
-        );
+        agent.add_system_prompt();
+
+        // Add introductory messages
+        agent.add_message_to_history(ChatMessage::system(format!("Default Model: {}", agent.api.get_model())));
+        agent.add_message_to_history(ChatMessage::system("AI Integration Chat Session"));
+        agent.add_message_to_history(ChatMessage::system("Type '/help' for commands. Press '!' for shell mode."));
+        agent.add_message_to_history(ChatMessage::system("While AI is replying: Ctrl+C to cancel, Esc to quit."));
+
+        agent
+    }
 
-        let system_message = ChatMessage {
-            role: ChatRole::System,
-            content: formatted_prompt,
+    /// 利用可能なツールをすべて登録したToolManagerを作成
+    fn default_tool_manager() -> ToolManager {
+        let mut tool_manager = ToolManager::new();
+        tool_manager.register_tool(tools::shell::ShellTool);
+        tool_manager.register_tool(tools::www::search::SearchEngineTool);
+        tool_manager.register_tool(tools::www::browse::WebPageBrowser);
+        tool_manager.register_tool(tools::files::info::InfoTool);
+        tool_manager.register_tool(tools::files::read::ReadTool);
+        tool_manager.register_tool(tools::files::write::WriteTool);
+        tool_manager.register_tool(tools::utils::weather::WeatherTool);
+        tool_manager.register_tool(tools::delegate::DelegateTool);
+        tool_manager
+    }
+
+    /// 登録されているツールのスキーマを埋め込んだシステムプロンプトを履歴に追加
+    fn add_system_prompt(&mut self) {
+        let formatted_prompt = self.system_prompt(false);
+        self.add_message_to_history(ChatMessage::system(formatted_prompt));
+    }
+
+    /// システムプロンプトを作る。ネイティブのツール呼び出しを使う場合は、
+    /// ツール定義をリクエストの `tools` で送るため、YAMLのスキーマを埋め込まない
+    fn system_prompt(&self, native_tools: bool) -> String {
+        let tool_schemas = if native_tools {
+            "ツールの定義はAPIのツール呼び出し機能で渡しています。".to_string()
+        } else {
+            serde_yaml::to_string(&self.tool_manager.get_tool_yaml_schemas()).unwrap_or_default()
         };
-        agent.add_message_to_history(system_message);
+        self.default_prompt_template.replace("{{TOOLS_YAML_SCHEMA}}", &tool_schemas)
+    }
 
-        // Add introductory messages
-        agent.add_message_to_history(ChatMessage {
-            role: ChatRole::System,
-            content: format!("Default Model: {}", agent.api.get_model()),
-        });
-        agent.add_message_to_history(ChatMessage {
-            role: ChatRole::System,
-            content: "AI Integration Chat Session".to_string(),
-        });
-        agent.add_message_to_history(ChatMessage {
-            role: ChatRole::System,
-            content: "Type '/help' for commands. Press '!' for shell mode.".to_string(),
-        });
-        agent.add_message_to_history(ChatMessage {
-            role: ChatRole::System,
-            content: "While AI is replying: Ctrl+C to cancel, Esc to quit.".to_string(),
+    /// 送信するメッセージのシステムプロンプトを、ネイティブのツール呼び出しを使うかどうかに合わせたものに置き換える
+    fn with_tools_prompt(&self, messages: &[ChatMessage], native_tools: bool) -> Vec<ChatMessage> {
+        let prompts = [self.system_prompt(false), self.system_prompt(true)];
+        let prompt = &prompts[usize::from(native_tools)];
+        messages
+            .iter()
+            .map(|message| {
+                if message.role == ChatRole::System && prompts.contains(&message.content) {
+                    ChatMessage::system(prompt.clone())
+                } else {
+                    message.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// `delegate` の呼び出しを実行する子エージェントを作成する。
+    /// 子エージェントは自分の履歴を持ち、親のツールのうち指定されたもの (`delegate` を除く) だけを使える。
+    /// ツールを実行できる回数は `max_steps` で、親の上限を超えない
+    fn sub_agent(&self, request: &DelegateRequest) -> Result<AIAgent, ToolError> {
+        let available: Vec<String> = self
+            .tool_manager
+            .tool_names()
+            .into_iter()
+            .filter(|name| name != DELEGATE_TOOL_NAME)
+            .collect();
+        if let Some(unknown) = request
+            .tools
+            .iter()
+            .flatten()
+            .find(|name| !available.contains(name))
+        {
+            return Err(ToolError::NotFound(format!(
+                "Tool '{}' cannot be given to a sub-agent. Available tools are: [{}]",
+                unknown,
+                available.join(", ")
+            )));
+        }
+        let mut tool_manager = self.tool_manager.clone();
+        tool_manager.retain(|name| {
+            available.iter().any(|tool| tool == name)
+                && request.tools.as_ref().is_none_or(|tools| tools.iter().any(|tool| tool == name))
         });
 
-        agent
+        let mut api = self.api.clone();
+        if let Some(model) = &request.model {
+            api.set_model(model.clone());
+        }
+        let max_steps = request.max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+        let mut sub_agent = AIAgent {
+            api,
+            messages: vec![],
+            tool_manager,
+            default_prompt_template: self.default_prompt_template.clone(),
+            log_file_path: self.log_file_path.clone(),
+            native_tool_support: self.native_tool_support.clone(),
+            response_format: None,
+            context_config: self.context_config.clone(),
+            context_lengths: self.context_lengths.clone(),
+            loop_limits: LoopLimits {
+                max_tool_iterations: Some(
+                    self.loop_limits
+                        .max_tool_iterations
+                        .map_or(max_steps, |max| max.min(max_steps)),
+                ),
+                ..self.loop_limits.clone()
+            },
+            tool_approval: self.tool_approval.clone(),
+            approved_tools: self.approved_tools.clone(),
+        };
+        sub_agent.add_system_prompt();
+        sub_agent.add_message_to_history(ChatMessage::user(request.prompt()));
+        Ok(sub_agent)
+    }
+
+    /// 子エージェントの最後の応答を、親に返すツールの結果にする
+    fn sub_agent_result(&self, stopped_by: Option<String>) -> Value {
+        let summary = match self.messages.last() {
+            Some(message) if message.role == ChatRole::Assistant && message.tool_calls.is_empty() => {
+                message.content.clone()
+            }
+            _ => "The sub-agent stopped before writing a summary.".to_string(),
+        };
+        let mut result = serde_json::json!({ "summary": summary });
+        if let Some(reason) = stopped_by {
+            result["stopped"] = Value::String(reason);
+        }
+        result
     }
 
     /// ログファイルの初期化とパス生成
@@ -237,12 +432,17 @@ impl AIAgent {
     fn write_message_to_log(&self, message: &ChatMessage) {
         if let Some(ref path) = self.log_file_path {
             // ログエントリのフォーマット: [タイムスタンプ] ロール: コンテンツ
-            let log_entry = format!(
-                "[{}] {}: {}\n---\n", // 各メッセージの終わりに "---" を追加して区切りを明確にする
+            let mut log_entry = format!(
+                "[{}] {}: {}\n",
                 Local::now().format("%Y-%m-%d %H:%M:%S"),
                 message.role,
                 message.content
             );
+            // 画像はデータを書かず、ファイル名だけを記録する
+            for attachment in &message.attachments {
+                log_entry.push_str(&format!("[attachment: {} ({})]\n", attachment.name, attachment.mime_type));
+            }
+            log_entry.push_str("---\n"); // 各メッセージの終わりに "---" を追加して区切りを明確にする
             // ファイルを追記モードで開き、存在しない場合は作成
             match OpenOptions::new().create(true).append(true).open(path) {
                 Ok(mut file) => {
@@ -257,16 +457,93 @@ impl AIAgent {
         }
     }
 
+    /// 1回のAI応答のトークン使用量をログファイルに書き込む
+    fn write_usage_to_log(&self, usage: &TokenUsage) {
+        if let Some(ref path) = self.log_file_path {
+            let log_entry = format!(
+                "[{}] Usage ({}): {}\n---\n",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                self.api.get_model(),
+                usage
+            );
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(mut file) => {
+                    if let Err(e) = file.write_all(log_entry.as_bytes()) {
+                        eprintln!("Failed to write to log file {}: {}", path.display(), e);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to open log file {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
     /// エージェントの内部APIを通じてモデルを設定する公開メソッド
     pub fn set_model(&mut self, model_name: String) {
         self.api.set_model(model_name);
     }
 
+    /// 生成パラメータを設定する
+    pub fn set_generation_options(&mut self, options: GenerationOptions) {
+        // num_ctx が変わるとコンテキスト長も変わる
+        self.context_lengths.clear();
+        self.api.set_generation_options(options);
+    }
+
+    /// 現在の生成パラメータを取得する
+    pub fn get_generation_options(&self) -> GenerationOptions {
+        self.api.get_generation_options()
+    }
+
+    /// 応答の形式を指定する。`None` で通常のテキストに戻す
+    pub fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.api.set_response_format(format.clone());
+        self.response_format = format;
+    }
+
+    /// 現在指定されている応答の形式を取得する
+    pub fn get_response_format(&self) -> Option<ResponseFormat> {
+        self.response_format.clone()
+    }
+
     /// 利用可能なモデルをリストアップ
     pub async fn list_available_models(&self) -> Result<serde_json::Value, ApiError> {
         self.api.list_models().await
     }
 
+    /// テキストの埋め込みベクトルを、チャットと同じプロバイダ設定で計算する
+    #[allow(dead_code)] // 検索などの機能からライブラリとして使う
+    pub async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        self.api.embed(texts).await
+    }
+
+    /// モデルをダウンロードし、進捗をイベントのストリームで返す
+    pub async fn pull_model(
+        &self,
+        name: &str,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<AgentEvent, ApiError>> + Send>>, ApiError> {
+        let stream = self.api.pull_model(name).await?;
+        Ok(stream
+            .map(|progress| progress.map(AgentEvent::PullProgress))
+            .boxed())
+    }
+
+    /// ローカルのモデルを削除する
+    pub async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.api.delete_model(name).await
+    }
+
+    /// ローカルのモデルを別名で複製する
+    pub async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.api.copy_model(source, destination).await
+    }
+
+    /// モデルの詳細を取得する
+    pub async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        self.api.show_model(name).await
+    }
+
     /// Get the configured log file path
     pub fn get_log_path(&self) -> Option<String> {
         self.log_file_path.as_ref().map(|p| p.to_string_lossy().to_string())
@@ -284,17 +561,252 @@ impl AIAgent {
         }
     }
 
+    /// 現在のモデルがネイティブのツール呼び出しに対応している場合、APIに渡すツール定義を返す。
+    /// 対応状況はモデルごとにキャッシュし、非対応の場合は `None` (YAMLプロトコルを使用) を返す。
+    async fn native_tool_definitions(self_arc_mutex: &Arc<Mutex<Self>>) -> Option<Vec<Value>> {
+        let (api, cached) = {
+            let agent_locked = self_arc_mutex.lock().await;
+            let model = agent_locked.api.get_model();
+            (
+                agent_locked.api.clone(),
+                agent_locked.native_tool_support.get(&model).copied(),
+            )
+        };
+
+        let supported = match cached {
+            Some(supported) => supported,
+            None => {
+                // 問い合わせ中はロックを保持しない
+                let supported = api.supports_native_tools().await;
+                let mut agent_locked = self_arc_mutex.lock().await;
+                agent_locked
+                    .native_tool_support
+                    .insert(api.get_model(), supported);
+                supported
+            }
+        };
+
+        if supported {
+            let agent_locked = self_arc_mutex.lock().await;
+            Some(agent_locked.tool_manager.get_tool_definitions())
+        } else {
+            None
+        }
+    }
+
+    /// 現在のモデルのコンテキスト長。設定ファイルの指定があればそれを使い、無ければモデルに問い合わせる
+    async fn context_length(self_arc_mutex: &Arc<Mutex<Self>>) -> Option<usize> {
+        let (api, configured, cached) = {
+            let agent_locked = self_arc_mutex.lock().await;
+            let model = agent_locked.api.get_model();
+            (
+                agent_locked.api.clone(),
+                agent_locked.context_config.max_context_tokens,
+                agent_locked.context_lengths.get(&model).copied(),
+            )
+        };
+        if configured.is_some() {
+            return configured;
+        }
+        if let Some(cached) = cached {
+            return cached;
+        }
+
+        // 問い合わせ中はロックを保持しない
+        let length = api.context_length().await;
+        let mut agent_locked = self_arc_mutex.lock().await;
+        agent_locked.context_lengths.insert(api.get_model(), length);
+        length
+    }
+
+    /// 送信するメッセージがコンテキスト長に収まらない場合、設定された手順で `messages` を縮める。
+    /// 縮めるのは送信する分だけで、履歴はそのまま残す (`persist_summaries` が有効なら要約だけを履歴にも反映する)。
+    /// システムプロンプトと最後のメッセージは必ず残す。縮めた場合はその説明を返す
+    async fn fit_context(
+        self_arc_mutex: &Arc<Mutex<Self>>,
+        messages: &mut Vec<ChatMessage>,
+        native_tools: &Option<Vec<Value>>,
+    ) -> Option<String> {
+        let context_length = Self::context_length(self_arc_mutex).await?;
+        let (api, config) = {
+            let agent_locked = self_arc_mutex.lock().await;
+            (agent_locked.api.clone(), agent_locked.context_config.clone())
+        };
+
+        // 応答とツール定義の分を空けておく
+        let reserve = api
+            .get_generation_options()
+            .max_tokens
+            .map(|tokens| tokens as usize)
+            .unwrap_or(config.reserve_tokens);
+        let tool_tokens = native_tools
+            .as_ref()
+            .map(|tools| context::estimate_tokens(&Value::from(tools.clone()).to_string()))
+            .unwrap_or(0);
+        let budget = context_length.saturating_sub(reserve + tool_tokens);
+        let before = context::estimate_messages(messages);
+        if before <= budget {
+            return None;
+        }
+
+        // 要約を履歴に反映する前に、要約している間に履歴が変わっていないかを確かめるため
+        let original = messages.clone();
+        let mut actions = Vec::new();
+        for strategy in &config.strategies {
+            if context::estimate_messages(messages) <= budget {
+                break;
+            }
+            match strategy {
+                ContextStrategy::TruncateToolOutputs => {
+                    let count =
+                        context::truncate_tool_outputs(messages, config.max_tool_output_chars);
+                    if count > 0 {
+                        actions.push(format!("truncated {} tool output(s)", count));
+                    }
+                }
+                ContextStrategy::DropToolResults => {
+                    let count = context::drop_tool_results(messages, budget);
+                    if count > 0 {
+                        actions.push(format!("dropped {} old tool result(s)", count));
+                    }
+                }
+                ContextStrategy::Summarize => {
+                    let Some(range) = context::summarizable_range(messages) else {
+                        continue;
+                    };
+                    match Self::summarize(&api, &messages[range.clone()]).await {
+                        Ok(summary) => {
+                            actions.push(format!("summarized {} earlier message(s)", range.len()));
+                            if config.persist_summaries
+                                && messages.len() == original.len()
+                                && Self::persist_summary(self_arc_mutex, &original, range.clone(), &summary).await
+                            {
+                                actions.push("kept the summary in the history".to_string());
+                            }
+                            messages.splice(range, [context::summary_message(&summary)]);
+                        }
+                        Err(e) => actions.push(format!("summarization failed ({})", e)),
+                    }
+                }
+            }
+        }
+        // それでも収まらなければ古いメッセージから削除する
+        let count = context::drop_oldest_messages(messages, budget);
+        if count > 0 {
+            actions.push(format!("removed {} oldest message(s)", count));
+        }
+
+        if actions.is_empty() {
+            actions.push("nothing left to remove besides the system prompt and the latest message".to_string());
+        }
+        let report = format!(
+            "Conversation (~{} tokens) exceeded the context budget of {} tokens: {}.",
+            before,
+            budget,
+            actions.join(", ")
+        );
+        let agent_locked = self_arc_mutex.lock().await;
+        agent_locked.write_message_to_log(&ChatMessage::system(report.clone()));
+        Some(report)
+    }
+
+    /// 要約した範囲を履歴でも要約に置き換える。
+    /// 要約している間に履歴が変わっていれば置き換えずに偽を返す (要約はそのリクエストだけに使う)
+    async fn persist_summary(
+        self_arc_mutex: &Arc<Mutex<Self>>,
+        original: &[ChatMessage],
+        range: std::ops::Range<usize>,
+        summary: &str,
+    ) -> bool {
+        let mut agent_locked = self_arc_mutex.lock().await;
+        let unchanged = agent_locked.messages.get(range.clone()).is_some_and(|current| {
+            current
+                .iter()
+                .zip(&original[range.clone()])
+                .all(|(current, original)| current.role == original.role && current.content == original.content)
+        });
+        if unchanged {
+            agent_locked.messages.splice(range, [context::summary_message(summary)]);
+        }
+        unchanged
+    }
+
+    /// 古いやり取りをモデルに要約させる。JSONモードは要約には使わない
+    async fn summarize(api: &AIApi, messages: &[ChatMessage]) -> Result<String, ApiError> {
+        let mut api = api.clone();
+        api.set_response_format(None);
+        let mut stream = api
+            .get_chat_completion_stream(context::summary_request(messages), None)
+            .await?;
+        let mut summary = String::new();
+        while let Some(chunk) = stream.next().await {
+            if let ChatStreamChunk::Content(text) = chunk? {
+                summary.push_str(&text);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// 上限に達してターンを止めたことをログに残す
+    async fn log_limit_reached(self_arc_mutex: &Arc<Mutex<Self>>, limit: &LimitReached) {
+        let agent_locked = self_arc_mutex.lock().await;
+        agent_locked.write_message_to_log(&ChatMessage::system(limit.to_string()));
+    }
+
+    /// 直前のターンがツールの結果を返したところで止まっていて、続行できるか
+    pub fn can_continue(&self) -> bool {
+        self.messages.last().is_some_and(context::is_tool_result)
+    }
+
+    /// ツールを実行する前に許可を求めるかを返す。
+    /// 登録されていないツールは実行時にエラーになるため、確認しない。
+    /// 委任も、設定ファイルで方針を指定しない限り確認しない (子エージェントのツール呼び出しをそれぞれ確認する)
+    fn approval_policy(&self, tool_name: &str) -> Option<(ApprovalPolicy, tools::ToolRisk)> {
+        let risk = self.tool_manager.get_tool(tool_name)?.risk();
+        if tool_name == DELEGATE_TOOL_NAME && !self.tool_approval.tools.contains_key(tool_name) {
+            return Some((ApprovalPolicy::Always, risk));
+        }
+        if self.approved_tools.contains(tool_name) {
+            return Some((ApprovalPolicy::Always, risk));
+        }
+        Some((self.tool_approval.policy(tool_name, risk), risk))
+    }
+
     /// ツール使用を伴うリアルタイムチャットセッションを開始
     /// この関数は、AIの応答をストリームし、ツール呼び出しを検出して実行し、その結果をAIにフィードバックして次の思考を促します。
     pub async fn chat_with_tools_realtime(
         self_arc_mutex: Arc<Mutex<Self>>,
         initial_messages: Vec<ChatMessage>, // 初期メッセージ (変更可能)
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<AgentEvent, ApiError>> + Send>>, ApiError> {
+    ) -> Result<AgentEventStream, ApiError> {
+        Ok(Self::tool_loop_stream(self_arc_mutex, initial_messages))
+    }
+
+    /// `chat_with_tools_realtime` のストリーム本体。
+    /// 子エージェントのストリームをこの中から作るため、非同期関数にせず直接返す
+    fn tool_loop_stream(
+        self_arc_mutex: Arc<Mutex<Self>>,
+        initial_messages: Vec<ChatMessage>,
+    ) -> AgentEventStream {
         let agent_stream = async_stream::stream! {
             // ループ内で使用するメッセージリストのクローン
             let mut _loop_messages = initial_messages.clone();
+            // 応答がJSONの形式に従わず再プロンプトした回数
+            let mut format_retries = 0;
+            // パースできなかったツール呼び出しを書き直させた回数
+            let mut tool_call_corrections = 0;
+            // ツールを呼び続けてターンが終わらなくなるのを防ぐ
+            let mut loop_guard = {
+                let agent_locked = self_arc_mutex.lock().await;
+                LoopGuard::new(agent_locked.loop_limits.clone())
+            };
 
             loop {
+                if let Err(limit) = loop_guard.check_before_request() {
+                    Self::log_limit_reached(&self_arc_mutex, &limit).await;
+                    yield Ok(AgentEvent::LimitReached(limit));
+                    break;
+                }
+
                 // --- 1. 最新の状態を取得し、API呼び出しの準備をする ---
                 let api_clone = { // AIApiはCloneを実装しているので、ロックを保持せずにクローンできる
                     let agent_locked = self_arc_mutex.lock().await;
@@ -302,28 +814,98 @@ impl AIAgent {
                 };
 
                 // --- 2. AI応答ストリームを取得 ---
-                let mut ai_response_stream = api_clone
-                    .get_chat_completion_stream(_loop_messages.clone())
-                    .await?;
+                // モデルが対応していればネイティブのツール呼び出しを優先する
+                let native_tools = Self::native_tool_definitions(&self_arc_mutex).await;
+                let mut compacted_messages = {
+                    let agent_locked = self_arc_mutex.lock().await;
+                    agent_locked.with_tools_prompt(&_loop_messages, native_tools.is_some())
+                };
+                // 履歴がコンテキスト長を超えそうなら、送る分だけを縮める
+                if let Some(report) = Self::fit_context(&self_arc_mutex, &mut compacted_messages, &native_tools).await {
+                    yield Ok(AgentEvent::ContextCompacted(report));
+                }
+                // ネイティブのツール呼び出しを使わない場合は、ツールの結果をテキストとして送る
+                let request_messages = if native_tools.is_some() {
+                    compacted_messages.clone()
+                } else {
+                    tool_messages_as_text(&compacted_messages)
+                };
+                let request_started = Instant::now();
+                let mut ai_response_stream = match api_clone
+                    .get_chat_completion_stream(request_messages, native_tools.clone())
+                    .await
+                {
+                    Ok(stream) => stream,
+                    Err(e) if native_tools.is_some() && e.rejects_tools() => {
+                        // ツール定義付きのリクエストが拒否された場合は、YAMLプロトコルにフォールバックする。
+                        // 接続の失敗など、ツールと関係のないエラーではフォールバックしない
+                        let fallback_messages = {
+                            let agent_locked = self_arc_mutex.lock().await;
+                            tool_messages_as_text(&agent_locked.with_tools_prompt(&compacted_messages, false))
+                        };
+                        let stream = api_clone
+                            .get_chat_completion_stream(fallback_messages, None)
+                            .await?;
+                        let mut agent_locked = self_arc_mutex.lock().await;
+                        agent_locked.native_tool_support.insert(api_clone.get_model(), false);
+                        stream
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
 
                 // --- 3. AIからのストリームを処理し、ツール呼び出しが検出されたら中断 ---
                 let mut full_ai_response_content = String::new();
-                let mut call_tool_option: Option<AiToolCall> = None;
+                let mut tool_calls: Vec<AiToolCall> = Vec::new();
+                let mut native_tool_calls = false;
                 let mut pre_tool_content: String = String::new();
+                // ツール呼び出しになりうるテキストは、そうではないと分かるまで表示しない
+                let mut tool_parser = ToolCallParser::new();
+                let mut usage = TokenUsage::default();
 
                 'stream_loop: while let Some(chunk_result) = ai_response_stream.next().await {
                     match chunk_result {
-                        Ok(chunk) => {
+                        Ok(ChatStreamChunk::ToolCalls(calls)) => {
+                            // ネイティブのツール呼び出しはYAMLブロックの検出より優先する。
+                            // 使用量は最後のチャンクで届くため、ストリームは最後まで読む
+                            if !native_tool_calls {
+                                native_tool_calls = true;
+                                tool_calls.clear();
+                                pre_tool_content = full_ai_response_content.clone();
+                            }
+                            tool_calls.extend(calls.into_iter().map(AiToolCall::from));
+                        }
+                        Ok(ChatStreamChunk::Content(_)) if native_tool_calls => {
+                            // ネイティブのツール呼び出し後のテキストは使わない
+                        }
+                        Ok(ChatStreamChunk::Reasoning(reasoning)) => {
+                            // 思考過程は表示のみに使い、モデルに送り返す履歴には含めない
+                            yield Ok(AgentEvent::Reasoning(reasoning));
+                        }
+                        Ok(ChatStreamChunk::Usage(chunk_usage)) => {
+                            usage.merge(chunk_usage);
+                        }
+                        Ok(ChatStreamChunk::Backend(label, is_fallback)) => {
+                            if is_fallback {
+                                let agent_locked = self_arc_mutex.lock().await;
+                                agent_locked.write_message_to_log(&ChatMessage::system(format!(
+                                    "Primary backend unavailable. Answered by {}",
+                                    label
+                                )));
+                            }
+                            yield Ok(AgentEvent::BackendSelected(label, is_fallback));
+                        }
+                        Ok(ChatStreamChunk::Content(chunk)) => {
                             full_ai_response_content.push_str(&chunk);
-                            // 蓄積されたコンテンツからツール呼び出しのパースを試みる
-                            if let Some((call_tool, pre_content)) = extract_tool_call_from_response(&full_ai_response_content) {
-                                call_tool_option = Some(call_tool);
-                                pre_tool_content = pre_content;
-                                // ツール呼び出しが検出されたら、AIのストリームの受信を停止
+                            let display = tool_parser.push(&chunk);
+                            if !display.is_empty() {
+                                yield Ok(AgentEvent::AiResponseChunk(display));
+                            }
+                            // 続けて別のツール呼び出しが書かれる間は受信を続け、通常のテキストが始まったら停止する
+                            if tool_parser.should_stop() {
                                 break 'stream_loop;
-                            } else {
-                                // ツール呼び出しがまだ検出されていない場合のみ、チャンクをUIに送信
-                                yield Ok(AgentEvent::AiResponseChunk(chunk.clone()));
                             }
                         }
                         Err(e) => {
@@ -333,78 +915,225 @@ impl AIAgent {
                     }
                 }
 
+                // 保留していたテキストのうち、ツール呼び出しではなかったものを表示する
+                if !native_tool_calls {
+                    let display = tool_parser.finish();
+                    if !display.is_empty() {
+                        yield Ok(AgentEvent::AiResponseChunk(display));
+                    }
+                    tool_calls = tool_parser.calls().to_vec();
+                    pre_tool_content = tool_parser.pre_content().to_string();
+                }
+
+                // 使用量をUIとログに送る。プロバイダが時間を報告しない場合は実測値を使う
+                if usage.total_duration.is_none() {
+                    usage.total_duration = Some(request_started.elapsed());
+                }
+                {
+                    let agent_locked = self_arc_mutex.lock().await;
+                    agent_locked.write_usage_to_log(&usage);
+                }
+                yield Ok(AgentEvent::Usage(usage));
+
+                // ツール呼び出しのつもりで書かれたがパースできなかったブロック
+                let malformed_tool_calls: Vec<MalformedToolCall> = if native_tool_calls {
+                    Vec::new()
+                } else {
+                    tool_parser.malformed().to_vec()
+                };
+                for malformed in &malformed_tool_calls {
+                    yield Ok(AgentEvent::YamlParseError(malformed.error.clone(), malformed.block.clone()));
+                }
+                let correct_tool_calls = !malformed_tool_calls.is_empty() && tool_call_corrections < MAX_TOOL_CALL_CORRECTIONS;
+                if correct_tool_calls {
+                    tool_call_corrections += 1;
+                    yield Ok(AgentEvent::ToolBlockParseWarning(format!(
+                        "{} tool call block(s) could not be parsed. Asking the model to rewrite them...",
+                        malformed_tool_calls.len()
+                    )));
+                } else if !malformed_tool_calls.is_empty() {
+                    yield Ok(AgentEvent::ToolBlockParseWarning(format!(
+                        "Tool call blocks still could not be parsed after {} corrections. Leaving them as text.",
+                        MAX_TOOL_CALL_CORRECTIONS
+                    )));
+                }
+
+                // 同じツール呼び出しを繰り返している場合は、実行も履歴への追加もせずに止める
+                if !tool_calls.is_empty()
+                    && let Err(limit) = loop_guard.record_tool_calls(&tool_calls)
+                {
+                    Self::log_limit_reached(&self_arc_mutex, &limit).await;
+                    yield Ok(AgentEvent::LimitReached(limit));
+                    break;
+                }
+
                 // --- 4. AIの完全な応答を履歴に追加 ---
-                if call_tool_option.is_some() {
-                    // ツール呼び出しがあった場合、ツール呼び出しより前の内容をAssistantメッセージとして追加
-                    if !pre_tool_content.is_empty() {
-                        let mut agent_locked = self_arc_mutex.lock().await;
-                        let assistant_message = ChatMessage {
-                            role: ChatRole::Assistant,
-                            content: pre_tool_content.clone(),
-                        };
-                        agent_locked.add_message_to_history(assistant_message.clone());
+                if !tool_calls.is_empty() {
+                    // ツール呼び出しがあった場合、ツール呼び出しより前の内容と呼び出しをAssistantメッセージとして追加
+                    let mut agent_locked = self_arc_mutex.lock().await;
+                    // IDを返さないプロバイダやYAMLの呼び出しには、結果と対応付けるためのIDを割り当てる
+                    let history_len = agent_locked.messages.len();
+                    for (index, call) in tool_calls.iter_mut().enumerate() {
+                        call.id.get_or_insert_with(|| format!("call_{}_{}", history_len, index));
                     }
+                    let assistant_message = ChatMessage {
+                        tool_calls: tool_calls.iter().map(AiToolCall::to_native).collect(),
+                        ..ChatMessage::assistant(pre_tool_content.clone())
+                    };
+                    agent_locked.add_message_to_history(assistant_message.clone());
                 } else if !full_ai_response_content.is_empty() {
                     // ツール呼び出しがなく、AIの応答があった場合
                     let mut agent_locked = self_arc_mutex.lock().await;
-                    let assistant_message = ChatMessage {
-                        role: ChatRole::Assistant,
-                        content: full_ai_response_content.clone(),
-                    };
+                    let assistant_message = ChatMessage::assistant(full_ai_response_content.clone());
                     agent_locked.add_message_to_history(assistant_message.clone());
                 }
 
                 // --- 5. ツール呼び出しが検出された場合、それを実行 ---
-                if let Some(call_tool) = call_tool_option {
-                    // ツール呼び出しイベントをUIに送信
-                    yield Ok(AgentEvent::ToolCallDetected(call_tool.clone()));
-                    yield Ok(AgentEvent::ToolExecuting(call_tool.tool_name.clone())); // ツール実行中イベント
-
-                    // ツールを実行
-                    let tool_result_outcome = { // ロックのスコープを限定
-                        let agent_locked = self_arc_mutex.lock().await;
-                        agent_locked.tool_manager.execute_tool(
-                            &call_tool.tool_name,
-                            call_tool.parameters.clone()
-                        ).await
-                    };
+                if !tool_calls.is_empty() {
+                    // ツール呼び出しごとにイベントをUIに送信し、方針に従って実行の許可を確認する
+                    let mut denials: Vec<Option<ToolError>> = Vec::with_capacity(tool_calls.len());
+                    for call_tool in &tool_calls {
+                        yield Ok(AgentEvent::ToolCallDetected(call_tool.clone()));
+                        let policy = self_arc_mutex.lock().await.approval_policy(&call_tool.tool_name);
+                        let denial = match policy {
+                            None | Some((ApprovalPolicy::Always, _)) => None,
+                            Some((ApprovalPolicy::Never, _)) => Some(ToolError::Denied(format!(
+                                "'{}' is disabled by the tool approval settings.",
+                                call_tool.tool_name
+                            ))),
+                            Some((ApprovalPolicy::Ask, risk)) => {
+                                // ユーザーが答えるまで待つ (ロックは持たない)
+                                let (request, decision) = ApprovalRequest::new(call_tool.clone(), risk);
+                                yield Ok(AgentEvent::ApprovalRequired(request));
+                                match decision.await.unwrap_or(ApprovalDecision::Deny) {
+                                    ApprovalDecision::Approve => None,
+                                    ApprovalDecision::AlwaysApprove => {
+                                        let mut agent_locked = self_arc_mutex.lock().await;
+                                        agent_locked.approved_tools.insert(call_tool.tool_name.clone());
+                                        None
+                                    }
+                                    ApprovalDecision::Deny => Some(ToolError::Denied(
+                                        "The user did not allow this tool call. Do not retry it unless the user asks; \
+                                        explain what you wanted to do or continue without it."
+                                            .to_string(),
+                                    )),
+                                }
+                            }
+                        };
+                        if denial.is_none() {
+                            yield Ok(AgentEvent::ToolExecuting(call_tool.tool_name.clone())); // ツール実行中イベント
+                        }
+                        denials.push(denial);
+                    }
 
-                    // ツールの結果を処理し、履歴に追加
-                    match tool_result_outcome {
-                        Ok(tool_result) => {
-                            yield Ok(AgentEvent::ToolResult(call_tool.tool_name.clone(), tool_result.clone())); // ツール結果をUIに送信
-                            let tool_output_message_content = serde_yaml::to_string(
-                                &serde_yaml::to_value(serde_json::json!({
-                                    "tool_result": { "tool_name": call_tool.tool_name, "result": tool_result }
-                                }))
-                                .unwrap_or_else(|_| serde_yaml::Value::Null)
-                            ).unwrap_or_else(|_| "Failed to serialize tool result.".to_string());
-
-                            let mut agent_locked = self_arc_mutex.lock().await;
-                            let tool_output_message = ChatMessage {
-                                role: ChatRole::User, // ロールをUserに変更
-                                content: format!("Tool result for '{}':\n---\n{}\n---", call_tool.tool_name, tool_output_message_content),
+                    // 許可されたツールを呼び出しの順に実行する。委任は前後の呼び出しと重ならないように、
+                    // 子エージェントのイベントを中継しながら1つずつ実行し、親の履歴には子エージェントの最後の応答だけを結果として返す。
+                    // 委任の間にある呼び出しはまとめて実行する (互いに影響しない呼び出しは並行に実行する)
+                    // 拒否した呼び出しはエラーとしてモデルに返す
+                    let mut outcomes: Vec<Option<Result<Value, ToolError>>> =
+                        denials.into_iter().map(|denial| denial.map(Err)).collect();
+                    let mut index = 0;
+                    while index < tool_calls.len() {
+                        let call_tool = &tool_calls[index];
+                        if call_tool.tool_name != DELEGATE_TOOL_NAME {
+                            let end = tool_calls[index..]
+                                .iter()
+                                .position(|call| call.tool_name == DELEGATE_TOOL_NAME)
+                                .map_or(tool_calls.len(), |offset| index + offset);
+                            let batch: Vec<usize> = (index..end).filter(|&i| outcomes[i].is_none()).collect();
+                            let calls: Vec<(String, Value)> = batch
+                                .iter()
+                                .map(|&i| (tool_calls[i].tool_name.clone(), tool_calls[i].parameters.clone()))
+                                .collect();
+                            let results = { // ロックのスコープを限定
+                                let agent_locked = self_arc_mutex.lock().await;
+                                agent_locked.tool_manager.execute_tools(&calls).await
                             };
+                            for (i, result) in batch.into_iter().zip(results) {
+                                outcomes[i] = Some(result);
+                            }
+                            index = end;
+                            continue;
+                        }
+                        if outcomes[index].is_some() {
+                            index += 1;
+                            continue;
+                        }
+                        let sub_agent = match DelegateRequest::from_args(&call_tool.parameters) {
+                            Ok(request) => self_arc_mutex
+                                .lock()
+                                .await
+                                .sub_agent(&request)
+                                .map(|sub_agent| Arc::new(Mutex::new(sub_agent))),
+                            Err(e) => Err(e),
+                        };
+                        let outcome = match sub_agent {
+                            Ok(sub_agent) => {
+                                let sub_messages = sub_agent.lock().await.messages.clone();
+                                let mut stopped_by = None;
+                                let mut failure = None;
+                                let mut sub_stream = Self::tool_loop_stream(sub_agent.clone(), sub_messages);
+                                while let Some(event) = sub_stream.next().await {
+                                    match event {
+                                        Ok(event) => {
+                                            if let AgentEvent::LimitReached(limit) = &event {
+                                                stopped_by = Some(limit.to_string());
+                                            }
+                                            yield Ok(AgentEvent::SubAgent(Box::new(event)));
+                                        }
+                                        Err(e) => {
+                                            failure = Some(e);
+                                            break;
+                                        }
+                                    }
+                                }
+                                // 子エージェントで「常に許可」したツールは子エージェントの中だけで有効とし、親には引き継がない
+                                let sub_agent_locked = sub_agent.lock().await;
+                                match failure {
+                                    Some(e) => Err(ToolError::Api(e)),
+                                    None => Ok(sub_agent_locked.sub_agent_result(stopped_by)),
+                                }
+                            }
+                            Err(e) => Err(e),
+                        };
+                        outcomes[index] = Some(outcome);
+                        index += 1;
+                    }
+                    let tool_result_outcomes: Vec<Result<Value, ToolError>> = outcomes
+                        .into_iter()
+                        .map(|outcome| outcome.expect("one outcome per tool call"))
+                        .collect();
+
+                    // ツールの結果を処理し、呼び出しごとにToolメッセージとして履歴に追加
+                    let mut tool_output_contents = Vec::new();
+                    for (call_tool, tool_result_outcome) in tool_calls.iter().zip(tool_result_outcomes) {
+                        match tool_result_outcome {
+                            Ok(tool_result) => {
+                                // 履歴には結果をJSONのまま残す (YAMLプロトコルで送る場合は送信時に変換する)
+                                tool_output_contents.push(tool_result.to_string());
+                                yield Ok(AgentEvent::ToolResult(call_tool.tool_name.clone(), tool_result)); // ツール結果をUIに送信
+                            }
+                            Err(e) => {
+                                let error_message = format!("{:?}", e);
+                                tool_output_contents.push(serde_json::json!({ "error": error_message }).to_string());
+                                yield Ok(AgentEvent::ToolError(call_tool.tool_name.clone(), error_message)); // ツールエラーをUIに送信
+                            }
+                        }
+                    }
+                    {
+                        let mut agent_locked = self_arc_mutex.lock().await;
+                        for (call_tool, content) in tool_calls.iter().zip(tool_output_contents) {
                             // ツール結果をエージェントの履歴に追加し、ログにも書き込む
-                            agent_locked.add_message_to_history(tool_output_message.clone());
+                            agent_locked.add_message_to_history(ChatMessage {
+                                tool_call: Some(call_tool.to_native()),
+                                ..ChatMessage::new(ChatRole::Tool, content)
+                            });
                         }
-                        Err(e) => {
-                            let error_message = format!("{:?}", e);
-                            yield Ok(AgentEvent::ToolError(call_tool.tool_name.clone(), error_message.clone())); // ツールエラーをUIに送信
-                            let error_message_content = serde_yaml::to_string(
-                                &serde_yaml::to_value(serde_json::json!({
-                                    "tool_error": { "tool_name": call_tool.tool_name, "error": error_message }
-                                }))
-                                .unwrap_or_else(|_| serde_yaml::Value::Null)
-                            ).unwrap_or_else(|_| "Failed to serialize tool error.".to_string());
-
-                            let mut agent_locked = self_arc_mutex.lock().await;
-                            let tool_error_message = ChatMessage {
-                                role: ChatRole::User, // ロールをUserに変更
-                                content: format!("Error from tool '{}':\n---\n{}\n---", call_tool.tool_name, error_message_content),
-                            };
-                            // ツールエラーをエージェントの履歴に追加し、ログにも書き込む
-                            agent_locked.add_message_to_history(tool_error_message.clone());
+                        if correct_tool_calls {
+                            // 一緒に書かれたパースできなかったブロックは、結果と合わせて書き直させる
+                            agent_locked.add_message_to_history(ChatMessage::synthetic_user(
+                                tool_call_correction_message(&malformed_tool_calls),
+                            ));
                         }
                     }
 
@@ -418,13 +1147,46 @@ impl AIAgent {
                     // ツール結果をAIに処理させ、再度思考させるためにループを続行
                 } else {
                     // AIの応答でツール呼び出しが検出されなかった
+                    // ツール呼び出しのつもりのブロックがパースできなかった場合は、理由を添えて書き直させる
+                    if correct_tool_calls {
+                        let mut agent_locked = self_arc_mutex.lock().await;
+                        agent_locked.add_message_to_history(ChatMessage::synthetic_user(
+                            tool_call_correction_message(&malformed_tool_calls),
+                        ));
+                        _loop_messages = agent_locked.messages.clone();
+                        continue;
+                    }
+                    // 応答の形式が指定されていれば、最終的な応答を検証してから返す
+                    let response_format = {
+                        let agent_locked = self_arc_mutex.lock().await;
+                        agent_locked.response_format.clone()
+                    };
+                    if let Some(format) = response_format
+                        && let Err(reason) = format.validate(&full_ai_response_content)
+                    {
+                        if format_retries >= MAX_FORMAT_RETRIES {
+                            yield Err(ApiError::ResponseFormatMismatch(reason));
+                            return;
+                        }
+                        format_retries += 1;
+                        yield Ok(AgentEvent::ResponseFormatMismatch(reason.clone()));
+
+                        // 理由を添えて、形式に従った回答をやり直させる
+                        let mut agent_locked = self_arc_mutex.lock().await;
+                        agent_locked.add_message_to_history(ChatMessage::synthetic_user(format!(
+                            "Your previous reply was rejected. {}\nReply again with only the JSON value, without any other text or code fences.",
+                            reason
+                        )));
+                        _loop_messages = agent_locked.messages.clone();
+                        continue;
+                    }
                     // 会話のターンが完了。ループを終了
                     break;
                 }
             }
         };
 
-        Ok(Box::pin(agent_stream))
+        Box::pin(agent_stream)
     }
 
     /// メッセージを履歴に追加し、ログファイルにも書き込む
@@ -434,11 +1196,12 @@ impl AIAgent {
         self.messages.push(message);
     }
 
-    /// 最後のユーザーメッセージとそれに続くAIの応答/ツール実行結果を履歴から削除
+    /// 最後のユーザーメッセージとそれに続くAIの応答/ツール実行結果を履歴から削除。
+    /// エージェントが追加した書き直しの依頼などは、ユーザーの発言として扱わずに一緒に削除する
     pub fn revert_last_user_message(&mut self) {
         let mut last_user_idx = None;
         for (i, msg) in self.messages.iter().enumerate().rev() {
-            if msg.role == ChatRole::User {
+            if msg.role == ChatRole::User && !msg.synthetic {
                 last_user_idx = Some(i);
                 break;
             }
@@ -452,3 +1215,740 @@ impl AIAgent {
         // ユーザーメッセージが見つからない場合は何もしない
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::api::NativeToolCall;
+    use crate::modules::agent::api::fallback::BackendConfig;
+    use crate::modules::agent::api::replay::RecordingApi;
+    use crate::modules::agent::api::replay::testing::ScriptedApi;
+    use crate::modules::agent::testing::{FAKE_MODEL, FakeOllama, FakeReply, FakeShell};
+    use serde_json::json;
+
+    /// `AIAgent::new` が最初に追加するシステムメッセージの数
+    const INTRO_MESSAGES: usize = 5;
+
+    /// ログを書かず、コマンドを実行する `shell` の代わりに `FakeShell` を使うエージェントを作る
+    fn test_agent(
+        provider: AIProvider,
+        base_url: String,
+        model: &str,
+        config: &AppConfig,
+        shell: FakeShell,
+    ) -> AIAgent {
+        let mut tool_manager = AIAgent::default_tool_manager();
+        tool_manager.register_tool(shell);
+        AIAgent::build(provider, base_url, model.to_string(), config, tool_manager, None)
+    }
+
+    fn fake_agent(fake: &FakeOllama) -> Arc<Mutex<AIAgent>> {
+        fake_agent_with(fake, &AppConfig::default(), FakeShell::default())
+    }
+
+    fn fake_agent_with(fake: &FakeOllama, config: &AppConfig, shell: FakeShell) -> Arc<Mutex<AIAgent>> {
+        Arc::new(Mutex::new(test_agent(
+            AIProvider::Ollama,
+            fake.base_url.clone(),
+            FAKE_MODEL,
+            config,
+            shell,
+        )))
+    }
+
+    /// ユーザーの発言を1つ送り、1ターン分のイベントを比較しやすい文字列にして返す
+    async fn run_turn(agent: &Arc<Mutex<AIAgent>>, input: &str) -> Vec<String> {
+        agent.lock().await.add_message_to_history(ChatMessage::user(input));
+        continue_turn(agent).await
+    }
+
+    /// ユーザーの発言を追加せずに、今の履歴からターンを進める。実行の確認にはすべて許可で答える
+    async fn continue_turn(agent: &Arc<Mutex<AIAgent>>) -> Vec<String> {
+        answer_turn(agent, ApprovalDecision::Approve).await
+    }
+
+    /// 今の履歴からターンを進め、実行の確認には `decision` で答える
+    async fn answer_turn(agent: &Arc<Mutex<AIAgent>>, decision: ApprovalDecision) -> Vec<String> {
+        let messages = agent.lock().await.messages.clone();
+        let mut stream = AIAgent::chat_with_tools_realtime(agent.clone(), messages)
+            .await
+            .unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = stream.next().await {
+            events.push(match event {
+                Ok(event) => describe_event(event, decision),
+                Err(e) => format!("api error: {}", e),
+            });
+        }
+        events
+    }
+
+    /// イベントを比較しやすい文字列にする。実行の確認には `decision` で答える
+    fn describe_event(event: AgentEvent, decision: ApprovalDecision) -> String {
+        match event {
+            AgentEvent::AiResponseChunk(chunk) => format!("chunk: {}", chunk),
+            AgentEvent::ToolCallDetected(call) => {
+                format!("call: {} {}", call.tool_name, call.parameters)
+            }
+            AgentEvent::ToolExecuting(name) => format!("executing: {}", name),
+            AgentEvent::ToolResult(name, result) => format!("result: {} {}", name, result),
+            AgentEvent::ToolError(name, _) => format!("tool error: {}", name),
+            AgentEvent::Thinking(_) => "thinking".to_string(),
+            AgentEvent::Reasoning(reasoning) => format!("reasoning: {}", reasoning),
+            // 時間は実行ごとに変わるため、トークン数だけを比べる
+            AgentEvent::Usage(usage) => format!(
+                "usage: {:?}/{:?}",
+                usage.prompt_tokens, usage.completion_tokens
+            ),
+            AgentEvent::ContextCompacted(report) => format!("compacted: {}", report),
+            AgentEvent::ResponseFormatMismatch(reason) => format!("mismatch: {}", reason),
+            AgentEvent::BackendSelected(label, _) => format!("backend: {}", label),
+            AgentEvent::LimitReached(limit) => format!("limit: {}", limit),
+            AgentEvent::YamlParseError(error, _) => format!("parse error: {}", error),
+            AgentEvent::ToolBlockParseWarning(warning) => format!("warning: {}", warning),
+            AgentEvent::ApprovalRequired(request) => {
+                let event = format!("approve? {} ({})", request.call.tool_name, request.risk);
+                request.respond(decision);
+                event
+            }
+            AgentEvent::SubAgent(event) => format!("sub: {}", describe_event(*event, decision)),
+            _ => "other".to_string(),
+        }
+    }
+
+    /// 最初のシステムメッセージより後の履歴を (ロール, 内容) の組で返す
+    async fn history(agent: &Arc<Mutex<AIAgent>>) -> Vec<(ChatRole, String)> {
+        let agent_locked = agent.lock().await;
+        agent_locked.messages[INTRO_MESSAGES..]
+            .iter()
+            .map(|message| (message.role.clone(), message.content.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn streams_text_split_across_network_chunks() {
+        let fake = FakeOllama::start(vec![FakeReply::text(&["こんにちは、", "世界"])], false).await;
+        let agent = fake_agent(&fake);
+
+        assert_eq!(
+            run_turn(&agent, "hi").await,
+            ["chunk: こんにちは、", "chunk: 世界", "usage: Some(10)/Some(5)"]
+        );
+        assert_eq!(
+            history(&agent).await,
+            [
+                (ChatRole::User, "hi".to_string()),
+                (ChatRole::Assistant, "こんにちは、世界".to_string()),
+            ]
+        );
+        // ネイティブのツール呼び出しに対応しないモデルにはツール定義を送らず、システムプロンプトに埋め込む
+        let request = &fake.chat_requests()[0];
+        assert!(request.get("tools").is_none());
+        assert!(request["messages"][0]["content"].as_str().unwrap().contains("Runs a shell command."));
+    }
+
+    #[tokio::test]
+    async fn runs_a_yaml_tool_call_block_and_answers_with_the_result() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::text(&[
+                    "Let me check.\n---tool_call\ntool_name: shell\n",
+                    "parameters:\n  command_line: echo yaml\n---\n",
+                ]),
+                FakeReply::text(&["It printed yaml."]),
+            ],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        let events = run_turn(&agent, "run echo").await;
+        assert_eq!(
+            events,
+            [
+                // ブロックが閉じるまではツール呼び出しと分からないため、そのまま表示される
+                "chunk: Let me check.\n".to_string(),
+                // 続けて別のツール呼び出しが書かれる可能性があるため、最後まで受信する
+                "usage: Some(10)/Some(5)".to_string(),
+                r#"call: shell {"command_line":"echo yaml"}"#.to_string(),
+                "approve? shell (executes code)".to_string(),
+                "executing: shell".to_string(),
+                r#"result: shell {"stdout":"yaml\n"}"#.to_string(),
+                "thinking".to_string(),
+                "chunk: It printed yaml.".to_string(),
+                "usage: Some(10)/Some(5)".to_string(),
+            ]
+        );
+
+        let history = history(&agent).await;
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[1], (ChatRole::Assistant, "Let me check.".to_string()));
+        assert_eq!(history[2], (ChatRole::Tool, r#"{"stdout":"yaml\n"}"#.to_string()));
+        assert_eq!(history[3], (ChatRole::Assistant, "It printed yaml.".to_string()));
+        // ネイティブのツール呼び出しに対応していないモデルには、ツールの結果をユーザーメッセージとして送る
+        let requests = fake.chat_requests();
+        assert_eq!(requests.len(), 2);
+        let sent = requests[1]["messages"].as_array().unwrap();
+        assert_eq!(sent.len(), INTRO_MESSAGES + 3);
+        assert_eq!(sent[INTRO_MESSAGES + 1]["tool_calls"], Value::Null);
+        assert_eq!(sent[INTRO_MESSAGES + 2]["role"], "user");
+        assert_eq!(
+            sent[INTRO_MESSAGES + 2]["content"],
+            "Tool result for 'shell':\n---\ntool_result:\n  result:\n    stdout: |\n      yaml\n  tool_name: shell\n\n---"
+        );
+    }
+
+    #[tokio::test]
+    async fn runs_native_tool_calls_and_reports_unknown_tools() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::tool_call("no_such_tool", json!({})),
+                FakeReply::tool_call("shell", json!({ "command_line": "echo native" })),
+                FakeReply::text(&["Done."]),
+            ],
+            true,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        assert_eq!(
+            run_turn(&agent, "go").await,
+            [
+                "usage: Some(10)/Some(5)",
+                "call: no_such_tool {}",
+                "executing: no_such_tool",
+                "tool error: no_such_tool",
+                "thinking",
+                "usage: Some(10)/Some(5)",
+                r#"call: shell {"command_line":"echo native"}"#,
+                "approve? shell (executes code)",
+                "executing: shell",
+                r#"result: shell {"stdout":"native\n"}"#,
+                "thinking",
+                "chunk: Done.",
+                "usage: Some(10)/Some(5)",
+            ]
+        );
+
+        let history = history(&agent).await;
+        assert_eq!(history[1], (ChatRole::Assistant, String::new()));
+        assert_eq!(history[2].0, ChatRole::Tool);
+        assert!(history[2].1.starts_with(r#"{"error":"#));
+        assert_eq!(history[4], (ChatRole::Tool, r#"{"stdout":"native\n"}"#.to_string()));
+        assert_eq!(history[5], (ChatRole::Assistant, "Done.".to_string()));
+        // ツールの結果は呼び出しと対応付けた `tool` ロールのメッセージとして送る
+        let requests = fake.chat_requests();
+        let sent = requests[2]["messages"].as_array().unwrap();
+        assert_eq!(
+            sent[INTRO_MESSAGES + 3]["tool_calls"],
+            json!([{ "function": { "name": "shell", "arguments": { "command_line": "echo native" } } }])
+        );
+        assert_eq!(sent[INTRO_MESSAGES + 4]["role"], "tool");
+        assert_eq!(sent[INTRO_MESSAGES + 4]["tool_name"], "shell");
+        assert_eq!(sent[INTRO_MESSAGES + 4]["content"], r#"{"stdout":"native\n"}"#);
+        // YAMLプロトコルで送る場合だけ、エラーを説明するテキストに変換する
+        let as_text = tool_messages_as_text(&agent.lock().await.messages);
+        assert!(as_text[INTRO_MESSAGES + 1].content.starts_with("Error from tool 'no_such_tool':\n---\ntool_error:"));
+        // ツール定義は名前順で毎回送る
+        let tool_names: Vec<&str> = requests[0]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|tool| tool["function"]["name"].as_str())
+            .collect();
+        assert!(tool_names.is_sorted());
+        assert!(tool_names.contains(&"shell"));
+        assert_eq!(requests[2]["tools"], requests[0]["tools"]);
+        // ツール定義を送る場合は、システムプロンプトにYAMLのスキーマを重ねて埋め込まない
+        assert!(!requests[0]["messages"][0]["content"].as_str().unwrap().contains("Runs a shell command."));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_yaml_tool_calls_only_when_the_tools_field_is_rejected() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::Status(404, json!({ "error": "model 'fake:latest' not found" })),
+                FakeReply::Status(400, json!({ "error": "fake:latest does not support tools" })),
+                FakeReply::text(&["Hi."]),
+                FakeReply::text(&["Hi again."]),
+            ],
+            true,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        // ツールと関係のないエラーはそのまま返し、ネイティブのツール呼び出しも続ける
+        let events = run_turn(&agent, "first").await;
+        assert!(events[0].starts_with("api error: Model not found"), "{}", events[0]);
+        // ツール定義を拒否された場合は、ツール定義を送らずに送り直し、以降も送らない
+        assert_eq!(run_turn(&agent, "second").await[0], "chunk: Hi.");
+        assert_eq!(run_turn(&agent, "third").await[0], "chunk: Hi again.");
+        let requests = fake.chat_requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].get("tools").is_some());
+        assert!(requests[2].get("tools").is_none());
+        assert!(requests[3].get("tools").is_none());
+    }
+
+    #[tokio::test]
+    async fn uses_yaml_tool_calls_unless_every_fallback_backend_supports_native_tools() {
+        let primary = FakeOllama::start(
+            vec![FakeReply::Status(404, json!({ "error": "model 'fake:latest' not found" }))],
+            true,
+        )
+        .await;
+        let secondary = FakeOllama::start(vec![FakeReply::text(&["Hi."])], false).await;
+        let config = AppConfig {
+            fallback: vec![BackendConfig {
+                provider: "ollama".to_string(),
+                model: FAKE_MODEL.to_string(),
+                base_url: Some(secondary.base_url.clone()),
+                api_key: None,
+            }],
+            ..AppConfig::default()
+        };
+        let agent = fake_agent_with(&primary, &config, FakeShell::default());
+
+        assert_eq!(
+            run_turn(&agent, "hi").await[..2],
+            [format!("backend: ollama:{}", FAKE_MODEL), "chunk: Hi.".to_string()]
+        );
+        // 予備のバックエンドにもYAMLのスキーマを埋め込んだシステムプロンプトが届く
+        let request = &secondary.chat_requests()[0];
+        assert!(request.get("tools").is_none());
+        assert!(request["messages"][0]["content"].as_str().unwrap().contains("Runs a shell command."));
+        assert!(primary.chat_requests()[0].get("tools").is_none());
+    }
+
+    #[tokio::test]
+    async fn runs_every_tool_call_in_a_response_and_returns_one_message() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::text(&[
+                    "Two checks.\n```yaml\ntool_calls:\n  - tool_name: shell\n    parameters: { command_line: echo one }\n",
+                    "  - tool_name: shell\n    parameters: { command_line: echo two }\n```\n",
+                    "Both printed.",
+                ]),
+                FakeReply::text(&["Done."]),
+            ],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        assert_eq!(
+            run_turn(&agent, "go").await,
+            [
+                "chunk: Two checks.\n",
+                "usage: None/None",
+                r#"call: shell {"command_line":"echo one"}"#,
+                "approve? shell (executes code)",
+                "executing: shell",
+                r#"call: shell {"command_line":"echo two"}"#,
+                "approve? shell (executes code)",
+                "executing: shell",
+                r#"result: shell {"stdout":"one\n"}"#,
+                r#"result: shell {"stdout":"two\n"}"#,
+                "thinking",
+                "chunk: Done.",
+                "usage: Some(10)/Some(5)",
+            ]
+        );
+
+        // ツール呼び出しの後に書かれた予想の文章は履歴に残さない
+        let history = history(&agent).await;
+        assert_eq!(history[1], (ChatRole::Assistant, "Two checks.".to_string()));
+        assert!(history[2].1.contains("one"));
+        assert!(history[3].1.contains("two"));
+        assert_eq!(history[4], (ChatRole::Assistant, "Done.".to_string()));
+        // ネイティブのツール呼び出しに対応していないモデルには、結果を1つのメッセージにまとめて送る
+        let sent = fake.chat_requests()[1]["messages"].as_array().unwrap().clone();
+        assert_eq!(sent.len(), INTRO_MESSAGES + 3);
+        let results = sent[INTRO_MESSAGES + 2]["content"].as_str().unwrap();
+        assert_eq!(results.matches("Tool result for 'shell':").count(), 2);
+        assert!(results.find("one").unwrap() < results.find("two").unwrap());
+    }
+
+    #[tokio::test]
+    async fn asks_before_running_tools_with_side_effects() {
+        let shell = |command_line: &str| FakeReply::tool_call("shell", json!({ "command_line": command_line }));
+        let fake = FakeOllama::start(
+            vec![
+                shell("echo denied"),
+                FakeReply::text(&["Understood."]),
+                shell("echo one"),
+                shell("echo two"),
+                FakeReply::text(&["Done."]),
+                FakeReply::tool_call("file_write", json!({ "path": "/tmp/never", "content": "x" })),
+                FakeReply::text(&["Skipped."]),
+            ],
+            true,
+        )
+        .await;
+        let mut config = AppConfig::default();
+        config.tool_approval.tools.insert("file_write".to_string(), ApprovalPolicy::Never);
+        let shell = FakeShell::default();
+        let agent = fake_agent_with(&fake, &config, shell.clone());
+
+        // 拒否した呼び出しは実行せず、エラーとしてモデルに返す
+        agent.lock().await.add_message_to_history(ChatMessage::user("run it"));
+        let events = answer_turn(&agent, ApprovalDecision::Deny).await;
+        assert_eq!(
+            events[1..4],
+            ["call: shell {\"command_line\":\"echo denied\"}", "approve? shell (executes code)", "tool error: shell"]
+        );
+        let messages = history(&agent).await;
+        assert_eq!(messages[2].0, ChatRole::Tool);
+        assert!(messages[2].1.contains("The user did not allow this tool call."));
+        assert_eq!(messages[3].1, "Understood.");
+        assert!(shell.commands().is_empty());
+
+        // 「常に許可」した後は、同じツールを確認せずに実行する
+        agent.lock().await.add_message_to_history(ChatMessage::user("run both"));
+        let events = answer_turn(&agent, ApprovalDecision::AlwaysApprove).await;
+        assert_eq!(events.iter().filter(|event| event.starts_with("approve?")).count(), 1);
+        assert_eq!(shell.commands(), ["echo one", "echo two"]);
+
+        // `never` のツールは確認もせずに拒否する
+        agent.lock().await.add_message_to_history(ChatMessage::user("write it"));
+        let events = answer_turn(&agent, ApprovalDecision::Approve).await;
+        assert_eq!(events[1..3], ["call: file_write {\"content\":\"x\",\"path\":\"/tmp/never\"}", "tool error: file_write"]);
+        assert!(!std::path::Path::new("/tmp/never").exists());
+        assert!(history(&agent).await.iter().any(|(_, content)| content.contains("disabled by the tool approval settings")));
+    }
+
+    #[tokio::test]
+    async fn delegates_a_task_to_a_sub_agent_and_returns_only_its_summary() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::tool_call(
+                    "delegate",
+                    json!({ "task": "Find the greeting.", "tools": ["shell"], "max_steps": 2 }),
+                ),
+                FakeReply::tool_call("shell", json!({ "command_line": "echo sub" })),
+                FakeReply::text(&["The greeting is sub."]),
+                FakeReply::text(&["Delegated."]),
+                FakeReply::tool_call("delegate", json!({ "task": "Recurse.", "tools": ["delegate"] })),
+                FakeReply::text(&["Could not delegate."]),
+            ],
+            true,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        agent.lock().await.add_message_to_history(ChatMessage::user("find it"));
+        let events = answer_turn(&agent, ApprovalDecision::AlwaysApprove).await;
+        for expected in [
+            "sub: call: shell {\"command_line\":\"echo sub\"}",
+            "sub: approve? shell (executes code)",
+            "sub: chunk: The greeting is sub.",
+        ] {
+            assert!(events.iter().any(|event| event == expected), "{:?}", events);
+        }
+        assert!(events.iter().any(|event| event.starts_with("sub: result: shell")));
+        assert_eq!(events[events.len() - 2], "chunk: Delegated.");
+
+        // 親の履歴には子エージェントの要約だけが残る
+        let messages = history(&agent).await;
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2].0, ChatRole::Tool);
+        assert!(messages[2].1.contains("The greeting is sub."));
+        assert!(!messages.iter().any(|(_, content)| content.contains("stdout")));
+        // 子エージェントの中で「常に許可」しても、親のセッションの許可は広がらない
+        assert!(agent.lock().await.approved_tools.is_empty());
+
+        // 子エージェントは自分の履歴と、指定されたツールだけを使う
+        let requests = fake.chat_requests();
+        let tool_names: Vec<&Value> = requests[1]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|tool| &tool["function"]["name"])
+            .collect();
+        assert_eq!(tool_names, [&json!("shell")]);
+        let sub_messages = requests[1]["messages"].as_array().unwrap();
+        assert_eq!(sub_messages.len(), 2);
+        assert!(sub_messages[1]["content"].as_str().unwrap().starts_with("Find the greeting."));
+
+        // 子エージェントに `delegate` は渡せない
+        let events = run_turn(&agent, "recurse").await;
+        assert!(events.contains(&"tool error: delegate".to_string()));
+        assert_eq!(fake.chat_requests().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn runs_a_delegated_task_in_order_with_the_other_tool_calls() {
+        let shell = |command_line: &str| ("shell", json!({ "command_line": command_line }));
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::tool_calls(&[
+                    shell("echo before"),
+                    ("delegate", json!({ "task": "Check it.", "tools": ["shell"] })),
+                    shell("echo after"),
+                ]),
+                FakeReply::tool_call("shell", json!({ "command_line": "echo sub" })),
+                FakeReply::text(&["Checked."]),
+                FakeReply::text(&["Done."]),
+            ],
+            true,
+        )
+        .await;
+        let shell = FakeShell::default();
+        let agent = fake_agent_with(&fake, &AppConfig::default(), shell.clone());
+
+        let events = run_turn(&agent, "go").await;
+        assert_eq!(events[events.len() - 2], "chunk: Done.");
+        // 委任より前の呼び出しは子エージェントより先に、後の呼び出しは子エージェントが終わってから実行する
+        assert_eq!(shell.commands(), ["echo before", "echo sub", "echo after"]);
+        let results: Vec<&String> = events.iter().filter(|event| event.starts_with("result:")).collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[1].starts_with("result: delegate"));
+    }
+
+    #[tokio::test]
+    async fn compacts_only_the_request_unless_summaries_are_persisted() {
+        for persist_summaries in [false, true] {
+            let fake = FakeOllama::start(
+                vec![FakeReply::text(&["They counted to three."]), FakeReply::text(&["Four."])],
+                false,
+            )
+            .await;
+            let agent = fake_agent(&fake);
+            {
+                let mut agent_locked = agent.lock().await;
+                let budget = context::estimate_messages(&agent_locked.messages) + 200;
+                agent_locked.context_config = ContextConfig {
+                    strategies: vec![ContextStrategy::Summarize],
+                    max_context_tokens: Some(budget),
+                    reserve_tokens: 0,
+                    persist_summaries,
+                    ..ContextConfig::default()
+                };
+                for count in 1..=3 {
+                    agent_locked.add_message_to_history(ChatMessage::user(format!("{} {}", count, "count ".repeat(80))));
+                    agent_locked.add_message_to_history(ChatMessage::assistant(count.to_string()));
+                }
+            }
+
+            let events = run_turn(&agent, "next").await;
+            assert!(events[0].starts_with("compacted: "), "{:?}", events);
+            // 要約はリクエストには必ず使う
+            let requests = fake.chat_requests();
+            let sent = requests[1]["messages"].as_array().unwrap();
+            assert_eq!(sent.len(), INTRO_MESSAGES + 2);
+            assert!(sent[INTRO_MESSAGES]["content"].as_str().unwrap().contains("They counted to three."));
+
+            // 履歴は設定で指定した場合だけ縮める
+            let history = history(&agent).await;
+            if persist_summaries {
+                assert_eq!(history.len(), 3);
+                assert_eq!(history[0].0, ChatRole::System);
+            } else {
+                assert_eq!(history.len(), 8);
+                assert!(history[0].1.starts_with("1 count"));
+            }
+            assert_eq!(history.last().unwrap().1, "Four.");
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_repeated_tool_calls_until_the_user_continues() {
+        let repeated = || FakeReply::tool_call("shell", json!({ "command_line": "echo again" }));
+        let fake = FakeOllama::start(
+            vec![repeated(), repeated(), repeated(), repeated(), FakeReply::text(&["Done."])],
+            true,
+        )
+        .await;
+        let mut config = AppConfig::default();
+        config.limits.max_identical_calls = Some(2);
+        let agent = fake_agent_with(&fake, &config, FakeShell::default());
+
+        let events = run_turn(&agent, "go").await;
+        assert_eq!(events.iter().filter(|event| event.starts_with("executing:")).count(), 2);
+        assert_eq!(
+            events.last().unwrap(),
+            "limit: Stopped because 'shell' was about to be called 3 times in a row with the same arguments."
+        );
+        // 3回目の呼び出しは実行せず、履歴は直前のツールの結果で止まっている
+        assert!(agent.lock().await.can_continue());
+        assert_eq!(history(&agent).await.last().unwrap().1, r#"{"stdout":"again\n"}"#);
+
+        // 続行すると数え直すため、同じ呼び出しをもう一度実行してから回答する
+        let events = continue_turn(&agent).await;
+        assert_eq!(events.iter().filter(|event| event.starts_with("executing:")).count(), 1);
+        assert_eq!(events[events.len() - 2], "chunk: Done.");
+        assert!(!agent.lock().await.can_continue());
+    }
+
+    #[tokio::test]
+    async fn asks_the_model_to_rewrite_tool_calls_that_cannot_be_parsed() {
+        let malformed = "```yaml tool\ntool_call:\n  tool_name: shell\n  parameters: echo fixed\n```";
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::text(&[malformed]),
+                FakeReply::text(&["```yaml tool\ntool_call:\n  tool_name: shell\n  parameters:\n    command_line: echo fixed\n```"]),
+                FakeReply::text(&["It printed fixed."]),
+                // 上限に達したら書き直させずに、そのまま回答として扱う
+                FakeReply::text(&[malformed]),
+                FakeReply::text(&[malformed]),
+                FakeReply::text(&[malformed]),
+            ],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        let events = run_turn(&agent, "run echo").await;
+        assert_eq!(
+            events[2..4],
+            [
+                "parse error: `parameters` of 'shell' must be a mapping from parameter names to values.",
+                "warning: 1 tool call block(s) could not be parsed. Asking the model to rewrite them...",
+            ]
+        );
+        assert!(events.contains(&r#"result: shell {"stdout":"fixed\n"}"#.to_string()));
+        assert_eq!(events[events.len() - 2], "chunk: It printed fixed.");
+
+        // 理由と元のブロックを添えて書き直させる
+        let messages = history(&agent).await;
+        assert_eq!(messages[1], (ChatRole::Assistant, malformed.to_string()));
+        assert_eq!(messages[2].0, ChatRole::User);
+        assert!(messages[2].1.contains("could not be parsed"));
+        assert!(messages[2].1.contains("must be a mapping"));
+        assert!(messages[2].1.contains("parameters: echo fixed"));
+
+        let events = run_turn(&agent, "again").await;
+        assert_eq!(fake.chat_requests().len(), 6);
+        assert_eq!(events.iter().filter(|event| event.starts_with("usage:")).count(), 3);
+        assert!(events.last().unwrap().starts_with("warning: Tool call blocks still could not be parsed"));
+        assert_eq!(history(&agent).await.last().unwrap().1, malformed);
+    }
+
+    #[tokio::test]
+    async fn reverts_a_corrected_turn_back_to_the_users_message() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::text(&["Hi."]),
+                FakeReply::text(&["```yaml tool\ntool_call:\n  tool_name: shell\n  parameters: echo\n```"]),
+                FakeReply::text(&["I cannot run it."]),
+            ],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+        run_turn(&agent, "hello").await;
+        run_turn(&agent, "run echo").await;
+        assert_eq!(history(&agent).await.len(), 6);
+
+        // 書き直しの依頼ではなく、ユーザーの発言の前まで戻す
+        agent.lock().await.revert_last_user_message();
+        assert_eq!(
+            history(&agent).await,
+            [
+                (ChatRole::User, "hello".to_string()),
+                (ChatRole::Assistant, "Hi.".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn re_prompts_until_the_reply_is_json_and_reverts_the_whole_turn() {
+        let fake = FakeOllama::start(
+            vec![FakeReply::text(&["Here you go: 1"]), FakeReply::text(&["{\"answer\": 1}"])],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+        agent.lock().await.set_response_format(Some(ResponseFormat::Json));
+
+        let events = run_turn(&agent, "answer in json").await;
+        assert!(events.iter().any(|event| event.starts_with("mismatch: ")));
+        let messages = history(&agent).await;
+        assert_eq!(messages.len(), 4);
+        assert!(messages[2].1.starts_with("Your previous reply was rejected."));
+        assert_eq!(messages[3], (ChatRole::Assistant, "{\"answer\": 1}".to_string()));
+
+        // 再プロンプトではなく、ユーザーの発言の前まで戻す
+        agent.lock().await.revert_last_user_message();
+        assert!(history(&agent).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn surfaces_http_and_mid_stream_errors() {
+        let fake = FakeOllama::start(
+            vec![
+                FakeReply::Status(404, json!({ "error": "model 'fake:latest' not found" })),
+                FakeReply::error_after(&["Partial"], "model runner crashed"),
+            ],
+            false,
+        )
+        .await;
+        let agent = fake_agent(&fake);
+
+        let events = run_turn(&agent, "first").await;
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("api error: Model not found"), "{}", events[0]);
+
+        assert_eq!(
+            run_turn(&agent, "second").await,
+            ["chunk: Partial", "api error: API error: model runner crashed"]
+        );
+        // モデル以外が見つからない404は、URLを添えてそのまま返す
+        let error = agent.lock().await.embed(vec!["x".to_string()]).await.unwrap_err();
+        assert!(matches!(error, ApiError::HttpStatus(reqwest::StatusCode::NOT_FOUND, _)));
+        assert!(error.to_string().contains(&format!("{}/api/embed", fake.base_url)), "{}", error);
+        // 失敗した応答は履歴に残さない
+        assert!(
+            history(&agent)
+                .await
+                .iter()
+                .all(|(role, _)| *role == ChatRole::User)
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_a_recorded_tool_loop_offline() {
+        let dir = std::env::temp_dir().join(format!("ai-integration-agent-{}", std::process::id()));
+        let config = AppConfig::default();
+
+        // 台本どおりに応答するバックエンドで1ターンを記録する
+        let mut scripted = ScriptedApi::new(vec![
+            vec![ChatStreamChunk::ToolCalls(vec![NativeToolCall {
+                id: None,
+                name: "shell".to_string(),
+                arguments: serde_json::json!({ "command_line": "echo replay" }),
+            }])],
+            vec![ChatStreamChunk::Content("The command printed replay.".to_string())],
+        ]);
+        scripted.native_tools = true;
+        let mut recorder = test_agent(
+            AIProvider::Ollama,
+            String::new(),
+            "scripted",
+            &config,
+            FakeShell::default(),
+        );
+        recorder.api = AIApi::from_backend(Box::new(RecordingApi::new(
+            Box::new(scripted),
+            dir.clone(),
+        )));
+        let recorded = run_turn(&Arc::new(Mutex::new(recorder)), "Run echo replay").await;
+        assert!(recorded.iter().any(|event| event.starts_with("result: shell")));
+        assert!(recorded.contains(&"chunk: The command printed replay.".to_string()));
+
+        // 再生ではネットワークを使わずに同じイベントが得られる
+        let replayer = test_agent(
+            AIProvider::Replay { dir: dir.clone() },
+            String::new(),
+            "scripted",
+            &config,
+            FakeShell::default(),
+        );
+        assert_eq!(
+            run_turn(&Arc::new(Mutex::new(replayer)), "Run echo replay").await,
+            recorded
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
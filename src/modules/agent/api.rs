@@ -1,14 +1,25 @@
 // src/modules/agent/api.rs
+pub mod anthropic;
+pub mod decoder;
+pub mod fallback;
 pub mod gemini;
 pub mod ollama;
+pub mod openai;
+pub mod reasoning;
+pub mod replay;
+pub mod retry;
 
 use async_trait::async_trait;
+use base64::prelude::*;
 use colored::*;
+use futures_util::StreamExt;
 use futures_util::stream::Stream;
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::Duration;
 
 // Common ChatMessage and ChatRole definitions
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
@@ -25,8 +36,225 @@ pub enum ChatRole {
 pub struct ChatMessage {
     pub role: ChatRole,
     pub content: String,
+    /// メッセージに添付された画像。マルチモーダル対応のプロバイダのみが送信する
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Assistantのメッセージで、モデルが要求したツール呼び出し
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<NativeToolCall>,
+    /// Toolのメッセージで、この結果を返したツール呼び出し
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call: Option<NativeToolCall>,
+    /// ユーザーではなくエージェントが書いたUserのメッセージ (書き直しの依頼など)。
+    /// 取り消しではユーザーの発言として扱わない
+    #[serde(skip)]
+    pub synthetic: bool,
 }
 
+impl ChatMessage {
+    /// 添付やツール呼び出しのない、テキストだけのメッセージを作る
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        ChatMessage {
+            role,
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn system(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::User, content)
+    }
+
+    /// エージェントがモデルに送る、ユーザーの発言ではないUserのメッセージを作る
+    pub fn synthetic_user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            synthetic: true,
+            ..Self::user(content)
+        }
+    }
+
+    pub fn assistant(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::Assistant, content)
+    }
+}
+
+/// メッセージに添付する画像
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    /// 表示用のファイル名
+    pub name: String,
+    /// MIMEタイプ (`image/png` など)
+    pub mime_type: String,
+    /// base64 エンコードしたデータ
+    pub data: String,
+}
+
+impl Attachment {
+    /// ローカルの画像ファイルを読み込み、添付ファイルを作成する
+    pub fn from_path(path: &Path) -> Result<Self, ApiError> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .unwrap_or_default();
+        let mime_type = match extension.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "bmp" => "image/bmp",
+            _ => {
+                return Err(ApiError::UnsupportedOperation(format!(
+                    "Unsupported image type: {} (supported: png, jpg, jpeg, gif, webp, bmp)",
+                    path.display()
+                )));
+            }
+        };
+
+        let bytes = std::fs::read(path)?;
+        Ok(Attachment {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.display().to_string()),
+            mime_type: mime_type.to_string(),
+            data: BASE64_STANDARD.encode(bytes),
+        })
+    }
+}
+
+/// ネイティブのツール呼び出し機能 (Ollama の `tools` など) でモデルが要求したツール呼び出し
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NativeToolCall {
+    /// 呼び出しのID。プロバイダが返さない場合はエージェントが割り当てる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// プロバイダのチャットストリームから流れてくる要素
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatStreamChunk {
+    /// 応答テキストの断片
+    Content(String),
+    /// ネイティブのツール呼び出し
+    ToolCalls(Vec<NativeToolCall>),
+    /// 応答したバックエンド (フォールバックチェーン使用時のみ)。2つ目は予備のバックエンドかどうか
+    Backend(String, bool),
+    /// トークン使用量と処理時間。1回の応答で複数回届く場合は後から届いた値で上書きする
+    Usage(TokenUsage),
+    /// 推論モデルの思考過程 (`<think>` ブロックや `thinking` フィールド)。履歴には含めない
+    Reasoning(String),
+}
+
+/// 1回のチャット応答で消費したトークン数と処理時間。
+/// プロバイダが報告しない項目は `None` のままにする。
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TokenUsage {
+    /// プロンプト (入力) のトークン数
+    pub prompt_tokens: Option<u64>,
+    /// 生成 (出力) したトークン数
+    pub completion_tokens: Option<u64>,
+    /// プロンプトの評価にかかった時間
+    pub prompt_duration: Option<Duration>,
+    /// 生成にかかった時間
+    pub completion_duration: Option<Duration>,
+    /// リクエスト全体にかかった時間
+    pub total_duration: Option<Duration>,
+}
+
+impl TokenUsage {
+    /// `other` で報告されている項目だけを上書きする
+    pub fn merge(&mut self, other: TokenUsage) {
+        self.prompt_tokens = other.prompt_tokens.or(self.prompt_tokens);
+        self.completion_tokens = other.completion_tokens.or(self.completion_tokens);
+        self.prompt_duration = other.prompt_duration.or(self.prompt_duration);
+        self.completion_duration = other.completion_duration.or(self.completion_duration);
+        self.total_duration = other.total_duration.or(self.total_duration);
+    }
+
+    /// 生成速度 (トークン/秒)。生成時間が分からない場合は全体の時間で計算する
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let tokens = self.completion_tokens?;
+        let duration = self.completion_duration.or(self.total_duration)?;
+        (!duration.is_zero()).then(|| tokens as f64 / duration.as_secs_f64())
+    }
+}
+
+impl fmt::Display for TokenUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show(value: Option<u64>) -> String {
+            value
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        }
+
+        write!(
+            f,
+            "prompt: {} tok, completion: {} tok",
+            show(self.prompt_tokens),
+            show(self.completion_tokens)
+        )?;
+        if let Some(duration) = self.total_duration {
+            write!(f, ", {:.2}s", duration.as_secs_f64())?;
+        }
+        if let Some(tps) = self.tokens_per_second() {
+            write!(f, ", {:.1} tok/s", tps)?;
+        }
+        Ok(())
+    }
+}
+
+/// プロバイダが返すチャット応答のストリーム
+pub type ChatCompletionStream =
+    Pin<Box<dyn Stream<Item = Result<ChatStreamChunk, ApiError>> + Send>>;
+
+/// モデルのダウンロード (`ollama pull`) の進捗。
+/// レイヤーのダウンロード中は `digest`、`total`、`completed` が入る。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PullProgress {
+    /// `pulling manifest`、`verifying sha256 digest`、`success` などの状態
+    pub status: String,
+    pub digest: Option<String>,
+    /// レイヤーの総バイト数
+    pub total: Option<u64>,
+    /// ダウンロード済みのバイト数
+    pub completed: Option<u64>,
+}
+
+impl PullProgress {
+    /// ダウンロード中のレイヤーの進捗率 (0.0〜100.0)
+    pub fn percent(&self) -> Option<f64> {
+        let total = self.total.filter(|total| *total > 0)?;
+        Some(self.completed.unwrap_or(0) as f64 * 100.0 / total as f64)
+    }
+}
+
+impl fmt::Display for PullProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.status)?;
+        if let (Some(percent), Some(total)) = (self.percent(), self.total) {
+            write!(
+                f,
+                ": {:.1}% ({:.1} / {:.1} MB)",
+                percent,
+                self.completed.unwrap_or(0) as f64 / 1_000_000.0,
+                total as f64 / 1_000_000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// モデルのダウンロードの進捗のストリーム
+pub type PullProgressStream = Pin<Box<dyn Stream<Item = Result<PullProgress, ApiError>> + Send>>;
+
 impl fmt::Display for ChatMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}: {}", "Role".green().bold(), self.role)?;
@@ -45,12 +273,219 @@ impl fmt::Display for ChatRole {
     }
 }
 
+/// 生成パラメータ。各プロバイダがそれぞれのリクエスト形式に変換する。
+/// `None` (または空の `stop`) の項目はプロバイダの既定値に任せる。
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GenerationOptions {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<u32>,
+    /// コンテキスト長 (Ollama の `num_ctx`)
+    pub num_ctx: Option<u32>,
+    pub seed: Option<i64>,
+    /// 停止シーケンス
+    pub stop: Vec<String>,
+    /// 生成する最大トークン数
+    pub max_tokens: Option<u32>,
+    pub repeat_penalty: Option<f64>,
+}
+
+impl Default for GenerationOptions {
+    fn default() -> Self {
+        GenerationOptions {
+            temperature: Some(0.7),
+            top_p: None,
+            top_k: None,
+            num_ctx: None,
+            seed: None,
+            stop: Vec::new(),
+            max_tokens: None,
+            repeat_penalty: None,
+        }
+    }
+}
+
+impl GenerationOptions {
+    /// `/set` コマンドで指定できるキー
+    pub const KEYS: [&'static str; 8] = [
+        "temperature",
+        "top_p",
+        "top_k",
+        "num_ctx",
+        "seed",
+        "stop",
+        "max_tokens",
+        "repeat_penalty",
+    ];
+
+    /// キー名と文字列の値から1項目を更新する。値に `none` を指定すると未設定に戻す。
+    /// `stop` はカンマ区切りで複数指定できる。
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, String> {
+            if value.eq_ignore_ascii_case("none") {
+                return Ok(None);
+            }
+            value
+                .parse::<T>()
+                .map(Some)
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))
+        }
+
+        let value = value.trim();
+        match key {
+            "temperature" => self.temperature = parse(key, value)?,
+            "top_p" => self.top_p = parse(key, value)?,
+            "top_k" => self.top_k = parse(key, value)?,
+            "num_ctx" => self.num_ctx = parse(key, value)?,
+            "seed" => self.seed = parse(key, value)?,
+            "max_tokens" => self.max_tokens = parse(key, value)?,
+            "repeat_penalty" => self.repeat_penalty = parse(key, value)?,
+            "stop" => {
+                self.stop = if value.eq_ignore_ascii_case("none") {
+                    Vec::new()
+                } else {
+                    value
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown option: {}. Available options: {}",
+                    key,
+                    Self::KEYS.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GenerationOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn show<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        }
+
+        writeln!(f, "temperature: {}", show(&self.temperature))?;
+        writeln!(f, "top_p: {}", show(&self.top_p))?;
+        writeln!(f, "top_k: {}", show(&self.top_k))?;
+        writeln!(f, "num_ctx: {}", show(&self.num_ctx))?;
+        writeln!(f, "seed: {}", show(&self.seed))?;
+        if self.stop.is_empty() {
+            writeln!(f, "stop: -")?;
+        } else {
+            writeln!(f, "stop: {}", self.stop.join(", "))?;
+        }
+        writeln!(f, "max_tokens: {}", show(&self.max_tokens))?;
+        write!(f, "repeat_penalty: {}", show(&self.repeat_penalty))
+    }
+}
+
+/// 応答の形式の指定。`/json` コマンドで切り替える
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseFormat {
+    /// 任意のJSON
+    Json,
+    /// JSON Schema に従うJSON
+    JsonSchema(serde_json::Value),
+}
+
+impl ResponseFormat {
+    /// JSON Schema ファイルを読み込む
+    pub fn from_schema_file(path: &Path) -> Result<Self, ApiError> {
+        let content = std::fs::read_to_string(path)?;
+        let schema: serde_json::Value = serde_json::from_str(&content)?;
+        // スキーマ自体が不正な場合はここで検出する
+        jsonschema::validator_for(&schema)
+            .map_err(|e| ApiError::Message(format!("Invalid JSON Schema: {}", e)))?;
+        Ok(ResponseFormat::JsonSchema(schema))
+    }
+
+    /// Ollama の `format` パラメータの値
+    pub fn ollama_format(&self) -> serde_json::Value {
+        match self {
+            ResponseFormat::Json => serde_json::Value::String("json".to_string()),
+            ResponseFormat::JsonSchema(schema) => schema.clone(),
+        }
+    }
+
+    /// 応答が指定した形式に従っているかを検証し、パースしたJSONを返す。
+    /// 従っていない場合はモデルに伝える理由を返す。
+    pub fn validate(&self, content: &str) -> Result<serde_json::Value, String> {
+        // ネイティブのJSONモードが無いプロバイダでは ```json で囲まれることがある
+        let trimmed = content.trim();
+        let json_text = trimmed
+            .strip_prefix("```json")
+            .or_else(|| trimmed.strip_prefix("```"))
+            .and_then(|rest| rest.strip_suffix("```"))
+            .unwrap_or(trimmed);
+        let value: serde_json::Value = serde_json::from_str(json_text)
+            .map_err(|e| format!("The reply is not valid JSON: {}", e))?;
+
+        if let ResponseFormat::JsonSchema(schema) = self {
+            let validator = jsonschema::validator_for(schema)
+                .map_err(|e| format!("Invalid JSON Schema: {}", e))?;
+            let errors: Vec<String> = validator
+                .iter_errors(&value)
+                .map(|error| {
+                    let path = error.instance_path.to_string();
+                    if path.is_empty() {
+                        error.to_string()
+                    } else {
+                        format!("{}: {}", path, error)
+                    }
+                })
+                .collect();
+            if !errors.is_empty() {
+                return Err(format!(
+                    "The reply does not match the JSON Schema: {}",
+                    errors.join("; ")
+                ));
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl fmt::Display for ResponseFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseFormat::Json => write!(f, "JSON"),
+            ResponseFormat::JsonSchema(schema) => match schema["title"].as_str() {
+                Some(title) => write!(f, "JSON Schema ({})", title),
+                None => write!(f, "JSON Schema"),
+            },
+        }
+    }
+}
+
 // Common Error type for API operations
 #[derive(Debug)]
 pub enum ApiError {
     Reqwest(reqwest::Error),
     SerdeJson(serde_json::Error),
     Message(String),
+    /// APIが成功以外のステータスを返した (ステータス, エラーメッセージ)
+    HttpStatus(reqwest::StatusCode, String),
+    /// サーバーに接続できなかった (接続先URLまたは詳細)
+    ConnectionRefused(String),
+    /// 接続または応答の待機がタイムアウトした
+    Timeout(String),
+    /// レート制限に達した (429)。サーバーが待機時間を指定していればそれを持つ
+    RateLimited(Option<Duration>, String),
+    /// 指定したモデルが見つからない
+    ModelNotFound(String),
+    /// 入力がモデルのコンテキスト長を超えた
+    ContextOverflow(String),
+    /// 再試行しても応答が指定したJSONの形式に従わなかった
+    ResponseFormatMismatch(String),
     StreamError(String),
     IoError(std::io::Error),
     UnsupportedOperation(String),
@@ -58,7 +493,23 @@ pub enum ApiError {
 
 impl From<reqwest::Error> for ApiError {
     fn from(err: reqwest::Error) -> Self {
-        ApiError::Reqwest(err)
+        // 接続失敗とタイムアウトはユーザーが対処できるように分類する
+        if err.is_connect() {
+            // 接続先と根本原因 (Connection refused など) を表示する
+            let mut cause: &dyn std::error::Error = &err;
+            while let Some(source) = cause.source() {
+                cause = source;
+            }
+            let target = err
+                .url()
+                .map(|url| url.to_string())
+                .unwrap_or_else(|| "the server".to_string());
+            ApiError::ConnectionRefused(format!("{} ({})", target, cause))
+        } else if err.is_timeout() {
+            ApiError::Timeout(err.to_string())
+        } else {
+            ApiError::Reqwest(err)
+        }
     }
 }
 
@@ -80,6 +531,25 @@ impl std::fmt::Display for ApiError {
             ApiError::Reqwest(e) => write!(f, "Reqwest error: {}", e),
             ApiError::SerdeJson(e) => write!(f, "JSON parsing error: {}", e),
             ApiError::Message(msg) => write!(f, "API error: {}", msg),
+            ApiError::HttpStatus(status, msg) => write!(
+                f,
+                "API error: APIリクエストが失敗しました: ステータス {} - {}",
+                status, msg
+            ),
+            ApiError::ConnectionRefused(target) => write!(f, "Could not connect to {}", target),
+            ApiError::Timeout(msg) => write!(f, "Request timed out: {}", msg),
+            ApiError::RateLimited(_, msg) => write!(f, "Rate limited: {}", msg),
+            ApiError::ModelNotFound(msg) => write!(f, "Model not found: {}", msg),
+            ApiError::ContextOverflow(msg) => {
+                write!(f, "Input exceeds the model's context window: {}", msg)
+            }
+            ApiError::ResponseFormatMismatch(msg) => {
+                write!(
+                    f,
+                    "The response did not match the requested format: {}",
+                    msg
+                )
+            }
             ApiError::IoError(e) => write!(f, "IO error: {}", e),
             ApiError::StreamError(msg) => write!(f, "Stream error: {}", msg),
 
@@ -90,16 +560,246 @@ impl std::fmt::Display for ApiError {
 
 impl std::error::Error for ApiError {}
 
+impl ApiError {
+    /// 成功以外のHTTPステータスとエラーメッセージを分類する
+    pub fn from_status(
+        status: reqwest::StatusCode,
+        message: String,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let lower = message.to_lowercase();
+        let is_context_overflow = [
+            "context length",
+            "context window",
+            "maximum context",
+            "prompt is too long",
+            "too many tokens",
+            "input is too long",
+            "n_ctx",
+        ]
+        .iter()
+        .any(|pattern| lower.contains(pattern));
+
+        match status {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => ApiError::RateLimited(retry_after, message),
+            // 404はURLやパスの誤りでも返るため、本文がモデルについて述べている場合だけモデルの問題とする
+            // (Ollamaの `model '…' not found`、OpenAIの `The model … does not exist`、
+            // Anthropicの `model: …`、Geminiの `models/… is not found`)
+            reqwest::StatusCode::NOT_FOUND if lower.contains("model") => {
+                ApiError::ModelNotFound(message)
+            }
+            reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::PAYLOAD_TOO_LARGE
+                if is_context_overflow =>
+            {
+                ApiError::ContextOverflow(message)
+            }
+            _ => ApiError::HttpStatus(status, message),
+        }
+    }
+
+    /// 別のバックエンドに切り替えれば成功する可能性があるエラーか。
+    /// 接続できない、サーバーエラー (5xx)、モデルが見つからない場合が該当する。
+    pub fn is_backend_unavailable(&self) -> bool {
+        match self {
+            ApiError::ConnectionRefused(_) | ApiError::Timeout(_) | ApiError::ModelNotFound(_) => {
+                true
+            }
+            ApiError::HttpStatus(status, _) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// リクエストに含めたツール定義 (`tools`) をサーバーが受け付けなかったことを示すエラーか。
+    /// ツールに対応していないモデルへのリクエストは、4xxのステータスとツールに触れたメッセージで拒否される
+    pub fn rejects_tools(&self) -> bool {
+        match self {
+            ApiError::HttpStatus(status, message) => {
+                status.is_client_error() && message.to_lowercase().contains("tool")
+            }
+            _ => false,
+        }
+    }
+
+    /// 同じリクエストを再試行すれば成功する可能性がある一時的なエラーか
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Timeout(_) | ApiError::RateLimited(..) => true,
+            ApiError::HttpStatus(status, _) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// ユーザーが取れる対処法
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            ApiError::ConnectionRefused(_) => Some(
+                "Is the server running? For Ollama, start it with `ollama serve` or check OLLAMA_BASE_URL.",
+            ),
+            ApiError::Timeout(_) => Some(
+                "The server did not respond in time. Large models may need longer; raise network.read_timeout_secs in the config file.",
+            ),
+            ApiError::RateLimited(..) => {
+                Some("The provider is rate limiting requests. Wait a moment and try again.")
+            }
+            ApiError::HttpStatus(reqwest::StatusCode::NOT_FOUND, _) => Some(
+                "The server has no such endpoint. Check the base URL (OLLAMA_BASE_URL, OPENAI_BASE_URL, ANTHROPIC_BASE_URL or GEMINI_BASE_URL).",
+            ),
+            ApiError::ModelNotFound(_) => Some(
+                "Check the name with `/list models` and switch with `/model <name>`. For Ollama, download it with `/model pull <name>`.",
+            ),
+            ApiError::ContextOverflow(_) => Some(
+                "The conversation is too long for this model. Use `/clear`, or raise the context length with `/set num_ctx <tokens>`.",
+            ),
+            ApiError::ResponseFormatMismatch(_) => Some(
+                "Simplify the schema, describe the expected fields in your message, or try a larger model. Use `/json off` to turn off JSON mode.",
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// 成功以外のステータスのレスポンスを分類済みの `ApiError` に変換する。
+/// `detail` はレスポンス本文から人間が読めるメッセージを取り出す (取り出せなければ本文をそのまま使う)。
+pub async fn error_from_response(
+    response: reqwest::Response,
+    detail: fn(&str) -> Option<String>,
+) -> ApiError {
+    let status = response.status();
+    let mut url = response.url().clone();
+    url.set_query(None);
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+    let message = detail(&body).unwrap_or(body);
+    match ApiError::from_status(status, message, retry_after) {
+        // モデル以外が見つからない場合は、ベースURLかパスが誤っていることが多いため、URLを添える
+        ApiError::HttpStatus(status, message) if status == reqwest::StatusCode::NOT_FOUND => {
+            ApiError::HttpStatus(status, format!("{} ({})", message.trim(), url))
+        }
+        error => error,
+    }
+}
+
+/// ストリームの最初の要素を先読みする。
+/// 最初の要素がエラーであれば、まだ何も表示していないのでストリームではなくエラーとして返す。
+pub async fn peek_stream(
+    mut stream: ChatCompletionStream,
+) -> Result<ChatCompletionStream, ApiError> {
+    match stream.next().await {
+        Some(Err(e)) => Err(e),
+        Some(Ok(first)) => Ok(futures_util::stream::once(async { Ok(first) })
+            .chain(stream)
+            .boxed()),
+        None => Ok(stream),
+    }
+}
+
+/// HTTP通信の設定。設定ファイルの `network` に書く
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// 接続のタイムアウト (秒)
+    pub connect_timeout_secs: u64,
+    /// 応答を待つ時間の上限 (秒)。ストリーム中は次のデータが届くまでの時間に適用される
+    pub read_timeout_secs: u64,
+    /// 最初のトークンが届く前に一時的なエラーが起きた場合の再試行回数
+    pub max_retries: u32,
+    /// 最初の再試行までの待機時間 (ミリ秒)。再試行のたびに2倍になる
+    pub initial_backoff_ms: u64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            connect_timeout_secs: 10,
+            // 大きなモデルの読み込みには時間がかかるため長めにする
+            read_timeout_secs: 300,
+            max_retries: 3,
+            initial_backoff_ms: 500,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// タイムアウトを設定したHTTPクライアントを作成する
+    pub fn build_client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .read_timeout(Duration::from_secs(self.read_timeout_secs))
+            .build()
+            .unwrap_or_else(|_| reqwest::Client::new())
+    }
+
+    /// `attempt` 回目 (0始まり) の再試行までの待機時間
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms.saturating_mul(1 << attempt.min(16)))
+    }
+}
+
 /// Trait for AI API implementations (Ollama, Gemini, etc.)
 #[async_trait]
 pub trait AIApiTrait: Send + Sync {
     fn set_model(&mut self, model_name: String);
     fn get_model(&self) -> String;
+    fn set_generation_options(&mut self, options: GenerationOptions);
+    fn get_generation_options(&self) -> GenerationOptions;
+    /// タイムアウトと再試行の設定を反映する
+    fn set_network_config(&mut self, config: NetworkConfig);
+    /// 応答の形式を指定する。`None` で通常のテキストに戻す
+    fn set_response_format(&mut self, format: Option<ResponseFormat>);
+    /// `embed` に使うモデルを指定する。`None` ならプロバイダの既定に従う
+    fn set_embedding_model(&mut self, _model: Option<String>) {}
     async fn list_models(&self) -> Result<serde_json::Value, ApiError>;
+    /// チャット応答をストリームで取得する。
+    /// `tools` が指定された場合、対応するプロバイダはネイティブのツール定義としてモデルに渡す。
     async fn get_chat_completion_stream(
         &self,
         messages: Vec<ChatMessage>,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String, ApiError>> + Send>>, ApiError>;
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError>;
+    /// 現在のモデルがネイティブのツール呼び出しに対応しているか
+    async fn supports_native_tools(&self) -> bool {
+        false
+    }
+    /// 現在のモデルで実際に使われるコンテキスト長 (トークン数)。分からない場合は `None`
+    async fn context_length(&self) -> Option<usize> {
+        None
+    }
+    /// テキストごとの埋め込みベクトルを現在のモデルで計算する。
+    /// 戻り値は `texts` と同じ順序で並ぶ
+    async fn embed(&self, _texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        Err(ApiError::UnsupportedOperation(
+            "This provider does not support embeddings.".to_string(),
+        ))
+    }
+    /// モデルをダウンロードし、進捗をストリームで返す (Ollamaのみ)
+    async fn pull_model(&self, _name: &str) -> Result<PullProgressStream, ApiError> {
+        Err(ApiError::UnsupportedOperation(
+            "This provider does not support pulling models.".to_string(),
+        ))
+    }
+    /// ローカルのモデルを削除する (Ollamaのみ)
+    async fn delete_model(&self, _name: &str) -> Result<(), ApiError> {
+        Err(ApiError::UnsupportedOperation(
+            "This provider does not support deleting models.".to_string(),
+        ))
+    }
+    /// ローカルのモデルを別名で複製する (Ollamaのみ)
+    async fn copy_model(&self, _source: &str, _destination: &str) -> Result<(), ApiError> {
+        Err(ApiError::UnsupportedOperation(
+            "This provider does not support copying models.".to_string(),
+        ))
+    }
+    /// モデルの詳細 (パラメータ数、量子化、コンテキスト長など) を取得する (Ollamaのみ)
+    async fn show_model(&self, _name: &str) -> Result<serde_json::Value, ApiError> {
+        Err(ApiError::UnsupportedOperation(
+            "This provider does not support showing model details.".to_string(),
+        ))
+    }
     fn clone_box(&self) -> Box<dyn AIApiTrait>;
 }
 
@@ -107,6 +807,63 @@ pub trait AIApiTrait: Send + Sync {
 pub enum AIProvider {
     Ollama,
     Gemini,
+    /// OpenAI互換の Chat Completions API (任意のBearerトークン付き)
+    OpenAiCompatible {
+        api_key: Option<String>,
+    },
+    /// Anthropic Messages API
+    Anthropic,
+    /// 記録済みの応答を再生する (`--provider=replay:<dir>`)
+    Replay {
+        dir: PathBuf,
+    },
+}
+
+impl AIProvider {
+    /// 設定ファイルやコマンドラインで使うプロバイダ名から作成する
+    pub fn from_name(name: &str, api_key: Option<String>) -> Option<Self> {
+        match name {
+            "ollama" => Some(AIProvider::Ollama),
+            "gemini" => Some(AIProvider::Gemini),
+            "anthropic" => Some(AIProvider::Anthropic),
+            "openai" => Some(AIProvider::OpenAiCompatible {
+                api_key: api_key.or_else(|| std::env::var("OPENAI_API_KEY").ok()),
+            }),
+            _ => name
+                .strip_prefix("replay:")
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| AIProvider::Replay {
+                    dir: PathBuf::from(dir),
+                }),
+        }
+    }
+
+    /// 表示やログに使うプロバイダ名
+    pub fn name(&self) -> &'static str {
+        match self {
+            AIProvider::Ollama => "ollama",
+            AIProvider::Gemini => "gemini",
+            AIProvider::OpenAiCompatible { .. } => "openai",
+            AIProvider::Anthropic => "anthropic",
+            AIProvider::Replay { .. } => "replay",
+        }
+    }
+
+    /// 既定のベースURL。環境変数 (`OLLAMA_BASE_URL` など) が設定されていればそれを使う
+    pub fn default_base_url(&self) -> String {
+        let (env_var, default) = match self {
+            // 再生時はフィクスチャのディレクトリをベースURLの代わりに表示する
+            AIProvider::Replay { dir } => return dir.display().to_string(),
+            AIProvider::Ollama => ("OLLAMA_BASE_URL", "http://localhost:11434"),
+            AIProvider::Gemini => (
+                "GEMINI_BASE_URL",
+                "https://generativelanguage.googleapis.com",
+            ),
+            AIProvider::OpenAiCompatible { .. } => ("OPENAI_BASE_URL", "http://localhost:8080"),
+            AIProvider::Anthropic => ("ANTHROPIC_BASE_URL", "https://api.anthropic.com"),
+        };
+        std::env::var(env_var).unwrap_or_else(|_| default.to_string())
+    }
 }
 
 /// Main API struct that holds a boxed trait object
@@ -124,22 +881,85 @@ impl Clone for AIApi {
 
 impl AIApi {
     pub fn new(provider: AIProvider, base_url: String, default_model: String) -> Self {
-        match provider {
-            AIProvider::Ollama => {
-                let ollama_api = ollama::OllamaApi::new(base_url, default_model);
-                AIApi {
-                    inner: Box::new(ollama_api),
-                }
-            }
-            AIProvider::Gemini => {
-                let gemini_api = gemini::GeminiApi::new(base_url, default_model);
-                AIApi {
-                    inner: Box::new(gemini_api),
+        AIApi {
+            inner: Self::build_backend(provider, base_url, default_model),
+        }
+    }
+
+    /// 主となるバックエンドの後に、設定ファイルで指定された予備のバックエンドを順に試すAPIを作成する。
+    /// `fallbacks` が空であれば `new` と同じ。
+    pub fn with_fallbacks(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+        fallbacks: &[fallback::BackendConfig],
+    ) -> Self {
+        if fallbacks.is_empty() {
+            return Self::new(provider, base_url, default_model);
+        }
+
+        let mut backends = vec![fallback::FallbackBackend::new(
+            provider.name(),
+            Self::build_backend(provider, base_url, default_model),
+        )];
+        for config in fallbacks {
+            let Some(provider) = AIProvider::from_name(&config.provider, config.api_key.clone())
+            else {
+                eprintln!(
+                    "不明なプロバイダ '{}' のフォールバック設定を無視します。",
+                    config.provider
+                );
+                continue;
+            };
+            let base_url = config
+                .base_url
+                .clone()
+                .unwrap_or_else(|| provider.default_base_url());
+            backends.push(fallback::FallbackBackend::new(
+                provider.name(),
+                Self::build_backend(provider, base_url, config.model.clone()),
+            ));
+        }
+
+        AIApi {
+            inner: Box::new(fallback::FallbackApi::new(backends)),
+        }
+    }
+
+    fn build_backend(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+    ) -> Box<dyn AIApiTrait> {
+        let backend: Box<dyn AIApiTrait> =
+            match provider {
+                AIProvider::Ollama => Box::new(ollama::OllamaApi::new(base_url, default_model)),
+                AIProvider::Gemini => Box::new(gemini::GeminiApi::new(base_url, default_model)),
+                AIProvider::OpenAiCompatible { api_key } => Box::new(
+                    openai::OpenAiCompatibleApi::new(base_url, default_model, api_key),
+                ),
+                AIProvider::Anthropic => {
+                    Box::new(anthropic::AnthropicApi::new(base_url, default_model))
                 }
-            }
+                AIProvider::Replay { dir } => Box::new(replay::ReplayApi::new(dir, default_model)),
+            };
+        // 一時的なエラーはバックエンドごとに再試行してから、フォールバックに進む
+        Box::new(retry::RetryApi::new(backend))
+    }
+
+    /// チャットのリクエストと応答を `dir` にフィクスチャとして保存するようにする (`--record=<dir>`)
+    pub fn recording(self, dir: PathBuf) -> Self {
+        AIApi {
+            inner: Box::new(replay::RecordingApi::new(self.inner, dir)),
         }
     }
 
+    /// 任意のバックエンドから作成する (テストで台本どおりに応答するバックエンドを使うため)
+    #[cfg(test)]
+    pub fn from_backend(backend: Box<dyn AIApiTrait>) -> Self {
+        AIApi { inner: backend }
+    }
+
     pub fn set_model(&mut self, model_name: String) {
         self.inner.set_model(model_name);
     }
@@ -148,6 +968,26 @@ impl AIApi {
         self.inner.get_model()
     }
 
+    pub fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.inner.set_generation_options(options);
+    }
+
+    pub fn get_generation_options(&self) -> GenerationOptions {
+        self.inner.get_generation_options()
+    }
+
+    pub fn set_network_config(&mut self, config: NetworkConfig) {
+        self.inner.set_network_config(config);
+    }
+
+    pub fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.inner.set_response_format(format);
+    }
+
+    pub fn set_embedding_model(&mut self, model: Option<String>) {
+        self.inner.set_embedding_model(model);
+    }
+
     pub async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
         self.inner.list_models().await
     }
@@ -155,7 +995,174 @@ impl AIApi {
     pub async fn get_chat_completion_stream(
         &self,
         messages: Vec<ChatMessage>,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String, ApiError>> + Send>>, ApiError> {
-        self.inner.get_chat_completion_stream(messages).await
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        self.inner.get_chat_completion_stream(messages, tools).await
+    }
+
+    pub async fn supports_native_tools(&self) -> bool {
+        self.inner.supports_native_tools().await
+    }
+
+    pub async fn context_length(&self) -> Option<usize> {
+        self.inner.context_length().await
+    }
+
+    pub async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.inner.embed(texts).await
+    }
+
+    pub async fn pull_model(&self, name: &str) -> Result<PullProgressStream, ApiError> {
+        self.inner.pull_model(name).await
+    }
+
+    pub async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.inner.delete_model(name).await
+    }
+
+    pub async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.inner.copy_model(source, destination).await
+    }
+
+    pub async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        self.inner.show_model(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn classifies_status_errors() {
+        assert!(matches!(
+            ApiError::from_status(StatusCode::NOT_FOUND, "model 'x' not found".into(), None),
+            ApiError::ModelNotFound(_)
+        ));
+        assert!(matches!(
+            ApiError::from_status(
+                StatusCode::NOT_FOUND,
+                "models/gemini-x is not found for API version v1beta".into(),
+                None
+            ),
+            ApiError::ModelNotFound(_)
+        ));
+        // URLやパスの誤りによる404はモデルの問題として扱わない (予備のバックエンドにも切り替えない)
+        let wrong_path =
+            ApiError::from_status(StatusCode::NOT_FOUND, "404 page not found".into(), None);
+        assert!(matches!(
+            wrong_path,
+            ApiError::HttpStatus(StatusCode::NOT_FOUND, _)
+        ));
+        assert!(!wrong_path.is_backend_unavailable());
+        assert!(matches!(
+            ApiError::from_status(
+                StatusCode::TOO_MANY_REQUESTS,
+                "slow down".into(),
+                Some(Duration::from_secs(2))
+            ),
+            ApiError::RateLimited(Some(d), _) if d == Duration::from_secs(2)
+        ));
+        assert!(matches!(
+            ApiError::from_status(
+                StatusCode::BAD_REQUEST,
+                "This model's maximum context length is 8192 tokens".into(),
+                None
+            ),
+            ApiError::ContextOverflow(_)
+        ));
+        assert!(matches!(
+            ApiError::from_status(StatusCode::BAD_REQUEST, "invalid json".into(), None),
+            ApiError::HttpStatus(StatusCode::BAD_REQUEST, _)
+        ));
+    }
+
+    #[test]
+    fn retryable_and_failover_errors() {
+        let unavailable =
+            ApiError::from_status(StatusCode::SERVICE_UNAVAILABLE, String::new(), None);
+        assert!(unavailable.is_retryable());
+        assert!(unavailable.is_backend_unavailable());
+
+        let missing = ApiError::ModelNotFound("x".into());
+        assert!(!missing.is_retryable());
+        assert!(missing.is_backend_unavailable());
+
+        let refused = ApiError::ConnectionRefused("http://localhost:11434".into());
+        assert!(!refused.is_retryable());
+        assert!(refused.is_backend_unavailable());
+        assert!(refused.hint().is_some());
+
+        let bad_request = ApiError::from_status(StatusCode::BAD_REQUEST, String::new(), None);
+        assert!(!bad_request.is_retryable());
+        assert!(!bad_request.is_backend_unavailable());
+    }
+
+    #[test]
+    fn backoff_doubles_each_attempt() {
+        let config = NetworkConfig {
+            initial_backoff_ms: 100,
+            ..NetworkConfig::default()
+        };
+        assert_eq!(config.backoff(0), Duration::from_millis(100));
+        assert_eq!(config.backoff(1), Duration::from_millis(200));
+        assert_eq!(config.backoff(3), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn pull_progress_shows_percent_only_while_downloading() {
+        let manifest = PullProgress {
+            status: "pulling manifest".to_string(),
+            ..PullProgress::default()
+        };
+        assert_eq!(manifest.percent(), None);
+        assert_eq!(manifest.to_string(), "pulling manifest");
+
+        let layer = PullProgress {
+            status: "pulling 6a0746a1ec1a".to_string(),
+            digest: Some("sha256:6a0746a1ec1a".to_string()),
+            total: Some(4_000_000),
+            completed: Some(1_000_000),
+        };
+        assert_eq!(layer.percent(), Some(25.0));
+        assert_eq!(
+            layer.to_string(),
+            "pulling 6a0746a1ec1a: 25.0% (1.0 / 4.0 MB)"
+        );
+    }
+
+    #[test]
+    fn validates_replies_against_the_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "city": { "type": "string" }, "temp": { "type": "number" } },
+            "required": ["city", "temp"],
+        });
+        let format = ResponseFormat::JsonSchema(schema);
+
+        assert!(
+            format
+                .validate(r#"{"city": "Tokyo", "temp": 21.5}"#)
+                .is_ok()
+        );
+        // コードフェンスで囲まれていても受け付ける
+        assert!(
+            format
+                .validate("```json\n{\"city\": \"Tokyo\", \"temp\": 21}\n```")
+                .is_ok()
+        );
+        let missing = format.validate(r#"{"city": "Tokyo"}"#).unwrap_err();
+        assert!(missing.contains("temp"), "{}", missing);
+        let wrong_type = format
+            .validate(r#"{"city": "Tokyo", "temp": "hot"}"#)
+            .unwrap_err();
+        assert!(wrong_type.contains("/temp"), "{}", wrong_type);
+
+        assert!(ResponseFormat::Json.validate("[1, 2]").is_ok());
+        assert!(ResponseFormat::Json.validate("Sure! Here it is").is_err());
     }
 }
@@ -0,0 +1,503 @@
+// src/modules/agent/api/anthropic.rs
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use futures_util::TryStreamExt;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::boxed::Box;
+
+use crate::modules::agent::api::decoder::{Frame, Framing, decode_stream};
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatRole, ChatStreamChunk,
+    GenerationOptions, NetworkConfig, ResponseFormat, TokenUsage, error_from_response,
+};
+
+/// Messages APIのバージョンヘッダー
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// Messages APIで必須の `max_tokens` の既定値
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Messages APIに送る1ターン分のメッセージ (`user` または `assistant`)
+#[derive(Serialize, Debug, Clone)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct MessagesRequest {
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub messages: Vec<AnthropicMessage>,
+    pub max_tokens: u32,
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+/// `content_block_delta` イベントの `delta`
+#[derive(Deserialize, Default)]
+pub struct ContentDelta {
+    #[serde(rename = "type", default)]
+    pub delta_type: String,
+    #[serde(default)]
+    pub text: String,
+    /// 拡張思考が有効な場合の `thinking_delta` の内容
+    #[serde(default)]
+    pub thinking: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AnthropicErrorBody {
+    #[serde(rename = "type", default)]
+    pub error_type: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// `message_start` の `message.usage` と `message_delta` の `usage`
+#[derive(Deserialize, Default)]
+pub struct AnthropicUsage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+}
+
+/// `message_start` イベントの `message`
+#[derive(Deserialize, Default)]
+pub struct MessageStart {
+    pub usage: Option<AnthropicUsage>,
+}
+
+/// SSEで届くイベント。`type` フィールドで種類を判別する
+#[derive(Deserialize, Default)]
+pub struct StreamEvent {
+    #[serde(rename = "type", default)]
+    pub event_type: String,
+    pub delta: Option<ContentDelta>,
+    pub error: Option<AnthropicErrorBody>,
+    pub message: Option<MessageStart>,
+    pub usage: Option<AnthropicUsage>,
+}
+
+/// Anthropic Messages API のクライアント
+#[derive(Debug, Clone)]
+pub struct AnthropicApi {
+    client: Client,
+    base_url: String,
+    default_model: String,
+    api_key: Option<String>,
+    options: GenerationOptions,
+}
+
+impl AnthropicApi {
+    pub fn new(base_url: String, default_model: String) -> Self {
+        // APIキーは環境変数から取得する
+        let api_key = std::env::var("ANTHROPIC_API_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty());
+        AnthropicApi {
+            client: NetworkConfig::default().build_client(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            default_model,
+            api_key,
+            options: GenerationOptions::default(),
+        }
+    }
+
+    /// 認証ヘッダーとバージョンヘッダーを付与する
+    fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder, ApiError> {
+        let api_key = self.api_key.as_deref().ok_or_else(|| {
+            ApiError::Message(
+                "Anthropic APIキーが設定されていません。環境変数 ANTHROPIC_API_KEY を設定してください。"
+                    .to_string(),
+            )
+        })?;
+        Ok(request
+            .header("x-api-key", api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION))
+    }
+
+    /// 共通の `ChatMessage` 履歴を Messages API の `system` と `messages` に変換する。
+    /// システムメッセージは `system` にまとめ、user/assistant が交互になるよう連続する同じロールを結合する。
+    /// 会話は user から始まる必要があるため、最初の user より前の assistant の発言は送らない。
+    fn build_request(&self, messages: Vec<ChatMessage>) -> MessagesRequest {
+        let mut system_prompts: Vec<String> = Vec::new();
+        let mut turns: Vec<AnthropicMessage> = Vec::new();
+
+        for message in messages {
+            let role = match message.role {
+                ChatRole::System => {
+                    system_prompts.push(message.content);
+                    continue;
+                }
+                ChatRole::Assistant => "assistant",
+                // ネイティブのツール呼び出しには対応していないため、ツールの結果はテキストとして送る
+                ChatRole::User | ChatRole::Tool => "user",
+            };
+            // 空のコンテンツは API に拒否されるため送らない
+            if message.content.trim().is_empty() || (role == "assistant" && turns.is_empty()) {
+                continue;
+            }
+
+            match turns.last_mut() {
+                Some(last) if last.role == role => {
+                    last.content.push_str("\n\n");
+                    last.content.push_str(&message.content);
+                }
+                _ => turns.push(AnthropicMessage {
+                    role: role.to_string(),
+                    content: message.content,
+                }),
+            }
+        }
+
+        MessagesRequest {
+            model: self.default_model.clone(),
+            system: (!system_prompts.is_empty()).then(|| system_prompts.join("\n\n")),
+            messages: turns,
+            max_tokens: self.options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            stream: true,
+            // seed, num_ctx, repeat_penalty に相当する設定は Messages API には無い
+            temperature: self.options.temperature,
+            top_p: self.options.top_p,
+            top_k: self.options.top_k,
+            stop_sequences: self.options.stop.clone(),
+        }
+    }
+
+    /// エラーレスポンスの本文から人間が読めるメッセージを取り出す
+    fn error_detail(body: &str) -> Option<String> {
+        serde_json::from_str::<StreamEvent>(body)
+            .ok()
+            .and_then(|event| event.error)
+            .map(|error| format!("{} ({})", error.message, error.error_type))
+    }
+
+    /// SSEイベント1つ分の `data:` ペイロードをパースし、テキストまたは使用量を取り出す
+    fn parse_event_data(data: &str) -> Result<Option<ChatStreamChunk>, ApiError> {
+        let event: StreamEvent = serde_json::from_str(data)?;
+        match event.event_type.as_str() {
+            "content_block_delta" => {
+                Ok(event
+                    .delta
+                    .and_then(|delta| match delta.delta_type.as_str() {
+                        "text_delta" if !delta.text.is_empty() => {
+                            Some(ChatStreamChunk::Content(delta.text))
+                        }
+                        "thinking_delta" if !delta.thinking.is_empty() => {
+                            Some(ChatStreamChunk::Reasoning(delta.thinking))
+                        }
+                        _ => None,
+                    }))
+            }
+            // 入力トークン数は message_start で届く
+            "message_start" => Ok(event
+                .message
+                .and_then(|message| message.usage)
+                .map(|usage| {
+                    ChatStreamChunk::Usage(TokenUsage {
+                        prompt_tokens: usage.input_tokens,
+                        ..TokenUsage::default()
+                    })
+                })),
+            // 出力トークン数の累計は message_delta で届く
+            "message_delta" => Ok(event.usage.map(|usage| {
+                ChatStreamChunk::Usage(TokenUsage {
+                    completion_tokens: usage.output_tokens,
+                    ..TokenUsage::default()
+                })
+            })),
+            // content_block_start, ping, message_stop などは本文を持たない。
+            // `error` イベントはデコーダが `ApiError` に変換済み
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for AnthropicApi {
+    fn set_model(&mut self, model_name: String) {
+        self.default_model = model_name;
+    }
+
+    fn get_model(&self) -> String {
+        self.default_model.clone()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.options = options;
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.options.clone()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.client = config.build_client();
+    }
+
+    fn set_response_format(&mut self, _format: Option<ResponseFormat>) {
+        // Messages API にはJSONモードが無いため、エージェント側の検証と再プロンプトに任せる
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        let url = format!("{}/v1/models", self.base_url);
+        let response = self
+            .authorize(self.client.get(&url))?
+            .query(&[("limit", "1000")])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        // フロントエンドが扱いやすいように Ollama の `/api/tags` と同じ形に揃える
+        let body: serde_json::Value = response.json().await?;
+        let models: Vec<serde_json::Value> = body["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|model| model["id"].as_str())
+            .map(|id| json!({ "name": id }))
+            .collect();
+        Ok(json!({ "models": models }))
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        _tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        if messages
+            .iter()
+            .any(|message| !message.attachments.is_empty())
+        {
+            return Err(ApiError::UnsupportedOperation(
+                "画像の添付はAnthropic APIではまだサポートされていません。".to_string(),
+            ));
+        }
+        let request_body = self.build_request(messages);
+
+        let url = format!("{}/v1/messages", self.base_url);
+        let response = self
+            .authorize(self.client.post(&url))?
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        let stream = decode_stream(response.bytes_stream(), Framing::Sse)
+            .try_filter_map(|frame: Frame| async move { Self::parse_event_data(&frame.data) })
+            .boxed();
+
+        Ok(stream)
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::testing::{FakeHttp, FakeReply, collect_reply};
+
+    fn fake_api(server: &FakeHttp) -> AnthropicApi {
+        AnthropicApi {
+            api_key: Some("test-key".to_string()),
+            ..AnthropicApi::new(server.base_url.clone(), "claude-test".to_string())
+        }
+    }
+
+    fn turns(request: &MessagesRequest) -> Vec<(&str, &str)> {
+        request
+            .messages
+            .iter()
+            .map(|turn| (turn.role.as_str(), turn.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn merges_turns_into_alternating_roles() {
+        let api = AnthropicApi::new("http://localhost".to_string(), "claude-test".to_string());
+        let request = api.build_request(vec![
+            ChatMessage::system("Be brief."),
+            ChatMessage::assistant("Earlier reply"),
+            ChatMessage::user("List the files."),
+            ChatMessage::assistant("Running ls."),
+            ChatMessage::assistant(""),
+            ChatMessage::new(ChatRole::Tool, "a.txt"),
+            ChatMessage::user("Thanks."),
+            ChatMessage::system("Answer in English."),
+        ]);
+
+        assert_eq!(
+            request.system.as_deref(),
+            Some("Be brief.\n\nAnswer in English.")
+        );
+        // 先頭の assistant の発言は送らず、user の内容を作り足すこともしない
+        assert_eq!(
+            turns(&request),
+            vec![
+                ("user", "List the files."),
+                ("assistant", "Running ls."),
+                ("user", "a.txt\n\nThanks."),
+            ]
+        );
+        assert_eq!(request.max_tokens, DEFAULT_MAX_TOKENS);
+        assert!(request.stream);
+
+        let request = api.build_request(vec![ChatMessage::assistant("Only a reply")]);
+        assert!(turns(&request).is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_deltas_and_usage_from_sse_events() {
+        let server = FakeHttp::start(vec![
+            FakeReply::sse_events(&[
+                (
+                    "message_start",
+                    json!({
+                        "type": "message_start",
+                        "message": { "id": "msg_1", "usage": { "input_tokens": 21, "output_tokens": 1 } },
+                    }),
+                ),
+                (
+                    "content_block_start",
+                    json!({
+                        "type": "content_block_start",
+                        "index": 0,
+                        "content_block": { "type": "thinking", "thinking": "" },
+                    }),
+                ),
+                (
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": 0,
+                        "delta": { "type": "thinking_delta", "thinking": "The user greets me." },
+                    }),
+                ),
+                ("ping", json!({ "type": "ping" })),
+                (
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": 1,
+                        "delta": { "type": "text_delta", "text": "こんにちは、" },
+                    }),
+                ),
+                (
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": 1,
+                        "delta": { "type": "text_delta", "text": "世界" },
+                    }),
+                ),
+                (
+                    "message_delta",
+                    json!({
+                        "type": "message_delta",
+                        "delta": { "stop_reason": "end_turn" },
+                        "usage": { "output_tokens": 9 },
+                    }),
+                ),
+                ("message_stop", json!({ "type": "message_stop" })),
+            ]),
+            FakeReply::sse_events(&[
+                (
+                    "content_block_delta",
+                    json!({
+                        "type": "content_block_delta",
+                        "index": 0,
+                        "delta": { "type": "text_delta", "text": "Partial" },
+                    }),
+                ),
+                (
+                    "error",
+                    json!({
+                        "type": "error",
+                        "error": { "type": "overloaded_error", "message": "Overloaded" },
+                    }),
+                ),
+            ]),
+        ])
+        .await;
+        let api = fake_api(&server);
+
+        let stream = api
+            .get_chat_completion_stream(vec![ChatMessage::user("Hi")], None)
+            .await
+            .unwrap();
+        let reply = collect_reply(stream).await.unwrap();
+        assert_eq!(reply.content, "こんにちは、世界");
+        assert_eq!(reply.reasoning, "The user greets me.");
+        // 入力トークン数は message_start の値が残り、出力トークン数は message_delta の値で上書きされる
+        let usage = reply.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, Some(21));
+        assert_eq!(usage.completion_tokens, Some(9));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("test-key"));
+        assert_eq!(
+            requests[0].header("anthropic-version"),
+            Some(ANTHROPIC_VERSION)
+        );
+        assert_eq!(
+            requests[0].body["messages"],
+            json!([{ "role": "user", "content": "Hi" }])
+        );
+
+        // ストリームの途中の `error` イベントはエラーとして返す
+        let stream = api
+            .get_chat_completion_stream(vec![ChatMessage::user("Hi")], None)
+            .await
+            .unwrap();
+        match collect_reply(stream).await {
+            Err(ApiError::Message(message)) => {
+                assert!(message.contains("Overloaded (overloaded_error)"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_error_body_of_a_failed_request() {
+        let server = FakeHttp::start(vec![FakeReply::Status(
+            401,
+            json!({
+                "type": "error",
+                "error": { "type": "authentication_error", "message": "invalid x-api-key" },
+            }),
+        )])
+        .await;
+
+        let error = fake_api(&server)
+            .get_chat_completion_stream(vec![ChatMessage::user("Hi")], None)
+            .await
+            .err()
+            .unwrap();
+        match error {
+            ApiError::HttpStatus(status, message) => {
+                assert_eq!(status, reqwest::StatusCode::UNAUTHORIZED);
+                assert_eq!(message, "invalid x-api-key (authentication_error)");
+            }
+            error => panic!("unexpected error: {:?}", error),
+        }
+    }
+}
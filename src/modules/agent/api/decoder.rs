@@ -0,0 +1,407 @@
+// src/modules/agent/api/decoder.rs
+use async_stream::stream;
+use bytes::Bytes;
+use futures_util::stream::{Stream, StreamExt};
+
+use crate::modules::agent::api::ApiError;
+
+/// プロバイダのストリーム応答のフレーミング形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// 1行に1つのJSONオブジェクト (Ollama)
+    Ndjson,
+    /// Server-Sent Events の `data:` フィールド (Gemini, OpenAI互換, Anthropic)
+    Sse,
+}
+
+/// デコードされた1フレーム分のペイロード
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Frame {
+    /// SSEの `event:` フィールド (NDJSONでは常に `None`)
+    pub event: Option<String>,
+    /// JSONペイロード
+    pub data: String,
+}
+
+/// バイトチャンクを行単位でバッファリングし、完全なフレームだけを取り出すデコーダ。
+/// チャンクの境界が行の途中やマルチバイト文字の途中にあっても、行が揃うまで保持する。
+#[derive(Debug)]
+pub struct StreamDecoder {
+    framing: Framing,
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data_lines: Vec<String>,
+    finished: bool,
+}
+
+impl StreamDecoder {
+    pub fn new(framing: Framing) -> Self {
+        StreamDecoder {
+            framing,
+            buffer: Vec::new(),
+            event: None,
+            data_lines: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// 終端 (`[DONE]` や `"done": true`) に到達したかどうか
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// チャンクを追加し、このチャンクで完成したフレームを返す。
+    /// エラーが発生した場合は、それまでに完成したフレームの後にエラーを1つ返し、以降の入力は無視する。
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<Frame, ApiError>> {
+        let mut frames = Vec::new();
+        if self.finished {
+            return frames;
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        // `\n` はUTF-8のマルチバイト列の途中に現れないため、行単位で切り出せば文字が分断されることはない
+        while let Some(newline) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+            let frame =
+                Self::decode_line(&line[..line.len() - 1]).and_then(|line| self.handle_line(&line));
+            if self.collect(frame, &mut frames) {
+                self.buffer.clear();
+                break;
+            }
+        }
+        frames
+    }
+
+    /// ストリームの終端で、改行で終わっていない残りのデータを処理する
+    pub fn finish(&mut self) -> Vec<Result<Frame, ApiError>> {
+        let mut frames = Vec::new();
+        if self.finished {
+            return frames;
+        }
+
+        let rest = std::mem::take(&mut self.buffer);
+        if !rest.is_empty() {
+            let frame = Self::decode_line(&rest).and_then(|line| self.handle_line(&line));
+            if self.collect(frame, &mut frames) {
+                return frames;
+            }
+        }
+        // SSEでは最後のイベントが空行で閉じられていない場合がある
+        if self.framing == Framing::Sse {
+            let frame = self.dispatch_event();
+            self.collect(frame, &mut frames);
+        }
+        self.finished = true;
+        frames
+    }
+
+    /// 1行分の処理結果を出力に追加し、これ以上入力を処理すべきでなければ `true` を返す
+    fn collect(
+        &mut self,
+        frame: Result<Option<Frame>, ApiError>,
+        frames: &mut Vec<Result<Frame, ApiError>>,
+    ) -> bool {
+        match frame {
+            Ok(Some(frame)) => frames.push(Ok(frame)),
+            Ok(None) => {}
+            Err(e) => {
+                frames.push(Err(e));
+                self.finished = true;
+            }
+        }
+        self.finished
+    }
+
+    fn decode_line(bytes: &[u8]) -> Result<String, ApiError> {
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        String::from_utf8(bytes.to_vec())
+            .map_err(|e| ApiError::StreamError(format!("Invalid UTF-8 sequence: {}", e)))
+    }
+
+    fn handle_line(&mut self, line: &str) -> Result<Option<Frame>, ApiError> {
+        match self.framing {
+            Framing::Ndjson => {
+                let data = line.trim();
+                if data.is_empty() {
+                    return Ok(None);
+                }
+                self.check_payload(data)?;
+                Ok(Some(Frame {
+                    event: None,
+                    data: data.to_string(),
+                }))
+            }
+            Framing::Sse => {
+                if line.is_empty() {
+                    return self.dispatch_event();
+                }
+                if line.starts_with(':') {
+                    // コメント行 (keep-alive など)
+                    return Ok(None);
+                }
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match field {
+                    "data" => self.data_lines.push(value.to_string()),
+                    "event" => self.event = Some(value.to_string()),
+                    // id, retry などは使用しない
+                    _ => {}
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// 蓄積したSSEの `data:` 行を1つのフレームにまとめる
+    fn dispatch_event(&mut self) -> Result<Option<Frame>, ApiError> {
+        let event = self.event.take();
+        if self.data_lines.is_empty() {
+            return Ok(None);
+        }
+        let data = std::mem::take(&mut self.data_lines).join("\n");
+        if data.trim() == "[DONE]" {
+            self.finished = true;
+            return Ok(None);
+        }
+        self.check_payload(&data)?;
+        Ok(Some(Frame { event, data }))
+    }
+
+    /// ペイロードがエラーオブジェクトであればエラーを返し、`"done": true` であれば終端として記録する
+    fn check_payload(&mut self, data: &str) -> Result<(), ApiError> {
+        if !data.contains("\"error\"") && !data.contains("\"done\"") {
+            return Ok(());
+        }
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(data) else {
+            return Ok(());
+        };
+        if let Some(error) = value.get("error").filter(|error| !error.is_null()) {
+            // `ApiError` の表示で "API error: " が付くため、ここでは前置きを付けない
+            return Err(ApiError::Message(Self::describe_error(error)));
+        }
+        if value.get("done").and_then(|done| done.as_bool()) == Some(true) {
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    /// プロバイダごとに異なるエラーオブジェクトの形式から、メッセージを取り出す
+    fn describe_error(error: &serde_json::Value) -> String {
+        if let Some(message) = error.as_str() {
+            return message.to_string();
+        }
+        let message = error["message"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| error.to_string());
+        match error["type"].as_str().or_else(|| error["status"].as_str()) {
+            Some(kind) => format!("{} ({})", message, kind),
+            None => message,
+        }
+    }
+}
+
+/// HTTPレスポンスのバイトストリームをフレームのストリームに変換する。
+/// 終端に到達するか、エラーが発生した時点でストリームを終了する。
+pub fn decode_stream<S, E>(
+    body: S,
+    framing: Framing,
+) -> impl Stream<Item = Result<Frame, ApiError>> + Send
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<ApiError> + Send,
+{
+    stream! {
+        let mut decoder = StreamDecoder::new(framing);
+        let mut body = Box::pin(body);
+
+        while let Some(chunk) = body.next().await {
+            let frames = match chunk {
+                Ok(bytes) => decoder.push(&bytes),
+                Err(e) => vec![Err(e.into())],
+            };
+            for frame in frames {
+                let is_error = frame.is_err();
+                yield frame;
+                if is_error {
+                    return;
+                }
+            }
+            if decoder.is_finished() {
+                return;
+            }
+        }
+
+        for frame in decoder.finish() {
+            yield frame;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    /// 指定した位置でバイト列を分割してデコーダに与え、得られたフレームを返す
+    fn decode_split(framing: Framing, input: &[u8], splits: &[usize]) -> Vec<Frame> {
+        let mut decoder = StreamDecoder::new(framing);
+        let mut frames = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain(std::iter::once(&input.len())) {
+            frames.extend(
+                decoder
+                    .push(&input[start..end])
+                    .into_iter()
+                    .map(Result::unwrap),
+            );
+            start = end;
+        }
+        frames.extend(decoder.finish().into_iter().map(Result::unwrap));
+        frames
+    }
+
+    fn data_of(frames: &[Frame]) -> Vec<&str> {
+        frames.iter().map(|frame| frame.data.as_str()).collect()
+    }
+
+    #[test]
+    fn ndjson_handles_every_single_split_point() {
+        let input =
+            "{\"message\":{\"content\":\"こんにちは\"}}\n{\"message\":{\"content\":\"世界\"}}\n";
+        let bytes = input.as_bytes();
+        let expected = vec![
+            "{\"message\":{\"content\":\"こんにちは\"}}",
+            "{\"message\":{\"content\":\"世界\"}}",
+        ];
+        for split in 0..=bytes.len() {
+            let frames = decode_split(Framing::Ndjson, bytes, &[split]);
+            assert_eq!(data_of(&frames), expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn ndjson_byte_by_byte_keeps_multibyte_characters_intact() {
+        let input = "{\"content\":\"🦀 絵文字\"}\n";
+        let bytes = input.as_bytes();
+        let splits: Vec<usize> = (1..bytes.len()).collect();
+        let frames = decode_split(Framing::Ndjson, bytes, &splits);
+        assert_eq!(data_of(&frames), vec!["{\"content\":\"🦀 絵文字\"}"]);
+    }
+
+    #[test]
+    fn ndjson_several_lines_in_one_chunk_and_missing_trailing_newline() {
+        let input = b"{\"a\":1}\n\n{\"a\":2}\r\n{\"a\":3}";
+        let frames = decode_split(Framing::Ndjson, input, &[]);
+        assert_eq!(
+            data_of(&frames),
+            vec!["{\"a\":1}", "{\"a\":2}", "{\"a\":3}"]
+        );
+    }
+
+    #[test]
+    fn ndjson_stops_after_done_object() {
+        let input = b"{\"done\":false}\n{\"done\":true,\"eval_count\":3}\n{\"done\":false}\n";
+        let frames = decode_split(Framing::Ndjson, input, &[5, 20]);
+        assert_eq!(
+            data_of(&frames),
+            vec!["{\"done\":false}", "{\"done\":true,\"eval_count\":3}"]
+        );
+    }
+
+    #[test]
+    fn ndjson_error_object_becomes_api_error() {
+        let mut decoder = StreamDecoder::new(Framing::Ndjson);
+        let mut results = decoder.push(b"{\"error\":\"model 'x' not found\"}\n{\"a\":1}\n");
+        assert_eq!(results.len(), 1);
+        match results.remove(0) {
+            Err(ApiError::Message(message)) => assert_eq!(message, "model 'x' not found"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(decoder.is_finished());
+    }
+
+    #[test]
+    fn sse_handles_every_single_split_point() {
+        let input = "event: content_block_delta\r\ndata: {\"text\":\"é\"}\r\n\r\n: keep-alive\n\ndata: {\"text\":\"ß\"}\n\ndata: [DONE]\n\ndata: {\"ignored\":true}\n\n";
+        let bytes = input.as_bytes();
+        for split in 0..=bytes.len() {
+            let frames = decode_split(Framing::Sse, bytes, &[split]);
+            assert_eq!(
+                frames,
+                vec![
+                    Frame {
+                        event: Some("content_block_delta".to_string()),
+                        data: "{\"text\":\"é\"}".to_string(),
+                    },
+                    Frame {
+                        event: None,
+                        data: "{\"text\":\"ß\"}".to_string(),
+                    },
+                ],
+                "split at {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn sse_joins_multiple_data_lines_and_flushes_unterminated_event() {
+        let input = b"data: {\"a\":\ndata: 1}\n\ndata:{\"b\":2}";
+        let frames = decode_split(Framing::Sse, input, &[3, 17]);
+        assert_eq!(data_of(&frames), vec!["{\"a\":\n1}", "{\"b\":2}"]);
+    }
+
+    #[test]
+    fn sse_error_event_keeps_preceding_frames_in_the_same_chunk() {
+        let mut decoder = StreamDecoder::new(Framing::Sse);
+        let input = b"data: {\"text\":\"Hey\"}\n\nevent: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n";
+        let mut results = decoder.push(input);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.remove(0).unwrap().data, "{\"text\":\"Hey\"}");
+        match results.remove(0) {
+            Err(ApiError::Message(message)) => {
+                assert!(message.contains("Overloaded (overloaded_error)"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_in_a_complete_line_is_a_stream_error() {
+        let mut decoder = StreamDecoder::new(Framing::Ndjson);
+        let results = decoder.push(b"\xff\xfe\n");
+        assert!(matches!(results[..], [Err(ApiError::StreamError(_))]));
+    }
+
+    #[tokio::test]
+    async fn decode_stream_reassembles_adversarial_chunks() {
+        let body = "data: {\"n\":\"一\"}\n\ndata: {\"n\":\"二\"}\n\ndata: [DONE]\n\n".as_bytes();
+        // 3バイトずつに分割し、マルチバイト文字と行の両方を分断する
+        let chunks: Vec<Result<Bytes, ApiError>> = body
+            .chunks(3)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let frames: Vec<Frame> = decode_stream(stream::iter(chunks), Framing::Sse)
+            .map(|frame| frame.unwrap())
+            .collect()
+            .await;
+        assert_eq!(data_of(&frames), vec!["{\"n\":\"一\"}", "{\"n\":\"二\"}"]);
+    }
+
+    #[tokio::test]
+    async fn decode_stream_stops_on_transport_error() {
+        let chunks: Vec<Result<Bytes, ApiError>> = vec![
+            Ok(Bytes::from_static(b"{\"a\":1}\n{\"a\"")),
+            Err(ApiError::StreamError("connection reset".to_string())),
+            Ok(Bytes::from_static(b":2}\n")),
+        ];
+        let results: Vec<Result<Frame, ApiError>> =
+            decode_stream(stream::iter(chunks), Framing::Ndjson)
+                .collect()
+                .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().data, "{\"a\":1}");
+        assert!(matches!(results[1], Err(ApiError::StreamError(_))));
+    }
+}
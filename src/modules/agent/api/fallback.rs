@@ -0,0 +1,188 @@
+// src/modules/agent/api/fallback.rs
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use std::boxed::Box;
+
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatStreamChunk, GenerationOptions,
+    NetworkConfig, PullProgressStream, ResponseFormat, peek_stream,
+};
+
+/// 設定ファイルの `fallback` に書く予備のバックエンド
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackendConfig {
+    /// `ollama`, `gemini`, `openai`, `anthropic` のいずれか
+    pub provider: String,
+    pub model: String,
+    /// 省略時はプロバイダの既定値 (環境変数があればそれ) を使う
+    pub base_url: Option<String>,
+    /// `openai` プロバイダのBearerトークン。省略時は `OPENAI_API_KEY` を使う
+    pub api_key: Option<String>,
+}
+
+/// フォールバックチェーンを構成する1つのバックエンド
+pub struct FallbackBackend {
+    provider_name: &'static str,
+    api: Box<dyn AIApiTrait>,
+}
+
+impl FallbackBackend {
+    pub fn new(provider_name: &'static str, api: Box<dyn AIApiTrait>) -> Self {
+        FallbackBackend { provider_name, api }
+    }
+
+    /// `ollama:llama3.1:8b` のような表示用の名前
+    fn label(&self) -> String {
+        format!("{}:{}", self.provider_name, self.api.get_model())
+    }
+}
+
+impl Clone for FallbackBackend {
+    fn clone(&self) -> Self {
+        FallbackBackend {
+            provider_name: self.provider_name,
+            api: self.api.clone_box(),
+        }
+    }
+}
+
+/// 優先順に並んだバックエンドを順に試すAPI。
+/// 接続できない、5xx、モデルが見つからない場合に次のバックエンドへ切り替える。
+/// 最初のバックエンドが主となり、モデルの変更やモデル一覧はこれに対して行う。
+#[derive(Clone)]
+pub struct FallbackApi {
+    backends: Vec<FallbackBackend>,
+}
+
+impl FallbackApi {
+    pub fn new(backends: Vec<FallbackBackend>) -> Self {
+        assert!(
+            !backends.is_empty(),
+            "FallbackApi requires at least one backend"
+        );
+        FallbackApi { backends }
+    }
+
+    fn primary(&self) -> &dyn AIApiTrait {
+        self.backends[0].api.as_ref()
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for FallbackApi {
+    fn set_model(&mut self, model_name: String) {
+        self.backends[0].api.set_model(model_name);
+    }
+
+    fn get_model(&self) -> String {
+        self.primary().get_model()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        for backend in &mut self.backends {
+            backend.api.set_generation_options(options.clone());
+        }
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.primary().get_generation_options()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        for backend in &mut self.backends {
+            backend.api.set_network_config(config.clone());
+        }
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        for backend in &mut self.backends {
+            backend.api.set_response_format(format.clone());
+        }
+    }
+
+    // 埋め込みは主のバックエンドだけで計算する
+    fn set_embedding_model(&mut self, model: Option<String>) {
+        self.backends[0].api.set_embedding_model(model);
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        self.primary().list_models().await
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        let mut last_error = None;
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            // ツール定義はすべてのバックエンドが対応している場合だけ渡されるため、どのバックエンドにも同じものを送る。
+            // ストリームの最初の要素がエラーの場合も、まだ何も表示していないので切り替えられる
+            let result = match backend
+                .api
+                .get_chat_completion_stream(messages.clone(), tools.clone())
+                .await
+            {
+                Ok(stream) => peek_stream(stream).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(response_stream) => {
+                    let backend_chunk = ChatStreamChunk::Backend(backend.label(), index > 0);
+                    return Ok(stream::once(async { Ok(backend_chunk) })
+                        .chain(response_stream)
+                        .boxed());
+                }
+                Err(e) if e.is_backend_unavailable() => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            ApiError::Message("利用可能なバックエンドがありません。".to_string())
+        }))
+    }
+
+    /// どのバックエンドに切り替わっても同じ履歴を送れるよう、すべてのバックエンドが対応している場合だけ
+    /// ネイティブのツール呼び出しを使う。1つでも対応していなければ、YAMLプロトコルでツールを呼び出す
+    async fn supports_native_tools(&self) -> bool {
+        for backend in &self.backends {
+            if !backend.api.supports_native_tools().await {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn context_length(&self) -> Option<usize> {
+        self.primary().context_length().await
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        self.primary().embed(texts).await
+    }
+
+    async fn pull_model(&self, name: &str) -> Result<PullProgressStream, ApiError> {
+        self.primary().pull_model(name).await
+    }
+
+    async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.primary().delete_model(name).await
+    }
+
+    async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.primary().copy_model(source, destination).await
+    }
+
+    async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        self.primary().show_model(name).await
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
@@ -1,26 +1,274 @@
 // src/modules/agent/api/gemini.rs
 use async_trait::async_trait;
-use futures_util::stream::Stream;
+use futures_util::StreamExt;
+use futures_util::TryStreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::boxed::Box;
-use std::pin::Pin;
 
-use crate::modules::agent::api::{AIApiTrait, ApiError, ChatMessage};
+use crate::modules::agent::api::decoder::{Frame, Framing, decode_stream};
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatRole, ChatStreamChunk,
+    GenerationOptions, NetworkConfig, ResponseFormat, TokenUsage, error_from_response,
+};
+
+/// Gemini APIのパート。テキストかインラインデータ (画像) のどちらか一方を持つ
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiPart {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inline_data: Option<GeminiInlineData>,
+    /// 思考の要約のパートでは `true` になる
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thought: Option<bool>,
+}
+
+/// `inlineData` パート。画像を base64 で埋め込む
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiInlineData {
+    pub mime_type: String,
+    pub data: String,
+}
+
+/// Gemini APIの1ターン分のコンテンツ (`user` または `model`)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GeminiContent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<GeminiPart>,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<GeminiContent>,
+    pub contents: Vec<GeminiContent>,
+    pub generation_config: GeminiGenerationConfig,
+}
+
+/// `generationConfig`。未設定の項目は送らない
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiGenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    /// JSONモードでは `application/json`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_json_schema: Option<serde_json::Value>,
+}
+
+impl From<&GenerationOptions> for GeminiGenerationConfig {
+    fn from(options: &GenerationOptions) -> Self {
+        // num_ctx と repeat_penalty に相当する設定は Gemini には無い
+        GeminiGenerationConfig {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            top_k: options.top_k,
+            seed: options.seed,
+            stop_sequences: options.stop.clone(),
+            max_output_tokens: options.max_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiCandidate {
+    pub content: Option<GeminiContent>,
+    #[allow(dead_code)]
+    pub finish_reason: Option<String>,
+}
+
+/// `usageMetadata`。ストリームでは各チャンクにその時点までの累計が入る
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiUsageMetadata {
+    pub prompt_token_count: Option<u64>,
+    pub candidates_token_count: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse {
+    #[serde(default)]
+    pub candidates: Vec<GeminiCandidate>,
+    pub error: Option<GeminiErrorBody>,
+    pub usage_metadata: Option<GeminiUsageMetadata>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct GeminiErrorBody {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub status: String,
+}
 
 #[derive(Debug, Clone)]
 pub struct GeminiApi {
-    #[allow(dead_code)]
+    client: Client,
     base_url: String,
     default_model: String,
+    api_key: Option<String>,
+    options: GenerationOptions,
+    response_format: Option<ResponseFormat>,
 }
 
 impl GeminiApi {
     pub fn new(base_url: String, default_model: String) -> Self {
+        // APIキーは環境変数から取得する (GEMINI_API_KEY を優先し、GOOGLE_API_KEY にフォールバック)
+        let api_key = std::env::var("GEMINI_API_KEY")
+            .or_else(|_| std::env::var("GOOGLE_API_KEY"))
+            .ok()
+            .filter(|key| !key.trim().is_empty());
         GeminiApi {
-            base_url,
+            client: NetworkConfig::default().build_client(),
+            base_url: base_url.trim_end_matches('/').to_string(),
             default_model,
+            api_key,
+            options: GenerationOptions::default(),
+            response_format: None,
+        }
+    }
+
+    fn api_key(&self) -> Result<&str, ApiError> {
+        self.api_key.as_deref().ok_or_else(|| {
+            ApiError::Message(
+                "Gemini APIキーが設定されていません。環境変数 GEMINI_API_KEY を設定してください。"
+                    .to_string(),
+            )
+        })
+    }
+
+    /// `models/` プレフィックスの有無に関わらずモデル名を正規化する
+    fn model_path(&self) -> String {
+        let model = self.default_model.trim_start_matches("models/");
+        format!("models/{}", model)
+    }
+
+    /// 共通の `ChatMessage` 履歴を Gemini の `systemInstruction` と `contents` に変換する。
+    /// システムメッセージは全て `systemInstruction` にまとめ、連続する同じロールのターンは1つにまとめる。
+    fn build_request(
+        messages: Vec<ChatMessage>,
+        options: &GenerationOptions,
+    ) -> GenerateContentRequest {
+        let mut system_parts: Vec<GeminiPart> = Vec::new();
+        let mut contents: Vec<GeminiContent> = Vec::new();
+
+        for message in messages {
+            let role = match message.role {
+                ChatRole::System => {
+                    system_parts.push(GeminiPart {
+                        text: Some(message.content),
+                        ..GeminiPart::default()
+                    });
+                    continue;
+                }
+                ChatRole::Assistant => "model",
+                ChatRole::User | ChatRole::Tool => "user",
+            };
+
+            let mut parts: Vec<GeminiPart> = message
+                .attachments
+                .into_iter()
+                .map(|attachment| GeminiPart {
+                    inline_data: Some(GeminiInlineData {
+                        mime_type: attachment.mime_type,
+                        data: attachment.data,
+                    }),
+                    ..GeminiPart::default()
+                })
+                .collect();
+            // 画像のみのメッセージでは空のテキストパートを送らない
+            if parts.is_empty() || !message.content.is_empty() {
+                parts.push(GeminiPart {
+                    text: Some(message.content),
+                    ..GeminiPart::default()
+                });
+            }
+            match contents.last_mut() {
+                Some(last) if last.role.as_deref() == Some(role) => last.parts.extend(parts),
+                _ => contents.push(GeminiContent {
+                    role: Some(role.to_string()),
+                    parts,
+                }),
+            }
+        }
+
+        GenerateContentRequest {
+            system_instruction: (!system_parts.is_empty()).then_some(GeminiContent {
+                role: None,
+                parts: system_parts,
+            }),
+            contents,
+            generation_config: options.into(),
         }
     }
+
+    /// エラーレスポンスの本文から人間が読めるメッセージを取り出す
+    fn error_detail(body: &str) -> Option<String> {
+        serde_json::from_str::<GenerateContentResponse>(body)
+            .ok()
+            .and_then(|response| response.error)
+            .map(|error| format!("{} ({})", error.message, error.status))
+    }
+
+    /// SSEイベント1つ分の `data:` ペイロードをパースし、テキストと使用量を取り出す
+    fn parse_event_data(data: &str) -> Result<Vec<ChatStreamChunk>, ApiError> {
+        // エラーオブジェクトはデコーダが `ApiError` に変換済み
+        let response: GenerateContentResponse = serde_json::from_str(data)?;
+        let mut chunks = Vec::new();
+
+        let mut reasoning = String::new();
+        let mut text = String::new();
+        for part in response
+            .candidates
+            .into_iter()
+            .filter_map(|candidate| candidate.content)
+            .flat_map(|content| content.parts)
+        {
+            match (part.text, part.thought) {
+                (Some(thought), Some(true)) => reasoning.push_str(&thought),
+                (Some(part_text), _) => text.push_str(&part_text),
+                (None, _) => {}
+            }
+        }
+        if !reasoning.is_empty() {
+            chunks.push(ChatStreamChunk::Reasoning(reasoning));
+        }
+        if !text.is_empty() {
+            chunks.push(ChatStreamChunk::Content(text));
+        }
+
+        // 累計値なので、受け取る側で後の値に上書きされる
+        if let Some(usage) = response.usage_metadata {
+            chunks.push(ChatStreamChunk::Usage(TokenUsage {
+                prompt_tokens: usage.prompt_token_count,
+                completion_tokens: usage.candidates_token_count,
+                ..TokenUsage::default()
+            }));
+        }
+        Ok(chunks)
+    }
 }
 
 #[async_trait]
@@ -33,23 +281,231 @@ impl AIApiTrait for GeminiApi {
         self.default_model.clone()
     }
 
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.options = options;
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.options.clone()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.client = config.build_client();
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.response_format = format;
+    }
+
     async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
-        Ok(json!([
-            { "name": "gemini-pro", "description": "Gemini Pro model" },
-            { "name": "gemini-pro-vision", "description": "Gemini Pro Vision model" }
-        ]))
+        let api_key = self.api_key()?;
+        let url = format!("{}/v1beta/models", self.base_url);
+        let mut models: Vec<serde_json::Value> = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .client
+                .get(&url)
+                .header("x-goog-api-key", api_key)
+                .query(&[("pageSize", "1000")]);
+            if let Some(token) = &page_token {
+                request = request.query(&[("pageToken", token)]);
+            }
+
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                return Err(error_from_response(response, Self::error_detail).await);
+            }
+            let page: serde_json::Value = response.json().await?;
+
+            for model in page["models"].as_array().into_iter().flatten() {
+                // チャットに使えない (generateContent 非対応の) モデルは除外する
+                let supports_chat = model["supportedGenerationMethods"]
+                    .as_array()
+                    .is_none_or(|methods| methods.iter().any(|m| m == "generateContent"));
+                if !supports_chat {
+                    continue;
+                }
+                let name = model["name"].as_str().unwrap_or_default();
+                models.push(json!({
+                    "name": name.trim_start_matches("models/"),
+                    "description": model["description"],
+                }));
+            }
+
+            match page["nextPageToken"].as_str() {
+                Some(token) if !token.is_empty() => page_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+
+        Ok(json!({ "models": models }))
     }
 
     async fn get_chat_completion_stream(
         &self,
-        _messages: Vec<ChatMessage>,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String, ApiError>> + Send>>, ApiError> {
-        Err(ApiError::UnsupportedOperation(
-            "Gemini chat completion stream not yet implemented.".to_string(),
-        ))
+        messages: Vec<ChatMessage>,
+        _tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        let api_key = self.api_key()?;
+        let mut request_body = Self::build_request(messages, &self.options);
+        if let Some(format) = &self.response_format {
+            let config = &mut request_body.generation_config;
+            config.response_mime_type = Some("application/json".to_string());
+            if let ResponseFormat::JsonSchema(schema) = format {
+                config.response_json_schema = Some(schema.clone());
+            }
+        }
+
+        let url = format!(
+            "{}/v1beta/{}:streamGenerateContent",
+            self.base_url,
+            self.model_path()
+        );
+        let response = self
+            .client
+            .post(&url)
+            .header("x-goog-api-key", api_key)
+            .query(&[("alt", "sse")])
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        let stream = decode_stream(response.bytes_stream(), Framing::Sse)
+            .and_then(|frame: Frame| async move {
+                let chunks = Self::parse_event_data(&frame.data)?;
+                Ok(futures_util::stream::iter(chunks.into_iter().map(Ok)))
+            })
+            .try_flatten()
+            .boxed();
+
+        Ok(stream)
     }
 
     fn clone_box(&self) -> Box<dyn AIApiTrait> {
         Box::new(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::testing::{FakeHttp, FakeReply, collect_reply};
+
+    fn fake_api(server: &FakeHttp) -> GeminiApi {
+        GeminiApi {
+            api_key: Some("test-key".to_string()),
+            ..GeminiApi::new(format!("{}/", server.base_url), "gemini-test".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_text_thoughts_and_usage() {
+        let server = FakeHttp::start(vec![FakeReply::sse(&[
+            json!({
+                "candidates": [{ "content": { "role": "model", "parts": [
+                    { "text": "Thinking about it", "thought": true },
+                    { "text": "こんにちは、" },
+                ] } }],
+                "usageMetadata": { "promptTokenCount": 12, "candidatesTokenCount": 3 },
+            }),
+            json!({
+                "candidates": [{
+                    "content": { "role": "model", "parts": [{ "text": "世界" }] },
+                    "finishReason": "STOP",
+                }],
+                "usageMetadata": { "promptTokenCount": 12, "candidatesTokenCount": 5 },
+            }),
+        ])])
+        .await;
+        let mut api = fake_api(&server);
+        api.set_response_format(Some(ResponseFormat::Json));
+
+        let stream = api
+            .get_chat_completion_stream(
+                vec![
+                    ChatMessage::system("Be brief."),
+                    ChatMessage::user("Hi"),
+                    ChatMessage::user("Say hello."),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        let reply = collect_reply(stream).await.unwrap();
+        assert_eq!(reply.content, "こんにちは、世界");
+        assert_eq!(reply.reasoning, "Thinking about it");
+        let usage = reply.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, Some(12));
+        assert_eq!(usage.completion_tokens, Some(5));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].path,
+            "/v1beta/models/gemini-test:streamGenerateContent?alt=sse"
+        );
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("test-key"));
+        let body = &requests[0].body;
+        assert_eq!(
+            body["systemInstruction"],
+            json!({ "parts": [{ "text": "Be brief." }] })
+        );
+        // 連続する user のメッセージは1つのターンにまとめる
+        assert_eq!(
+            body["contents"],
+            json!([{ "role": "user", "parts": [{ "text": "Hi" }, { "text": "Say hello." }] }])
+        );
+        assert_eq!(
+            body["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_error_statuses() {
+        let server = FakeHttp::start(vec![
+            FakeReply::Status(
+                403,
+                json!({ "error": {
+                    "code": 403,
+                    "message": "API key not valid.",
+                    "status": "PERMISSION_DENIED",
+                } }),
+            ),
+            FakeReply::Status(
+                404,
+                json!({ "error": {
+                    "code": 404,
+                    "message": "models/gemini-test is not found for API version v1beta.",
+                    "status": "NOT_FOUND",
+                } }),
+            ),
+        ])
+        .await;
+        let api = fake_api(&server);
+
+        for expected in ["PERMISSION_DENIED", "NOT_FOUND"] {
+            let error = api
+                .get_chat_completion_stream(vec![ChatMessage::user("Hi")], None)
+                .await
+                .err()
+                .unwrap();
+            match (expected, error) {
+                ("PERMISSION_DENIED", ApiError::HttpStatus(status, message)) => {
+                    assert_eq!(status, reqwest::StatusCode::FORBIDDEN);
+                    assert_eq!(message, "API key not valid. (PERMISSION_DENIED)");
+                }
+                ("NOT_FOUND", ApiError::ModelNotFound(message)) => {
+                    assert!(message.contains("models/gemini-test is not found"));
+                }
+                (_, error) => panic!("unexpected error: {:?}", error),
+            }
+        }
+    }
+}
@@ -1,23 +1,98 @@
 // src/modules/agent/api/ollama.rs
-use bytes::Bytes;
 use futures_util::StreamExt;
 use futures_util::TryStreamExt;
-use futures_util::stream::Stream;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::boxed::Box;
-use std::pin::Pin;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
-use crate::modules::agent::api::{AIApiTrait, ApiError, ChatMessage};
+use crate::modules::agent::api::decoder::{Frame, Framing, decode_stream};
+use crate::modules::agent::api::reasoning::split_think_tags;
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatRole, ChatStreamChunk,
+    GenerationOptions, NativeToolCall, NetworkConfig, PullProgress, PullProgressStream,
+    ResponseFormat, TokenUsage, error_from_response,
+};
+
+/// `/api/chat` に送るメッセージ。画像は base64 の配列として `images` に入れる
+#[derive(Serialize, Debug, Clone)]
+pub struct OllamaMessage {
+    pub role: ChatRole,
+    pub content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// assistant のメッセージで、モデルが要求したツール呼び出し
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<OllamaToolCall>,
+    /// `tool` のメッセージで、結果を返したツールの名前
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_name: Option<String>,
+}
+
+impl From<ChatMessage> for OllamaMessage {
+    fn from(message: ChatMessage) -> Self {
+        OllamaMessage {
+            role: message.role,
+            content: message.content,
+            images: message
+                .attachments
+                .into_iter()
+                .map(|attachment| attachment.data)
+                .collect(),
+            tool_calls: message
+                .tool_calls
+                .into_iter()
+                .map(|call| OllamaToolCall {
+                    function: OllamaFunctionCall {
+                        name: call.name,
+                        arguments: call.arguments,
+                    },
+                })
+                .collect(),
+            tool_name: message.tool_call.map(|call| call.name),
+        }
+    }
+}
 
 #[derive(Serialize, Default)]
 pub struct ChatCompletionRequest {
     pub model: String,
-    pub messages: Vec<ChatMessage>,
+    pub messages: Vec<OllamaMessage>,
     pub stream: bool,
     pub options: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+    /// `"json"` または JSON Schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
+}
+
+/// `message.tool_calls[].function`
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct OllamaFunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: serde_json::Value,
+}
+
+/// `message.tool_calls[]`
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct OllamaToolCall {
+    pub function: OllamaFunctionCall,
+}
+
+/// ストリームで届く応答メッセージ。通常のテキストに加えてネイティブのツール呼び出しを含むことがある
+#[derive(Deserialize, Default)]
+pub struct ChatResponseMessage {
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub tool_calls: Vec<OllamaToolCall>,
+    /// 思考に対応したモデルの思考過程 (本文とは別に届く)
+    #[serde(default)]
+    pub thinking: String,
 }
 
 #[derive(Deserialize, Default)]
@@ -25,26 +100,118 @@ pub struct ChatCompletionRequest {
 pub struct ChatCompletionResponse {
     pub model: String,
     pub created_at: String,
-    pub message: Option<ChatMessage>,
+    pub message: Option<ChatResponseMessage>,
     pub done: bool,
+    /// 以下は最後のチャンク (`done: true`) にのみ含まれる。時間はナノ秒単位
     pub total_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub eval_duration: Option<u64>,
+}
+
+impl ChatCompletionResponse {
+    /// 最後のチャンクに含まれる統計情報をトークン使用量に変換する
+    fn usage(&self) -> Option<TokenUsage> {
+        if !self.done {
+            return None;
+        }
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_eval_count,
+            completion_tokens: self.eval_count,
+            prompt_duration: self.prompt_eval_duration.map(Duration::from_nanos),
+            completion_duration: self.eval_duration.map(Duration::from_nanos),
+            total_duration: self.total_duration.map(Duration::from_nanos),
+        })
+    }
+}
+
+/// `/api/embed` の応答
+#[derive(Deserialize, Default)]
+pub struct EmbedResponse {
+    #[serde(default)]
+    pub embeddings: Vec<Vec<f32>>,
 }
 
+/// `/api/pull` のストリームで届く進捗。失敗した場合は `error` のみが入る
+#[derive(Deserialize, Default)]
+pub struct PullResponse {
+    #[serde(default)]
+    pub status: String,
+    pub digest: Option<String>,
+    pub total: Option<u64>,
+    pub completed: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// `num_ctx` を指定しない場合に Ollama が使うコンテキスト長
+const DEFAULT_NUM_CTX: usize = 4096;
+
 #[derive(Debug, Clone)]
 pub struct OllamaApi {
     client: Client,
     base_url: String,
     default_model: String,
+    options: GenerationOptions,
+    response_format: Option<ResponseFormat>,
+    /// 埋め込みに使うモデル (`None` ならチャットのモデル)
+    embedding_model: Option<String>,
 }
 
 impl OllamaApi {
     pub fn new(base_url: String, default_model: String) -> Self {
-        let client = Client::new();
+        let client = NetworkConfig::default().build_client();
         OllamaApi {
             client,
             base_url,
             default_model,
+            options: GenerationOptions::default(),
+            response_format: None,
+            embedding_model: None,
+        }
+    }
+
+    /// `{"error": "..."}` 形式のエラーレスポンスからメッセージを取り出す
+    fn error_detail(body: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|value| value["error"].as_str().map(str::to_string))
+    }
+
+    /// モデル管理APIにJSONを送り、失敗した場合はエラーを返す
+    async fn send_model_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<reqwest::Response, ApiError> {
+        let url = format!("{}{}", self.base_url, path);
+        let response = self.client.request(method, &url).json(&body).send().await?;
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
         }
+        Ok(response)
+    }
+
+    /// 生成パラメータを Ollama の `options` オブジェクトに変換する
+    fn request_options(options: &GenerationOptions) -> serde_json::Value {
+        let mut map = serde_json::Map::new();
+        let mut insert = |key: &str, value: Option<serde_json::Value>| {
+            if let Some(value) = value {
+                map.insert(key.to_string(), value);
+            }
+        };
+        insert("temperature", options.temperature.map(Into::into));
+        insert("top_p", options.top_p.map(Into::into));
+        insert("top_k", options.top_k.map(Into::into));
+        insert("num_ctx", options.num_ctx.map(Into::into));
+        insert("seed", options.seed.map(Into::into));
+        insert("num_predict", options.max_tokens.map(Into::into));
+        insert("repeat_penalty", options.repeat_penalty.map(Into::into));
+        if !options.stop.is_empty() {
+            map.insert("stop".to_string(), options.stop.clone().into());
+        }
+        serde_json::Value::Object(map)
     }
 }
 
@@ -58,6 +225,26 @@ impl AIApiTrait for OllamaApi {
         self.default_model.clone()
     }
 
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.options = options;
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.options.clone()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.client = config.build_client();
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.response_format = format;
+    }
+
+    fn set_embedding_model(&mut self, model: Option<String>) {
+        self.embedding_model = model;
+    }
+
     async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
         let url = format!("{}/api/tags", self.base_url);
         let response = self.client.get(&url).send().await?.json().await?;
@@ -67,60 +254,219 @@ impl AIApiTrait for OllamaApi {
     async fn get_chat_completion_stream(
         &self,
         messages: Vec<ChatMessage>,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String, ApiError>> + Send>>, ApiError> {
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
         let request_body = ChatCompletionRequest {
             model: self.default_model.clone(),
-            messages,
+            messages: messages.into_iter().map(OllamaMessage::from).collect(),
             stream: true,
-            options: Some(serde_json::json!({ "temperature": 0.7, })),
+            options: Some(Self::request_options(&self.options)),
+            tools,
+            format: self
+                .response_format
+                .as_ref()
+                .map(ResponseFormat::ollama_format),
         };
 
         let url = format!("{}/api/chat", self.base_url);
         let response = self.client.post(&url).json(&request_body).send().await?;
 
-        let status = response.status();
-        if !status.is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown API error".to_string());
-            return Err(ApiError::Message(format!(
-                "APIリクエストが失敗しました: ステータス {} - {}",
-                status, error_text
-            )));
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
         }
 
-        let body_stream = response.bytes_stream();
+        let stream = decode_stream(response.bytes_stream(), Framing::Ndjson)
+            .and_then(|frame: Frame| async move {
+                let response_obj: ChatCompletionResponse = serde_json::from_str(&frame.data)?;
+                let usage = response_obj.usage();
+                let mut chunks = Vec::new();
+
+                if let Some(message) = response_obj.message {
+                    if !message.thinking.is_empty() {
+                        chunks.push(Ok(ChatStreamChunk::Reasoning(message.thinking)));
+                    }
+                    // ネイティブのツール呼び出しはテキストより優先して返す
+                    if !message.tool_calls.is_empty() {
+                        let calls = message
+                            .tool_calls
+                            .into_iter()
+                            .map(|call| NativeToolCall {
+                                id: None,
+                                name: call.function.name,
+                                arguments: call.function.arguments,
+                            })
+                            .collect();
+                        chunks.push(Ok(ChatStreamChunk::ToolCalls(calls)));
+                    }
+                    if !message.content.is_empty() {
+                        chunks.push(Ok(ChatStreamChunk::Content(message.content)));
+                    }
+                }
+                if let Some(usage) = usage {
+                    chunks.push(Ok(ChatStreamChunk::Usage(usage)));
+                }
+                Ok(futures_util::stream::iter(chunks))
+            })
+            .try_flatten()
+            .boxed();
 
-        let stream = body_stream
-            .map_err(ApiError::Reqwest)
-            .and_then(|bytes: Bytes| async move {
-                let s = String::from_utf8(bytes.to_vec())
-                    .map_err(|e| ApiError::StreamError(format!("Invalid UTF-8 sequence: {}", e)))?;
+        // `thinking` フィールドを使わないモデルは本文に `<think>` ブロックを書く
+        Ok(split_think_tags(stream))
+    }
 
-                let trimmed_s = s.trim();
-                let json_str = trimmed_s.strip_prefix("data: ").unwrap_or(trimmed_s);
+    async fn supports_native_tools(&self) -> bool {
+        // `/api/show` の `capabilities` に "tools" が含まれるモデルのみネイティブのツール呼び出しに対応する
+        let url = format!("{}/api/show", self.base_url);
+        let response = self
+            .client
+            .post(&url)
+            .json(&serde_json::json!({ "model": self.default_model }))
+            .send()
+            .await;
+        let Ok(response) = response else {
+            return false;
+        };
+        if !response.status().is_success() {
+            return false;
+        }
+        response
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|info| info["capabilities"].as_array().cloned())
+            .is_some_and(|capabilities| capabilities.iter().any(|c| c == "tools"))
+    }
 
-                if json_str == "[DONE]" {
-                    return Ok(None);
-                }
+    async fn context_length(&self) -> Option<usize> {
+        // Ollama はモデルの最大長ではなく `num_ctx` (既定値は4096) で入力を切り詰める
+        let model_max = self
+            .show_model(&self.default_model)
+            .await
+            .ok()
+            .and_then(|info| {
+                info["model_info"].as_object().and_then(|model_info| {
+                    model_info
+                        .iter()
+                        .find(|(key, _)| key.ends_with(".context_length"))
+                        .and_then(|(_, value)| value.as_u64())
+                })
+            })
+            .map(|length| length as usize);
+        match (self.options.num_ctx, model_max) {
+            (Some(num_ctx), Some(max)) => Some((num_ctx as usize).min(max)),
+            (Some(num_ctx), None) => Some(num_ctx as usize),
+            (None, Some(max)) => Some(max.min(DEFAULT_NUM_CTX)),
+            (None, None) => None,
+        }
+    }
 
-                let response_obj: ChatCompletionResponse = serde_json::from_str(json_str)?;
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        let response = self
+            .send_model_request(
+                reqwest::Method::POST,
+                "/api/embed",
+                serde_json::json!({
+                    "model": self.embedding_model.as_ref().unwrap_or(&self.default_model),
+                    "input": texts,
+                }),
+            )
+            .await?;
+        let body: EmbedResponse = response.json().await?;
+        Ok(body.embeddings)
+    }
 
-                if let Some(message) = response_obj.message {
-                    if !message.content.is_empty() {
-                        return Ok(Some(message.content));
-                    }
+    async fn pull_model(&self, name: &str) -> Result<PullProgressStream, ApiError> {
+        let response = self
+            .send_model_request(
+                reqwest::Method::POST,
+                "/api/pull",
+                serde_json::json!({ "model": name, "stream": true }),
+            )
+            .await?;
+
+        let stream = decode_stream(response.bytes_stream(), Framing::Ndjson)
+            .and_then(|frame: Frame| async move {
+                let pull: PullResponse = serde_json::from_str(&frame.data)?;
+                // ダウンロード中のエラーはステータス200のまま `error` として届く
+                if let Some(error) = pull.error {
+                    return Err(ApiError::Message(error));
                 }
-                Ok(None)
+                Ok(PullProgress {
+                    status: pull.status,
+                    digest: pull.digest,
+                    total: pull.total,
+                    completed: pull.completed,
+                })
             })
-            .try_filter_map(|opt_content| async move { Ok(opt_content) })
             .boxed();
 
         Ok(stream)
     }
 
+    async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.send_model_request(
+            reqwest::Method::DELETE,
+            "/api/delete",
+            serde_json::json!({ "model": name }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.send_model_request(
+            reqwest::Method::POST,
+            "/api/copy",
+            serde_json::json!({ "source": source, "destination": destination }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        let response = self
+            .send_model_request(
+                reqwest::Method::POST,
+                "/api/show",
+                serde_json::json!({ "model": name }),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
     fn clone_box(&self) -> Box<dyn AIApiTrait> {
         Box::new(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::testing::{FakeHttp, FakeReply};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn embeds_with_the_embedding_model_or_the_chat_model() {
+        let embeddings = json!({ "model": "any", "embeddings": [[0.5, -1.0], [0.25, 2.0]] });
+        let server = FakeHttp::start(vec![
+            FakeReply::Status(200, embeddings.clone()),
+            FakeReply::Status(200, embeddings),
+        ])
+        .await;
+        let mut api = OllamaApi::new(server.base_url.clone(), "llama3.1:8b".to_string());
+        let texts = vec!["first".to_string(), "second".to_string()];
+
+        let vectors = api.embed(texts.clone()).await.unwrap();
+        assert_eq!(vectors, vec![vec![0.5, -1.0], vec![0.25, 2.0]]);
+        api.set_embedding_model(Some("nomic-embed-text".to_string()));
+        api.embed(texts).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/embed");
+        assert_eq!(
+            requests[0].body,
+            json!({ "model": "llama3.1:8b", "input": ["first", "second"] })
+        );
+        assert_eq!(requests[1].body["model"], "nomic-embed-text");
+    }
+}
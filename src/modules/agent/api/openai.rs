@@ -0,0 +1,479 @@
+// src/modules/agent/api/openai.rs
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use futures_util::TryStreamExt;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::boxed::Box;
+use std::time::Duration;
+
+use crate::modules::agent::api::decoder::{Frame, Framing, decode_stream};
+use crate::modules::agent::api::reasoning::split_think_tags;
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatRole, ChatStreamChunk,
+    GenerationOptions, NetworkConfig, ResponseFormat, TokenUsage, error_from_response,
+};
+
+/// `/v1/chat/completions` に送るメッセージ
+#[derive(Serialize, Debug, Clone)]
+pub struct OpenAiMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<OpenAiMessage>,
+    pub stream: bool,
+    /// 最後のチャンクで `usage` を返してもらうための指定
+    pub stream_options: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    /// llama.cpp server や vLLM が受け付ける拡張パラメータ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// llama.cpp server が受け付ける拡張パラメータ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ChatCompletionDelta {
+    pub content: Option<String>,
+    /// 思考過程 (llama.cpp server や DeepSeek は `reasoning_content`、vLLM などは `reasoning`)
+    #[serde(alias = "reasoning")]
+    pub reasoning_content: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ChatCompletionChoice {
+    #[serde(default)]
+    pub delta: ChatCompletionDelta,
+    #[allow(dead_code)]
+    pub finish_reason: Option<String>,
+}
+
+/// `stream_options.include_usage` を指定した場合に最後のチャンクに含まれる使用量
+#[derive(Deserialize, Default)]
+pub struct ChatCompletionUsage {
+    pub prompt_tokens: Option<u64>,
+    pub completion_tokens: Option<u64>,
+}
+
+/// llama.cpp server が返す拡張の処理時間 (ミリ秒)
+#[derive(Deserialize, Default)]
+pub struct LlamaCppTimings {
+    pub prompt_ms: Option<f64>,
+    pub predicted_ms: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ChatCompletionChunk {
+    #[serde(default)]
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Option<ChatCompletionUsage>,
+    pub timings: Option<LlamaCppTimings>,
+}
+
+/// `/v1/embeddings` の応答の `data[]`
+#[derive(Deserialize, Default)]
+pub struct EmbeddingData {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct EmbeddingResponse {
+    #[serde(default)]
+    pub data: Vec<EmbeddingData>,
+}
+
+/// OpenAI互換の Chat Completions API (llama.cpp server, vLLM, LM Studio など) のクライアント
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatibleApi {
+    client: Client,
+    base_url: String,
+    default_model: String,
+    api_key: Option<String>,
+    options: GenerationOptions,
+    response_format: Option<ResponseFormat>,
+    /// 埋め込みに使うモデル。チャットのモデルは埋め込みを返さないことが多いため、既定値は持たない
+    embedding_model: Option<String>,
+}
+
+impl OpenAiCompatibleApi {
+    pub fn new(base_url: String, default_model: String, api_key: Option<String>) -> Self {
+        OpenAiCompatibleApi {
+            client: NetworkConfig::default().build_client(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            default_model,
+            api_key: api_key.filter(|key| !key.trim().is_empty()),
+            options: GenerationOptions::default(),
+            response_format: None,
+            embedding_model: None,
+        }
+    }
+
+    /// ベースURLが `/v1` で終わっていてもいなくても、正しいエンドポイントURLを組み立てる
+    fn endpoint(&self, path: &str) -> String {
+        if self.base_url.ends_with("/v1") {
+            format!("{}/{}", self.base_url, path)
+        } else {
+            format!("{}/v1/{}", self.base_url, path)
+        }
+    }
+
+    /// Bearerトークンが設定されていれば付与する
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        }
+    }
+
+    fn to_openai_messages(messages: Vec<ChatMessage>) -> Vec<OpenAiMessage> {
+        messages
+            .into_iter()
+            .map(|message| {
+                let role = match message.role {
+                    ChatRole::System => "system",
+                    ChatRole::Assistant => "assistant",
+                    // `tool` ロールには tool_call_id が必須のため、ユーザーメッセージとして送る
+                    ChatRole::User | ChatRole::Tool => "user",
+                };
+                OpenAiMessage {
+                    role: role.to_string(),
+                    content: message.content,
+                }
+            })
+            .collect()
+    }
+
+    /// エラーレスポンスの本文から人間が読めるメッセージを取り出す
+    fn error_detail(body: &str) -> Option<String> {
+        serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|value| value["error"]["message"].as_str().map(str::to_string))
+    }
+
+    /// SSEイベント1つ分の `data:` ペイロードをパースし、テキストと使用量を取り出す
+    fn parse_event_data(data: &str) -> Result<Vec<ChatStreamChunk>, ApiError> {
+        // `[DONE]` とエラーオブジェクトはデコーダが処理済み
+        let chunk: ChatCompletionChunk = serde_json::from_str(data)?;
+        let mut chunks = Vec::new();
+
+        let mut reasoning = String::new();
+        let mut text = String::new();
+        for choice in chunk.choices {
+            reasoning.extend(choice.delta.reasoning_content);
+            text.extend(choice.delta.content);
+        }
+        if !reasoning.is_empty() {
+            chunks.push(ChatStreamChunk::Reasoning(reasoning));
+        }
+        if !text.is_empty() {
+            chunks.push(ChatStreamChunk::Content(text));
+        }
+
+        if let Some(usage) = chunk.usage {
+            let millis = |ms: f64| Duration::from_secs_f64(ms.max(0.0) / 1000.0);
+            let timings = chunk.timings.unwrap_or_default();
+            chunks.push(ChatStreamChunk::Usage(TokenUsage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                prompt_duration: timings.prompt_ms.map(millis),
+                completion_duration: timings.predicted_ms.map(millis),
+                total_duration: None,
+            }));
+        }
+        Ok(chunks)
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for OpenAiCompatibleApi {
+    fn set_model(&mut self, model_name: String) {
+        self.default_model = model_name;
+    }
+
+    fn get_model(&self) -> String {
+        self.default_model.clone()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.options = options;
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.options.clone()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.client = config.build_client();
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.response_format = format;
+    }
+
+    fn set_embedding_model(&mut self, model: Option<String>) {
+        self.embedding_model = model;
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        let url = self.endpoint("models");
+        let response = self.authorize(self.client.get(&url)).send().await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        // フロントエンドが扱いやすいように Ollama の `/api/tags` と同じ形に揃える
+        let body: serde_json::Value = response.json().await?;
+        let models: Vec<serde_json::Value> = body["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|model| model["id"].as_str())
+            .map(|id| json!({ "name": id }))
+            .collect();
+        Ok(json!({ "models": models }))
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        _tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        if messages
+            .iter()
+            .any(|message| !message.attachments.is_empty())
+        {
+            return Err(ApiError::UnsupportedOperation(
+                "画像の添付はOpenAI互換APIではまだサポートされていません。".to_string(),
+            ));
+        }
+        let request_body = ChatCompletionRequest {
+            model: self.default_model.clone(),
+            messages: Self::to_openai_messages(messages),
+            stream: true,
+            stream_options: json!({ "include_usage": true }),
+            // num_ctx はサーバー起動時に決まるため送らない
+            temperature: self.options.temperature,
+            top_p: self.options.top_p,
+            top_k: self.options.top_k,
+            seed: self.options.seed,
+            stop: self.options.stop.clone(),
+            max_tokens: self.options.max_tokens,
+            repeat_penalty: self.options.repeat_penalty,
+            response_format: self.response_format.as_ref().map(|format| match format {
+                ResponseFormat::Json => json!({ "type": "json_object" }),
+                ResponseFormat::JsonSchema(schema) => json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema },
+                }),
+            }),
+        };
+
+        let url = self.endpoint("chat/completions");
+        let response = self
+            .authorize(self.client.post(&url))
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        let stream = decode_stream(response.bytes_stream(), Framing::Sse)
+            .and_then(|frame: Frame| async move {
+                let chunks = Self::parse_event_data(&frame.data)?;
+                Ok(futures_util::stream::iter(chunks.into_iter().map(Ok)))
+            })
+            .try_flatten()
+            .boxed();
+
+        // 思考過程を別フィールドで返さないサーバーでは本文に `<think>` ブロックが含まれる
+        Ok(split_think_tags(stream))
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        let model = self.embedding_model.as_deref().ok_or_else(|| {
+            ApiError::UnsupportedOperation(
+                "OpenAI互換APIで埋め込みを使うには、設定ファイルの embedding_model を指定してください。"
+                    .to_string(),
+            )
+        })?;
+        let url = self.endpoint("embeddings");
+        let request_body = json!({ "model": model, "input": texts });
+        let response = self
+            .authorize(self.client.post(&url))
+            .json(&request_body)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response(response, Self::error_detail).await);
+        }
+
+        // `data` は入力の順序で返る決まりだが、念のため `index` で並べ直す
+        let mut body: EmbeddingResponse = response.json().await?;
+        body.data.sort_by_key(|data| data.index);
+        Ok(body.data.into_iter().map(|data| data.embedding).collect())
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::testing::{FakeHttp, FakeReply, collect_reply};
+
+    #[tokio::test]
+    async fn streams_content_reasoning_and_usage() {
+        let events = [
+            json!({ "choices": [{ "delta": { "role": "assistant", "reasoning_content": "Greeting." } }] }),
+            json!({ "choices": [{ "delta": { "content": "<think>More thought</think>こんにちは、" } }] }),
+            json!({ "choices": [{ "delta": { "content": "世界" }, "finish_reason": "stop" }] }),
+            json!({
+                "choices": [],
+                "usage": { "prompt_tokens": 15, "completion_tokens": 4 },
+                "timings": { "prompt_ms": 250.0, "predicted_ms": 1500.0 },
+            }),
+        ];
+        let sse = events
+            .iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .chain(["data: [DONE]\n\n".to_string()])
+            .collect();
+        let server = FakeHttp::start(vec![FakeReply::Sse(sse)]).await;
+
+        let mut api = OpenAiCompatibleApi::new(
+            format!("{}/v1", server.base_url),
+            "local-model".to_string(),
+            Some("secret".to_string()),
+        );
+        api.set_response_format(Some(ResponseFormat::JsonSchema(
+            json!({ "type": "object" }),
+        )));
+        let stream = api
+            .get_chat_completion_stream(vec![ChatMessage::user("Hi")], None)
+            .await
+            .unwrap();
+        let reply = collect_reply(stream).await.unwrap();
+        assert_eq!(reply.content, "こんにちは、世界");
+        assert_eq!(reply.reasoning, "Greeting.More thought");
+        let usage = reply.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, Some(15));
+        assert_eq!(usage.completion_tokens, Some(4));
+        assert_eq!(usage.completion_duration, Some(Duration::from_millis(1500)));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer secret"));
+        let body = &requests[0].body;
+        assert_eq!(body["stream_options"], json!({ "include_usage": true }));
+        assert_eq!(
+            body["response_format"],
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": { "type": "object" } },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn maps_error_responses() {
+        let server = FakeHttp::start(vec![
+            FakeReply::Status(
+                404,
+                json!({ "error": {
+                    "message": "The model `missing` does not exist.",
+                    "type": "invalid_request_error",
+                } }),
+            ),
+            FakeReply::Status(
+                400,
+                json!({ "error": {
+                    "message": "This model's maximum context length is 4096 tokens.",
+                    "type": "invalid_request_error",
+                } }),
+            ),
+            FakeReply::sse(&[
+                json!({ "choices": [{ "delta": { "content": "Partial" } }] }),
+                json!({ "error": { "message": "server overloaded", "type": "server_error" } }),
+            ]),
+        ])
+        .await;
+        let api = OpenAiCompatibleApi::new(server.base_url.clone(), "missing".to_string(), None);
+        let request = || api.get_chat_completion_stream(vec![ChatMessage::user("Hi")], None);
+
+        assert!(matches!(
+            request().await.err(),
+            Some(ApiError::ModelNotFound(message)) if message.contains("does not exist")
+        ));
+        assert!(matches!(
+            request().await.err(),
+            Some(ApiError::ContextOverflow(_))
+        ));
+        match collect_reply(request().await.unwrap()).await {
+            Err(ApiError::Message(message)) => {
+                assert!(message.contains("server overloaded (server_error)"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(server.requests()[0].header("authorization"), None);
+    }
+
+    #[tokio::test]
+    async fn embeds_with_the_configured_model_in_input_order() {
+        let server = FakeHttp::start(vec![FakeReply::Status(
+            200,
+            json!({
+                "object": "list",
+                "data": [
+                    { "object": "embedding", "index": 1, "embedding": [0.3, 0.4] },
+                    { "object": "embedding", "index": 0, "embedding": [0.1, 0.2] },
+                ],
+            }),
+        )])
+        .await;
+        let mut api =
+            OpenAiCompatibleApi::new(server.base_url.clone(), "local-model".to_string(), None);
+        let texts = vec!["first".to_string(), "second".to_string()];
+
+        // チャットのモデルでは埋め込みを計算できないことが多いため、指定が無ければ送らない
+        assert!(matches!(
+            api.embed(texts.clone()).await,
+            Err(ApiError::UnsupportedOperation(_))
+        ));
+        assert!(server.requests().is_empty());
+
+        api.set_embedding_model(Some("text-embedding-3-small".to_string()));
+        let vectors = api.embed(texts).await.unwrap();
+        // `index` の順に並べ直す
+        assert_eq!(vectors, vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/embeddings");
+        assert_eq!(
+            requests[0].body,
+            json!({ "model": "text-embedding-3-small", "input": ["first", "second"] })
+        );
+    }
+}
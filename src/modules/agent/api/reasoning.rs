@@ -0,0 +1,152 @@
+// src/modules/agent/api/reasoning.rs
+use async_stream::stream;
+use futures_util::stream::StreamExt;
+
+use crate::modules::agent::api::{ChatCompletionStream, ChatStreamChunk};
+
+const OPEN_TAG: &str = "<think>";
+const CLOSE_TAG: &str = "</think>";
+
+/// 本文に `<think>…</think>` を書き出す推論モデルの出力を、推論と本文に振り分ける。
+/// タグがチャンクの境界で分割されていても、揃うまで保持してから判定する。
+#[derive(Debug, Default)]
+pub struct ThinkTagSplitter {
+    in_think: bool,
+    pending: String,
+    /// `</think>` の直後の空白を本文に含めないためのフラグ
+    trim_next_content: bool,
+}
+
+impl ThinkTagSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// テキストを追加し、推論か本文かが確定した部分をチャンクとして返す
+    pub fn push(&mut self, text: &str) -> Vec<ChatStreamChunk> {
+        self.pending.push_str(text);
+        let mut chunks = Vec::new();
+
+        loop {
+            let tag = if self.in_think { CLOSE_TAG } else { OPEN_TAG };
+            match self.pending.find(tag) {
+                Some(position) => {
+                    let before: String = self.pending.drain(..position).collect();
+                    self.pending.drain(..tag.len());
+                    self.emit(before, &mut chunks);
+                    self.in_think = !self.in_think;
+                    self.trim_next_content = !self.in_think;
+                }
+                None => {
+                    // 末尾がタグの先頭部分に一致する場合は、次のチャンクまで保持する
+                    let keep = (1..tag.len())
+                        .rev()
+                        .find(|&len| self.pending.ends_with(&tag[..len]))
+                        .unwrap_or(0);
+                    let ready: String = self.pending.drain(..self.pending.len() - keep).collect();
+                    self.emit(ready, &mut chunks);
+                    return chunks;
+                }
+            }
+        }
+    }
+
+    /// ストリームの終わりに、保持していたテキストを返す
+    pub fn finish(&mut self) -> Vec<ChatStreamChunk> {
+        let rest = std::mem::take(&mut self.pending);
+        let mut chunks = Vec::new();
+        self.emit(rest, &mut chunks);
+        chunks
+    }
+
+    fn emit(&mut self, mut text: String, chunks: &mut Vec<ChatStreamChunk>) {
+        if self.in_think {
+            if !text.is_empty() {
+                chunks.push(ChatStreamChunk::Reasoning(text));
+            }
+            return;
+        }
+        if self.trim_next_content {
+            text = text.trim_start().to_string();
+            if text.is_empty() {
+                return;
+            }
+            self.trim_next_content = false;
+        }
+        if !text.is_empty() {
+            chunks.push(ChatStreamChunk::Content(text));
+        }
+    }
+}
+
+/// チャット応答のストリームの本文から `<think>` ブロックを取り出し、`Reasoning` チャンクに変換する
+pub fn split_think_tags(mut response: ChatCompletionStream) -> ChatCompletionStream {
+    stream! {
+        let mut splitter = ThinkTagSplitter::new();
+        while let Some(chunk) = response.next().await {
+            match chunk {
+                Ok(ChatStreamChunk::Content(text)) => {
+                    for chunk in splitter.push(&text) {
+                        yield Ok(chunk);
+                    }
+                }
+                other => yield other,
+            }
+        }
+        for chunk in splitter.finish() {
+            yield Ok(chunk);
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 指定した分割でテキストを与え、推論と本文をそれぞれ連結して返す
+    fn split(pieces: &[&str]) -> (String, String) {
+        let mut splitter = ThinkTagSplitter::new();
+        let mut chunks: Vec<ChatStreamChunk> = Vec::new();
+        for piece in pieces {
+            chunks.extend(splitter.push(piece));
+        }
+        chunks.extend(splitter.finish());
+
+        let mut reasoning = String::new();
+        let mut content = String::new();
+        for chunk in chunks {
+            match chunk {
+                ChatStreamChunk::Reasoning(text) => reasoning.push_str(&text),
+                ChatStreamChunk::Content(text) => content.push_str(&text),
+                _ => {}
+            }
+        }
+        (reasoning, content)
+    }
+
+    #[test]
+    fn separates_think_block_from_answer() {
+        let (reasoning, content) = split(&["<think>\nLet me check.\n</think>\n\nThe answer is 4."]);
+        assert_eq!(reasoning, "\nLet me check.\n");
+        assert_eq!(content, "The answer is 4.");
+    }
+
+    #[test]
+    fn handles_tags_split_across_chunks() {
+        let input = "<think>2 + 2 = 4</think>\n\n答えは4です。";
+        // あらゆる位置で分割しても結果が変わらないこと
+        for (index, _) in input.char_indices().skip(1) {
+            let (reasoning, content) = split(&[&input[..index], &input[index..]]);
+            assert_eq!(reasoning, "2 + 2 = 4", "split at {}", index);
+            assert_eq!(content, "答えは4です。", "split at {}", index);
+        }
+    }
+
+    #[test]
+    fn passes_through_text_without_tags() {
+        let (reasoning, content) = split(&["a < b", " and <thin", "king> is fine"]);
+        assert_eq!(reasoning, "");
+        assert_eq!(content, "a < b and <thinking> is fine");
+    }
+}
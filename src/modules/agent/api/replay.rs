@@ -0,0 +1,448 @@
+// src/modules/agent/api/replay.rs
+use async_stream::stream;
+use async_trait::async_trait;
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, ChatStreamChunk, GenerationOptions,
+    NetworkConfig, PullProgressStream, ResponseFormat,
+};
+
+/// モデルごとの情報 (ネイティブのツール呼び出しへの対応など) を保存するファイル
+const CAPABILITIES_FILE: &str = "capabilities.json";
+
+/// 1回のチャットのリクエストと、その応答として流れてきたチャンク
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Fixture {
+    /// 記録したときのモデル (参考用。照合には使わない)
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub tools: Option<Vec<serde_json::Value>>,
+    pub format: Option<serde_json::Value>,
+    pub chunks: Vec<ChatStreamChunk>,
+}
+
+/// モデルごとの情報。再生時はリクエストを送る前に必要になるため、応答とは別に記録する
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Capabilities {
+    pub native_tools: bool,
+    pub context_length: Option<usize>,
+}
+
+/// リクエストの内容 (メッセージ、ツール定義、応答の形式) から照合に使うキーを計算する。
+/// Rustのバージョンによって変わらないように、JSONにしたものをFNV-1aでハッシュする
+pub fn request_key(
+    messages: &[ChatMessage],
+    tools: &Option<Vec<serde_json::Value>>,
+    format: &Option<serde_json::Value>,
+) -> String {
+    let request = serde_json::json!({
+        "messages": messages,
+        "tools": tools,
+        "format": format,
+    });
+    let hash = request
+        .to_string()
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+fn fixture_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.json", key))
+}
+
+fn load_capabilities(dir: &Path) -> BTreeMap<String, Capabilities> {
+    std::fs::read_to_string(dir.join(CAPABILITIES_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// 他のバックエンドをラップし、チャットのリクエストと応答をフィクスチャとしてディレクトリに保存するAPI。
+/// 最後まで受信できた応答だけを保存する (エラーや中断で途切れた応答は保存しない)
+pub struct RecordingApi {
+    inner: Box<dyn AIApiTrait>,
+    dir: PathBuf,
+    format: Option<ResponseFormat>,
+}
+
+impl RecordingApi {
+    pub fn new(inner: Box<dyn AIApiTrait>, dir: PathBuf) -> Self {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            eprintln!(
+                "Failed to create recording directory {}: {}",
+                dir.display(),
+                e
+            );
+        }
+        RecordingApi {
+            inner,
+            dir,
+            format: None,
+        }
+    }
+
+    /// 現在のモデルの情報を更新して保存する
+    fn record_capabilities(&self, update: impl FnOnce(&mut Capabilities)) {
+        let mut capabilities = load_capabilities(&self.dir);
+        update(capabilities.entry(self.inner.get_model()).or_default());
+        let path = self.dir.join(CAPABILITIES_FILE);
+        if let Err(e) = serde_json::to_string_pretty(&capabilities)
+            .map_err(std::io::Error::other)
+            .and_then(|content| std::fs::write(&path, content))
+        {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Clone for RecordingApi {
+    fn clone(&self) -> Self {
+        RecordingApi {
+            inner: self.inner.clone_box(),
+            dir: self.dir.clone(),
+            format: self.format.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for RecordingApi {
+    fn set_model(&mut self, model_name: String) {
+        self.inner.set_model(model_name);
+    }
+
+    fn get_model(&self) -> String {
+        self.inner.get_model()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.inner.set_generation_options(options);
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.inner.get_generation_options()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.inner.set_network_config(config);
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.inner.set_response_format(format.clone());
+        self.format = format;
+    }
+
+    fn set_embedding_model(&mut self, model: Option<String>) {
+        self.inner.set_embedding_model(model);
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        self.inner.list_models().await
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        let mut response = self
+            .inner
+            .get_chat_completion_stream(messages.clone(), tools.clone())
+            .await?;
+        let mut fixture = Fixture {
+            model: self.inner.get_model(),
+            messages,
+            tools,
+            format: self.format.as_ref().map(ResponseFormat::ollama_format),
+            chunks: Vec::new(),
+        };
+        let path = fixture_path(
+            &self.dir,
+            &request_key(&fixture.messages, &fixture.tools, &fixture.format),
+        );
+
+        Ok(stream! {
+            while let Some(chunk) = response.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        fixture.chunks.push(chunk.clone());
+                        yield Ok(chunk);
+                    }
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                }
+            }
+            if let Err(e) = serde_json::to_string_pretty(&fixture)
+                .map_err(std::io::Error::other)
+                .and_then(|content| std::fs::write(&path, content))
+            {
+                eprintln!("Failed to write fixture {}: {}", path.display(), e);
+            }
+        }
+        .boxed())
+    }
+
+    async fn supports_native_tools(&self) -> bool {
+        let supported = self.inner.supports_native_tools().await;
+        self.record_capabilities(|capabilities| capabilities.native_tools = supported);
+        supported
+    }
+
+    async fn context_length(&self) -> Option<usize> {
+        let length = self.inner.context_length().await;
+        self.record_capabilities(|capabilities| capabilities.context_length = length);
+        length
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        self.inner.embed(texts).await
+    }
+
+    async fn pull_model(&self, name: &str) -> Result<PullProgressStream, ApiError> {
+        self.inner.pull_model(name).await
+    }
+
+    async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.inner.delete_model(name).await
+    }
+
+    async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.inner.copy_model(source, destination).await
+    }
+
+    async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        self.inner.show_model(name).await
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
+
+/// `RecordingApi` が保存したフィクスチャから応答を返すプロバイダ。ネットワークには接続しない。
+/// 同じリクエスト (メッセージ、ツール定義、応答の形式) に対して記録した応答を返す
+#[derive(Clone)]
+pub struct ReplayApi {
+    dir: PathBuf,
+    model: String,
+    options: GenerationOptions,
+    format: Option<ResponseFormat>,
+}
+
+impl ReplayApi {
+    pub fn new(dir: PathBuf, model: String) -> Self {
+        ReplayApi {
+            dir,
+            model,
+            options: GenerationOptions::default(),
+            format: None,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        load_capabilities(&self.dir)
+            .remove(&self.model)
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for ReplayApi {
+    fn set_model(&mut self, model_name: String) {
+        self.model = model_name;
+    }
+
+    fn get_model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.options = options;
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.options.clone()
+    }
+
+    fn set_network_config(&mut self, _config: NetworkConfig) {}
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.format = format;
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        // 記録したモデルを Ollama の `/api/tags` と同じ形式で返す
+        let models: Vec<serde_json::Value> = load_capabilities(&self.dir)
+            .into_keys()
+            .map(|name| serde_json::json!({ "name": name }))
+            .collect();
+        Ok(serde_json::json!({ "models": models }))
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        let format = self.format.as_ref().map(ResponseFormat::ollama_format);
+        let key = request_key(&messages, &tools, &format);
+        let path = fixture_path(&self.dir, &key);
+        let content = std::fs::read_to_string(&path).map_err(|_| {
+            ApiError::Message(format!(
+                "No recorded response for this request ({} not found). Record it again with --record={}",
+                path.display(),
+                self.dir.display()
+            ))
+        })?;
+        let fixture: Fixture = serde_json::from_str(&content)
+            .map_err(|e| ApiError::Message(format!("Invalid fixture {}: {}", path.display(), e)))?;
+        Ok(futures_util::stream::iter(fixture.chunks.into_iter().map(Ok)).boxed())
+    }
+
+    async fn supports_native_tools(&self) -> bool {
+        self.capabilities().native_tools
+    }
+
+    async fn context_length(&self) -> Option<usize> {
+        self.capabilities().context_length
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
+
+/// 台本どおりに応答するテスト用のバックエンド
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    pub struct ScriptedApi {
+        replies: Arc<Mutex<Vec<Vec<ChatStreamChunk>>>>,
+        pub native_tools: bool,
+    }
+
+    impl ScriptedApi {
+        /// `replies` をリクエストのたびに先頭から1つずつ返す
+        pub fn new(replies: Vec<Vec<ChatStreamChunk>>) -> Self {
+            ScriptedApi {
+                replies: Arc::new(Mutex::new(replies)),
+                native_tools: false,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AIApiTrait for ScriptedApi {
+        fn set_model(&mut self, _model_name: String) {}
+
+        fn get_model(&self) -> String {
+            "scripted".to_string()
+        }
+
+        fn set_generation_options(&mut self, _options: GenerationOptions) {}
+
+        fn get_generation_options(&self) -> GenerationOptions {
+            GenerationOptions::default()
+        }
+
+        fn set_network_config(&mut self, _config: NetworkConfig) {}
+
+        fn set_response_format(&mut self, _format: Option<ResponseFormat>) {}
+
+        async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+            Ok(serde_json::json!({ "models": [] }))
+        }
+
+        async fn get_chat_completion_stream(
+            &self,
+            _messages: Vec<ChatMessage>,
+            _tools: Option<Vec<serde_json::Value>>,
+        ) -> Result<ChatCompletionStream, ApiError> {
+            let mut replies = self.replies.lock().unwrap();
+            if replies.is_empty() {
+                return Err(ApiError::Message(
+                    "The script has no more replies.".to_string(),
+                ));
+            }
+            let chunks = replies.remove(0);
+            Ok(futures_util::stream::iter(chunks.into_iter().map(Ok)).boxed())
+        }
+
+        async fn supports_native_tools(&self) -> bool {
+            self.native_tools
+        }
+
+        fn clone_box(&self) -> Box<dyn AIApiTrait> {
+            Box::new(self.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::ScriptedApi;
+    use super::*;
+
+    fn user(content: &str) -> Vec<ChatMessage> {
+        vec![ChatMessage::user(content)]
+    }
+
+    async fn collect(api: &dyn AIApiTrait, messages: Vec<ChatMessage>) -> Result<String, ApiError> {
+        let mut stream = api.get_chat_completion_stream(messages, None).await?;
+        let mut content = String::new();
+        while let Some(chunk) = stream.next().await {
+            if let ChatStreamChunk::Content(text) = chunk? {
+                content.push_str(&text);
+            }
+        }
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_responses_by_request() {
+        let dir =
+            std::env::temp_dir().join(format!("ai-integration-replay-{}", std::process::id()));
+        let scripted = ScriptedApi::new(vec![
+            vec![
+                ChatStreamChunk::Content("Hello, ".to_string()),
+                ChatStreamChunk::Content("world".to_string()),
+            ],
+            vec![ChatStreamChunk::Content("Bye".to_string())],
+        ]);
+        let recorder = RecordingApi::new(Box::new(scripted), dir.clone());
+        assert_eq!(
+            collect(&recorder, user("hi")).await.unwrap(),
+            "Hello, world"
+        );
+        assert_eq!(collect(&recorder, user("bye")).await.unwrap(), "Bye");
+        assert!(!recorder.supports_native_tools().await);
+
+        // 順序に関係なく、同じリクエストには同じ応答を返す
+        let replay = ReplayApi::new(dir.clone(), "scripted".to_string());
+        assert_eq!(collect(&replay, user("bye")).await.unwrap(), "Bye");
+        assert_eq!(collect(&replay, user("hi")).await.unwrap(), "Hello, world");
+        assert!(matches!(
+            collect(&replay, user("unknown")).await,
+            Err(ApiError::Message(_))
+        ));
+        assert_eq!(
+            replay.list_models().await.unwrap()["models"][0]["name"],
+            "scripted"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,132 @@
+// src/modules/agent/api/retry.rs
+use async_trait::async_trait;
+use std::boxed::Box;
+
+use crate::modules::agent::api::{
+    AIApiTrait, ApiError, ChatCompletionStream, ChatMessage, GenerationOptions, NetworkConfig,
+    PullProgressStream, ResponseFormat, peek_stream,
+};
+
+/// 最初のトークンが届く前の一時的なエラー (タイムアウト、レート制限、5xx) を
+/// 指数バックオフで再試行するAPI。トークンが届き始めた後のエラーはそのまま返す。
+pub struct RetryApi {
+    inner: Box<dyn AIApiTrait>,
+    config: NetworkConfig,
+}
+
+impl RetryApi {
+    pub fn new(inner: Box<dyn AIApiTrait>) -> Self {
+        RetryApi {
+            inner,
+            config: NetworkConfig::default(),
+        }
+    }
+}
+
+impl Clone for RetryApi {
+    fn clone(&self) -> Self {
+        RetryApi {
+            inner: self.inner.clone_box(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl AIApiTrait for RetryApi {
+    fn set_model(&mut self, model_name: String) {
+        self.inner.set_model(model_name);
+    }
+
+    fn get_model(&self) -> String {
+        self.inner.get_model()
+    }
+
+    fn set_generation_options(&mut self, options: GenerationOptions) {
+        self.inner.set_generation_options(options);
+    }
+
+    fn get_generation_options(&self) -> GenerationOptions {
+        self.inner.get_generation_options()
+    }
+
+    fn set_network_config(&mut self, config: NetworkConfig) {
+        self.inner.set_network_config(config.clone());
+        self.config = config;
+    }
+
+    fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        self.inner.set_response_format(format);
+    }
+
+    fn set_embedding_model(&mut self, model: Option<String>) {
+        self.inner.set_embedding_model(model);
+    }
+
+    async fn list_models(&self) -> Result<serde_json::Value, ApiError> {
+        self.inner.list_models().await
+    }
+
+    async fn get_chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        tools: Option<Vec<serde_json::Value>>,
+    ) -> Result<ChatCompletionStream, ApiError> {
+        let mut attempt = 0;
+        loop {
+            let result = match self
+                .inner
+                .get_chat_completion_stream(messages.clone(), tools.clone())
+                .await
+            {
+                Ok(stream) => peek_stream(stream).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Err(e) if e.is_retryable() && attempt < self.config.max_retries => {
+                    // サーバーが待機時間を指定していればそれに従う
+                    let delay = match &e {
+                        ApiError::RateLimited(Some(retry_after), _) => *retry_after,
+                        _ => self.config.backoff(attempt),
+                    };
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn supports_native_tools(&self) -> bool {
+        self.inner.supports_native_tools().await
+    }
+
+    async fn context_length(&self) -> Option<usize> {
+        self.inner.context_length().await
+    }
+
+    async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, ApiError> {
+        self.inner.embed(texts).await
+    }
+
+    async fn pull_model(&self, name: &str) -> Result<PullProgressStream, ApiError> {
+        self.inner.pull_model(name).await
+    }
+
+    async fn delete_model(&self, name: &str) -> Result<(), ApiError> {
+        self.inner.delete_model(name).await
+    }
+
+    async fn copy_model(&self, source: &str, destination: &str) -> Result<(), ApiError> {
+        self.inner.copy_model(source, destination).await
+    }
+
+    async fn show_model(&self, name: &str) -> Result<serde_json::Value, ApiError> {
+        self.inner.show_model(name).await
+    }
+
+    fn clone_box(&self) -> Box<dyn AIApiTrait> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,138 @@
+// src/modules/agent/approval.rs
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::sync::oneshot;
+
+use crate::modules::agent::AiToolCall;
+use crate::modules::agent::tools::ToolRisk;
+
+/// ツールを実行する前にユーザーの許可を求めるかどうか
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalPolicy {
+    /// 確認せずに実行する
+    Always,
+    /// 呼び出しごとにユーザーに確認する
+    Ask,
+    /// 実行せず、拒否したことをモデルに伝える
+    Never,
+}
+
+/// 設定ファイルの `tool_approval` セクション。
+/// 指定がなければ、ファイルの書き込みとコマンドの実行だけを確認する
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ApprovalConfig {
+    /// 読み取り専用のツールの方針
+    pub read_only: ApprovalPolicy,
+    /// ネットワークから情報を取得するツールの方針
+    pub network: ApprovalPolicy,
+    /// ファイルを書き込むツールの方針
+    pub writes_files: ApprovalPolicy,
+    /// コマンドを実行するツールの方針
+    pub executes_code: ApprovalPolicy,
+    /// ツール名ごとの方針 (上の分類ごとの方針より優先する)
+    pub tools: BTreeMap<String, ApprovalPolicy>,
+}
+
+impl Default for ApprovalConfig {
+    fn default() -> Self {
+        ApprovalConfig {
+            read_only: ApprovalPolicy::Always,
+            network: ApprovalPolicy::Always,
+            writes_files: ApprovalPolicy::Ask,
+            executes_code: ApprovalPolicy::Ask,
+            tools: BTreeMap::new(),
+        }
+    }
+}
+
+impl ApprovalConfig {
+    /// ツールに適用する方針を返す
+    pub fn policy(&self, tool_name: &str, risk: ToolRisk) -> ApprovalPolicy {
+        if let Some(policy) = self.tools.get(tool_name) {
+            return *policy;
+        }
+        match risk {
+            ToolRisk::ReadOnly => self.read_only,
+            ToolRisk::Network => self.network,
+            ToolRisk::WritesFiles => self.writes_files,
+            ToolRisk::ExecutesCode => self.executes_code,
+        }
+    }
+}
+
+/// 確認に対するユーザーの答え
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApprovalDecision {
+    /// この呼び出しだけを実行する
+    Approve,
+    /// 実行しない
+    Deny,
+    /// このセッションの間、同じツールは確認せずに実行する
+    AlwaysApprove,
+}
+
+/// UIに送るツール実行の確認。
+/// `respond` で答えるまでエージェントは待つ。答えずに破棄した場合は拒否として扱う
+pub struct ApprovalRequest {
+    /// 実行しようとしているツール呼び出し
+    pub call: AiToolCall,
+    pub risk: ToolRisk,
+    responder: oneshot::Sender<ApprovalDecision>,
+}
+
+impl ApprovalRequest {
+    /// 確認と、答えを受け取るためのレシーバーを作る
+    pub fn new(call: AiToolCall, risk: ToolRisk) -> (Self, oneshot::Receiver<ApprovalDecision>) {
+        let (responder, receiver) = oneshot::channel();
+        (
+            ApprovalRequest {
+                call,
+                risk,
+                responder,
+            },
+            receiver,
+        )
+    }
+
+    /// 確認に答える
+    pub fn respond(self, decision: ApprovalDecision) {
+        // エージェントのターンが既に中断されていれば、答えを受け取る側はいない
+        let _ = self.responder.send(decision);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asks_only_for_side_effects_unless_configured() {
+        let config = ApprovalConfig::default();
+        assert_eq!(
+            config.policy("file_read", ToolRisk::ReadOnly),
+            ApprovalPolicy::Always
+        );
+        assert_eq!(
+            config.policy("shell", ToolRisk::ExecutesCode),
+            ApprovalPolicy::Ask
+        );
+
+        let config: ApprovalConfig =
+            serde_yaml::from_str("network: ask\ntools:\n  shell: never\n  file_write: always\n")
+                .unwrap();
+        assert_eq!(
+            config.policy("web_search", ToolRisk::Network),
+            ApprovalPolicy::Ask
+        );
+        assert_eq!(
+            config.policy("shell", ToolRisk::ExecutesCode),
+            ApprovalPolicy::Never
+        );
+        assert_eq!(
+            config.policy("file_write", ToolRisk::WritesFiles),
+            ApprovalPolicy::Always
+        );
+    }
+}
@@ -0,0 +1,293 @@
+// src/modules/agent/context.rs
+use serde::{Deserialize, Serialize};
+
+use crate::modules::agent::api::{ChatMessage, ChatRole};
+
+/// 画像1枚あたりのトークン数の概算
+const TOKENS_PER_IMAGE: usize = 768;
+/// メッセージごとのロールや区切りの分の概算
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// コンテキストが溢れそうな場合に試す手順
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    /// 大きなツールの出力の中間部分を省略する
+    TruncateToolOutputs,
+    /// 古いツールの結果から順に省略する
+    DropToolResults,
+    /// 古いやり取りをモデルに要約させて置き換える
+    Summarize,
+}
+
+/// 設定ファイルの `context` セクション
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ContextConfig {
+    /// 上から順に、収まるまで適用する。それでも収まらなければ古いメッセージから削除する
+    pub strategies: Vec<ContextStrategy>,
+    /// モデルのコンテキスト長を上書きする (Ollama以外のプロバイダでは指定しない限り管理しない)
+    pub max_context_tokens: Option<usize>,
+    /// 応答のために空けておくトークン数 (`max_tokens` が設定されていればそちらを使う)
+    pub reserve_tokens: usize,
+    /// `truncate_tool_outputs` で残すツールの出力の最大文字数
+    pub max_tool_output_chars: usize,
+    /// `summarize` の要約を履歴にも残す (保存や取り消しの対象になる履歴も縮まる)。
+    /// 無効なら送信するメッセージだけを縮め、履歴はそのまま残す
+    pub persist_summaries: bool,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            strategies: vec![
+                ContextStrategy::TruncateToolOutputs,
+                ContextStrategy::DropToolResults,
+                ContextStrategy::Summarize,
+            ],
+            max_context_tokens: None,
+            reserve_tokens: 1024,
+            max_tool_output_chars: 4000,
+            persist_summaries: false,
+        }
+    }
+}
+
+/// テキストのトークン数を概算する。
+/// 英数字は約4文字で1トークン、日本語などの非ASCII文字は1文字で約1トークンとして数える。
+pub fn estimate_tokens(text: &str) -> usize {
+    let (ascii, other) = text.chars().fold((0usize, 0usize), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+    ascii.div_ceil(4) + other
+}
+
+/// メッセージ列全体のトークン数を概算する
+pub fn estimate_messages(messages: &[ChatMessage]) -> usize {
+    messages
+        .iter()
+        .map(|message| {
+            TOKENS_PER_MESSAGE
+                + estimate_tokens(&message.content)
+                + message.attachments.len() * TOKENS_PER_IMAGE
+        })
+        .sum()
+}
+
+/// ツールの実行結果をモデルに返したメッセージかどうか
+pub fn is_tool_result(message: &ChatMessage) -> bool {
+    message.role == ChatRole::Tool
+}
+
+/// 省略済みのツールの結果かどうか (二重に数えたり省略したりしないため)
+fn is_omitted(message: &ChatMessage) -> bool {
+    message.content.ends_with(OMITTED_SUFFIX)
+}
+
+const OMITTED_SUFFIX: &str = "[omitted to save context]";
+
+/// `max_chars` を超えるツールの出力の中間部分を省略し、省略したメッセージの数を返す。
+/// 最後のメッセージも対象にする (1回の出力だけで溢れることがあるため)
+pub fn truncate_tool_outputs(messages: &mut [ChatMessage], max_chars: usize) -> usize {
+    let mut truncated = 0;
+    for message in messages
+        .iter_mut()
+        .filter(|message| is_tool_result(message))
+    {
+        let length = message.content.chars().count();
+        if length <= max_chars {
+            continue;
+        }
+        // 先頭と末尾を残す (エラーメッセージは末尾にあることが多い)
+        let head: String = message.content.chars().take(max_chars / 2).collect();
+        let tail: String = message
+            .content
+            .chars()
+            .skip(length - max_chars / 2)
+            .collect();
+        message.content = format!(
+            "{}\n... [{} characters truncated to save context] ...\n{}",
+            head,
+            length - head.chars().count() - tail.chars().count(),
+            tail
+        );
+        truncated += 1;
+    }
+    truncated
+}
+
+/// 古いツールの結果から順に、`budget` に収まるまで短い注記に置き換え、置き換えた数を返す。
+/// どのツールの結果かは `tool_call` に残る。
+/// 最後のメッセージ (直前のツールの結果) は残す
+pub fn drop_tool_results(messages: &mut [ChatMessage], budget: usize) -> usize {
+    let mut dropped = 0;
+    let last = messages.len().saturating_sub(1);
+    for index in 0..last {
+        if estimate_messages(messages) <= budget {
+            break;
+        }
+        let message = &mut messages[index];
+        if !is_tool_result(message) || is_omitted(message) {
+            continue;
+        }
+        message.content = OMITTED_SUFFIX.to_string();
+        dropped += 1;
+    }
+    dropped
+}
+
+/// 要約の対象にできる古いやり取りの範囲。
+/// システムメッセージより後から、最後のユーザーの発言の直前までを返す
+pub fn summarizable_range(messages: &[ChatMessage]) -> Option<std::ops::Range<usize>> {
+    let start = messages
+        .iter()
+        .position(|message| message.role != ChatRole::System)?;
+    let end = messages
+        .iter()
+        .rposition(|message| message.role == ChatRole::User)?;
+    // 1つのメッセージだけを要約しても縮まらない
+    (end > start + 1).then_some(start..end)
+}
+
+/// システムメッセージと最後のメッセージを残し、`budget` に収まるまで古いメッセージを削除する。
+/// 削除したメッセージの数を返す
+pub fn drop_oldest_messages(messages: &mut Vec<ChatMessage>, budget: usize) -> usize {
+    let mut dropped = 0;
+    while estimate_messages(messages) > budget {
+        let removable = messages
+            .iter()
+            .enumerate()
+            .take(messages.len().saturating_sub(1))
+            .find(|(_, message)| message.role != ChatRole::System)
+            .map(|(index, _)| index);
+        match removable {
+            Some(index) => {
+                messages.remove(index);
+                dropped += 1;
+            }
+            None => break,
+        }
+    }
+    dropped
+}
+
+/// 要約を依頼するためのメッセージを作る
+pub fn summary_request(messages: &[ChatMessage]) -> Vec<ChatMessage> {
+    let transcript: String = messages
+        .iter()
+        .map(|message| {
+            let speaker = match message.role {
+                ChatRole::User => "User",
+                ChatRole::Assistant => "Assistant",
+                ChatRole::System => "System",
+                ChatRole::Tool => "Tool",
+            };
+            format!("{}: {}\n\n", speaker, message.content)
+        })
+        .collect();
+    vec![
+        ChatMessage::system(
+            "Summarize the following conversation between a user and an AI assistant. \
+            Keep facts, decisions, file names, commands and results that later turns may need. \
+            Reply with the summary only.",
+        ),
+        ChatMessage::user(transcript),
+    ]
+}
+
+/// 要約を履歴に入れるメッセージ
+pub fn summary_message(summary: &str) -> ChatMessage {
+    ChatMessage::system(format!(
+        "Summary of the earlier conversation:\n{}",
+        summary.trim()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::agent::api::NativeToolCall;
+
+    fn message(role: ChatRole, content: &str) -> ChatMessage {
+        ChatMessage::new(role, content)
+    }
+
+    fn tool_result(output: &str) -> ChatMessage {
+        ChatMessage {
+            tool_call: Some(NativeToolCall {
+                id: None,
+                name: "shell".to_string(),
+                arguments: serde_json::json!({ "command_line": "ls" }),
+            }),
+            ..message(ChatRole::Tool, output)
+        }
+    }
+
+    #[test]
+    fn estimates_ascii_and_cjk_text() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcdefgh"), 2);
+        assert_eq!(estimate_tokens("こんにちは"), 5);
+    }
+
+    #[test]
+    fn truncates_only_large_tool_outputs() {
+        let mut messages = vec![
+            message(ChatRole::User, &"x".repeat(100)),
+            // ユーザーの発言はツールの結果と同じ書き出しでも省略しない
+            message(
+                ChatRole::User,
+                &format!("Tool result for 'shell':\n{}", "z".repeat(100)),
+            ),
+            tool_result(&"y".repeat(100)),
+            tool_result("short"),
+        ];
+        assert_eq!(truncate_tool_outputs(&mut messages, 40), 1);
+        assert_eq!(messages[0].content.len(), 100);
+        assert!(messages[1].content.ends_with(&"z".repeat(100)));
+        assert!(
+            messages[2]
+                .content
+                .contains("... [60 characters truncated to save context] ...")
+        );
+        assert!(messages[2].content.starts_with(&"y".repeat(20)));
+        assert_eq!(messages[3].content, "short");
+    }
+
+    #[test]
+    fn drops_oldest_tool_results_until_within_budget() {
+        let mut messages = vec![
+            message(ChatRole::System, "system prompt"),
+            message(ChatRole::User, "list files"),
+            tool_result(&"a".repeat(400)),
+            tool_result(&"b".repeat(400)),
+            tool_result(&"c".repeat(400)),
+        ];
+        let budget = estimate_messages(&messages) - 150;
+        assert_eq!(drop_tool_results(&mut messages, budget), 2);
+        assert_eq!(messages[2].content, OMITTED_SUFFIX);
+        assert_eq!(messages[3].content, OMITTED_SUFFIX);
+        assert!(messages[3].tool_call.is_some());
+        // 直前のツールの結果は残す
+        assert!(messages[4].content.contains(&"c".repeat(400)));
+    }
+
+    #[test]
+    fn keeps_system_prompt_and_latest_message() {
+        let mut messages = vec![
+            message(ChatRole::System, "system prompt"),
+            message(ChatRole::User, &"old question ".repeat(50)),
+            message(ChatRole::Assistant, &"old answer ".repeat(50)),
+            message(ChatRole::User, "new question"),
+        ];
+        assert_eq!(summarizable_range(&messages), Some(1..3));
+        assert_eq!(drop_oldest_messages(&mut messages, 20), 2);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "system prompt");
+        assert_eq!(messages[1].content, "new question");
+    }
+}
@@ -0,0 +1,183 @@
+// src/modules/agent/limits.rs
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::modules::agent::AiToolCall;
+
+/// 設定ファイルの `limits` セクション。
+/// ツールを呼び続けるモデルで1ターンが終わらなくなるのを防ぐ。各項目は `null` にすると無制限になる
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LoopLimits {
+    /// 1ターンでツールを実行する回数の上限 (1つの応答で複数のツールを呼んだ場合も1回と数える)
+    pub max_tool_iterations: Option<usize>,
+    /// 1ターンにかける時間の上限 (秒)。実行中のツールは中断せず、次のリクエストの前に確認する
+    pub max_turn_secs: Option<u64>,
+    /// 同じツールを同じ引数で続けて呼び出せる回数
+    pub max_identical_calls: Option<usize>,
+}
+
+impl Default for LoopLimits {
+    fn default() -> Self {
+        LoopLimits {
+            max_tool_iterations: Some(25),
+            max_turn_secs: Some(600),
+            max_identical_calls: Some(3),
+        }
+    }
+}
+
+/// ターンを止めた理由
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitReached {
+    /// ツールの実行回数が上限に達した
+    ToolIterations(usize),
+    /// ターンの経過時間が上限に達した
+    TurnDuration(Duration),
+    /// 同じツール呼び出しが続いた (ツール名, 回数)
+    IdenticalCalls(String, usize),
+}
+
+impl fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitReached::ToolIterations(count) => {
+                write!(
+                    f,
+                    "Stopped after {} tool rounds without a final answer.",
+                    count
+                )
+            }
+            LimitReached::TurnDuration(elapsed) => write!(
+                f,
+                "Stopped after {}s without a final answer.",
+                elapsed.as_secs()
+            ),
+            LimitReached::IdenticalCalls(name, count) => write!(
+                f,
+                "Stopped because '{}' was about to be called {} times in a row with the same arguments.",
+                name, count
+            ),
+        }
+    }
+}
+
+/// 1ターン分のツールループの進み具合を記録し、上限を確認する
+pub struct LoopGuard {
+    limits: LoopLimits,
+    started: Instant,
+    iterations: usize,
+    /// 直前のツール呼び出しと、それが続いた回数
+    last_calls: Option<(String, usize)>,
+}
+
+impl LoopGuard {
+    pub fn new(limits: LoopLimits) -> Self {
+        LoopGuard {
+            limits,
+            started: Instant::now(),
+            iterations: 0,
+            last_calls: None,
+        }
+    }
+
+    /// モデルに次のリクエストを送る前に確認する。ターンの最初のリクエストは常に送る
+    pub fn check_before_request(&self) -> Result<(), LimitReached> {
+        if self.iterations == 0 {
+            return Ok(());
+        }
+        if let Some(max) = self.limits.max_tool_iterations
+            && self.iterations >= max
+        {
+            return Err(LimitReached::ToolIterations(self.iterations));
+        }
+        let elapsed = self.started.elapsed();
+        if let Some(max) = self.limits.max_turn_secs
+            && elapsed >= Duration::from_secs(max)
+        {
+            return Err(LimitReached::TurnDuration(elapsed));
+        }
+        Ok(())
+    }
+
+    /// ツールを実行する前に確認し、実行する回数を数える。
+    /// 上限に達した場合は数えない (続行したときに同じ呼び出しを1回は実行できるように)
+    pub fn record_tool_calls(&mut self, calls: &[AiToolCall]) -> Result<(), LimitReached> {
+        let signature = serde_json::to_string(calls).unwrap_or_default();
+        let repeats = match &self.last_calls {
+            Some((last, count)) if *last == signature => count + 1,
+            _ => 1,
+        };
+        if let Some(max) = self.limits.max_identical_calls
+            && repeats > max
+        {
+            let name = calls
+                .iter()
+                .map(|call| call.tool_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(LimitReached::IdenticalCalls(name, repeats));
+        }
+        self.last_calls = Some((signature, repeats));
+        self.iterations += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shell(command_line: &str) -> Vec<AiToolCall> {
+        vec![AiToolCall {
+            tool_name: "shell".to_string(),
+            parameters: json!({ "command_line": command_line }),
+            id: None,
+        }]
+    }
+
+    #[test]
+    fn stops_after_the_configured_number_of_tool_rounds() {
+        let mut guard = LoopGuard::new(LoopLimits {
+            max_tool_iterations: Some(2),
+            max_identical_calls: None,
+            ..LoopLimits::default()
+        });
+        assert_eq!(guard.check_before_request(), Ok(()));
+        guard.record_tool_calls(&shell("ls")).unwrap();
+        assert_eq!(guard.check_before_request(), Ok(()));
+        guard.record_tool_calls(&shell("ls")).unwrap();
+        assert_eq!(
+            guard.check_before_request(),
+            Err(LimitReached::ToolIterations(2))
+        );
+    }
+
+    #[test]
+    fn stops_repeated_identical_calls_but_not_different_ones() {
+        let mut guard = LoopGuard::new(LoopLimits {
+            max_identical_calls: Some(2),
+            ..LoopLimits::default()
+        });
+        guard.record_tool_calls(&shell("ls")).unwrap();
+        guard.record_tool_calls(&shell("ls")).unwrap();
+        assert_eq!(
+            guard.record_tool_calls(&shell("ls")),
+            Err(LimitReached::IdenticalCalls("shell".to_string(), 3))
+        );
+        // 別の呼び出しを挟めば数え直す
+        guard.record_tool_calls(&shell("pwd")).unwrap();
+        guard.record_tool_calls(&shell("ls")).unwrap();
+
+        // 上限なし
+        let mut guard = LoopGuard::new(LoopLimits {
+            max_identical_calls: None,
+            ..LoopLimits::default()
+        });
+        for _ in 0..10 {
+            guard.record_tool_calls(&shell("ls")).unwrap();
+        }
+    }
+}
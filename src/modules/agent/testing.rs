@@ -0,0 +1,459 @@
+// src/modules/agent/testing.rs
+use crate::modules::agent::api::{ApiError, ChatCompletionStream, ChatStreamChunk, TokenUsage};
+use crate::modules::agent::tools::{Tool, ToolError};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// 偽のサーバーが提供するモデルの名前
+pub const FAKE_MODEL: &str = "fake:latest";
+
+/// 偽のサーバーが返す1回分の応答
+#[derive(Debug, Clone)]
+pub enum FakeReply {
+    /// NDJSONの行を順に送る。各行はバイト単位で2回に分けて書き込み、
+    /// 行やUTF-8の文字がネットワークのチャンクの境界で分割される場合を再現する
+    Stream(Vec<Value>),
+    /// SSEのイベント (空行で終わる `event:` や `data:` の行) を順に送る。書き込み方は `Stream` と同じ
+    Sse(Vec<String>),
+    /// 指定したステータスコードと本文を返す
+    Status(u16, Value),
+}
+
+impl FakeReply {
+    /// 本文を `pieces` の断片に分けて送り、最後に使用量を含む `done` の行を送る
+    pub fn text(pieces: &[&str]) -> Self {
+        let mut lines: Vec<Value> = pieces
+            .iter()
+            .map(|piece| {
+                json!({
+                    "model": FAKE_MODEL,
+                    "created_at": "2024-01-01T00:00:00Z",
+                    "message": { "role": "assistant", "content": piece },
+                    "done": false,
+                })
+            })
+            .collect();
+        lines.push(Self::done_line());
+        FakeReply::Stream(lines)
+    }
+
+    /// ネイティブのツール呼び出しを1つ返す
+    pub fn tool_call(name: &str, arguments: Value) -> Self {
+        Self::tool_calls(&[(name, arguments)])
+    }
+
+    /// 1つの応答でネイティブのツール呼び出しを複数返す
+    pub fn tool_calls(calls: &[(&str, Value)]) -> Self {
+        let tool_calls: Vec<Value> = calls
+            .iter()
+            .map(
+                |(name, arguments)| json!({ "function": { "name": name, "arguments": arguments } }),
+            )
+            .collect();
+        FakeReply::Stream(vec![
+            json!({
+                "model": FAKE_MODEL,
+                "created_at": "2024-01-01T00:00:00Z",
+                "message": {
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": tool_calls,
+                },
+                "done": false,
+            }),
+            Self::done_line(),
+        ])
+    }
+
+    /// 本文の断片を送った後、ストリームの途中でエラーの行を送る
+    pub fn error_after(pieces: &[&str], error: &str) -> Self {
+        let FakeReply::Stream(mut lines) = Self::text(pieces) else {
+            unreachable!()
+        };
+        lines.pop();
+        lines.push(json!({ "error": error }));
+        FakeReply::Stream(lines)
+    }
+
+    /// `data:` だけのSSEイベントを順に送る
+    pub fn sse(data: &[Value]) -> Self {
+        FakeReply::Sse(
+            data.iter()
+                .map(|data| format!("data: {}\n\n", data))
+                .collect(),
+        )
+    }
+
+    /// `event:` と `data:` を持つSSEイベントを順に送る
+    pub fn sse_events(events: &[(&str, Value)]) -> Self {
+        FakeReply::Sse(
+            events
+                .iter()
+                .map(|(event, data)| format!("event: {}\ndata: {}\n\n", event, data))
+                .collect(),
+        )
+    }
+
+    fn done_line() -> Value {
+        json!({
+            "model": FAKE_MODEL,
+            "created_at": "2024-01-01T00:00:00Z",
+            "message": { "role": "assistant", "content": "" },
+            "done": true,
+            "prompt_eval_count": 10,
+            "eval_count": 5,
+            "eval_duration": 1_000_000_000u64,
+            "total_duration": 1_500_000_000u64,
+        })
+    }
+}
+
+/// ストリームを最後まで読んだ結果
+#[derive(Debug, Default)]
+pub struct CollectedReply {
+    /// 本文の断片を連結したもの
+    pub content: String,
+    /// 思考過程の断片を連結したもの
+    pub reasoning: String,
+    /// 届いた使用量をエージェントと同じように `TokenUsage::merge` でまとめたもの
+    pub usage: Option<TokenUsage>,
+}
+
+/// プロバイダのストリームを最後まで読む。途中でエラーが届いた場合はそのエラーを返す
+pub async fn collect_reply(mut stream: ChatCompletionStream) -> Result<CollectedReply, ApiError> {
+    let mut reply = CollectedReply::default();
+    while let Some(chunk) = stream.next().await {
+        match chunk? {
+            ChatStreamChunk::Content(text) => reply.content.push_str(&text),
+            ChatStreamChunk::Reasoning(text) => reply.reasoning.push_str(&text),
+            ChatStreamChunk::Usage(usage) => reply.usage.get_or_insert_default().merge(usage),
+            ChatStreamChunk::ToolCalls(_) | ChatStreamChunk::Backend(..) => {}
+        }
+    }
+    Ok(reply)
+}
+
+/// コマンドを実行しない `shell` ツールの代わり。
+/// 頼まれたコマンドを記録し、`echo` の引数だけを出力として返す
+#[derive(Clone, Default)]
+pub struct FakeShell {
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeShell {
+    /// これまでに実行を頼まれたコマンド
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Tool for FakeShell {
+    fn name(&self) -> &'static str {
+        "shell"
+    }
+
+    fn description(&self) -> &'static str {
+        "Runs a shell command."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "command_line": { "type": "string" } },
+            "required": ["command_line"]
+        })
+    }
+
+    async fn execute(&self, args: Value) -> Result<Value, ToolError> {
+        let command_line = args["command_line"].as_str().unwrap_or_default();
+        self.commands.lock().unwrap().push(command_line.to_string());
+        let output = command_line.strip_prefix("echo ").unwrap_or_default();
+        Ok(json!({ "stdout": format!("{}\n", output) }))
+    }
+}
+
+#[derive(Default)]
+struct FakeState {
+    replies: VecDeque<FakeReply>,
+    chat_requests: Vec<Value>,
+    native_tools: bool,
+}
+
+/// テスト用の偽のOllamaサーバー。
+/// `/api/chat` には台本どおりの応答をストリームで返し、`/api/tags` と `/api/show` にも最低限の内容で応答する。
+/// 値を破棄するとサーバーも停止する
+pub struct FakeOllama {
+    pub base_url: String,
+    state: Arc<Mutex<FakeState>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl FakeOllama {
+    /// ローカルの空いているポートでサーバーを起動する。
+    /// `native_tools` が真なら `/api/show` でツール呼び出しに対応していると答える
+    pub async fn start(replies: Vec<FakeReply>, native_tools: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(FakeState {
+            replies: replies.into(),
+            native_tools,
+            ..FakeState::default()
+        }));
+
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(socket, server_state.clone()));
+            }
+        });
+        FakeOllama {
+            base_url,
+            state,
+            server,
+        }
+    }
+
+    /// これまでに受け取った `/api/chat` のリクエストの本文
+    pub fn chat_requests(&self) -> Vec<Value> {
+        self.state.lock().unwrap().chat_requests.clone()
+    }
+}
+
+impl Drop for FakeOllama {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// 偽のサーバーが受け取ったリクエスト
+#[derive(Debug, Clone)]
+pub struct FakeRequest {
+    /// クエリ文字列を含むパス
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Value,
+}
+
+impl FakeRequest {
+    /// ヘッダーの値 (名前の大文字と小文字は区別しない)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Default)]
+struct FakeHttpState {
+    replies: VecDeque<FakeReply>,
+    requests: Vec<FakeRequest>,
+}
+
+/// パスに関係なく、届いたリクエストに台本どおりの応答を順に返す偽のHTTPサーバー。
+/// Ollama以外のプロバイダのテストに使う。値を破棄するとサーバーも停止する
+pub struct FakeHttp {
+    pub base_url: String,
+    state: Arc<Mutex<FakeHttpState>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl FakeHttp {
+    /// ローカルの空いているポートでサーバーを起動する
+    pub async fn start(replies: Vec<FakeReply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(FakeHttpState {
+            replies: replies.into(),
+            ..FakeHttpState::default()
+        }));
+
+        let server_state = state.clone();
+        let server = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle_http_connection(socket, server_state.clone()));
+            }
+        });
+        FakeHttp {
+            base_url,
+            state,
+            server,
+        }
+    }
+
+    /// これまでに受け取ったリクエスト
+    pub fn requests(&self) -> Vec<FakeRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for FakeHttp {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// `FakeHttp` の接続で1つのリクエストを読んで記録し、次の応答を返す
+async fn handle_http_connection(mut socket: TcpStream, state: Arc<Mutex<FakeHttpState>>) {
+    let Some((head, body)) = read_request(&mut socket).await else {
+        return;
+    };
+    let mut lines = head.lines();
+    let path = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default()
+        .to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let reply = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(FakeRequest {
+            path,
+            headers,
+            body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+        });
+        state.replies.pop_front().unwrap_or_else(|| {
+            FakeReply::Status(
+                500,
+                json!({ "error": "the fake server has no more replies" }),
+            )
+        })
+    };
+    write_reply(&mut socket, reply).await;
+}
+
+/// 1つの接続で1つのリクエストを読み、応答して接続を閉じる
+async fn handle_connection(mut socket: TcpStream, state: Arc<Mutex<FakeState>>) {
+    let Some((head, body)) = read_request(&mut socket).await else {
+        return;
+    };
+    let path = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+
+    let reply = match path {
+        "/api/tags" => FakeReply::Status(
+            200,
+            json!({ "models": [{ "name": FAKE_MODEL, "size": 1_000_000_000u64 }] }),
+        ),
+        "/api/show" => {
+            let native_tools = state.lock().unwrap().native_tools;
+            let mut capabilities = vec!["completion"];
+            if native_tools {
+                capabilities.push("tools");
+            }
+            FakeReply::Status(
+                200,
+                json!({
+                    "capabilities": capabilities,
+                    "model_info": { "fake.context_length": 32768 },
+                }),
+            )
+        }
+        "/api/chat" => {
+            let mut state = state.lock().unwrap();
+            state
+                .chat_requests
+                .push(serde_json::from_slice(&body).unwrap_or(Value::Null));
+            state.replies.pop_front().unwrap_or_else(|| {
+                FakeReply::Status(
+                    500,
+                    json!({ "error": "the fake server has no more replies" }),
+                )
+            })
+        }
+        _ => FakeReply::Status(404, json!({ "error": "not found" })),
+    };
+    write_reply(&mut socket, reply).await;
+}
+
+/// 応答を書き込んで接続を閉じる
+async fn write_reply(socket: &mut TcpStream, reply: FakeReply) {
+    match reply {
+        FakeReply::Status(status, body) => {
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+        FakeReply::Stream(lines) => {
+            let lines = lines.iter().map(|line| format!("{}\n", line)).collect();
+            write_chunks(socket, "application/x-ndjson", lines).await;
+        }
+        FakeReply::Sse(events) => write_chunks(socket, "text/event-stream", events).await,
+    }
+    let _ = socket.shutdown().await;
+}
+
+/// ストリームの本文を、それぞれバイト単位で2回に分けて書き込む
+async fn write_chunks(socket: &mut TcpStream, content_type: &str, chunks: Vec<String>) {
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n",
+        content_type
+    );
+    if socket.write_all(header.as_bytes()).await.is_err() {
+        return;
+    }
+    for chunk in chunks {
+        let (first, second) = chunk.as_bytes().split_at(chunk.len() / 2);
+        for part in [first, second] {
+            if socket.write_all(part).await.is_err() || socket.flush().await.is_err() {
+                return;
+            }
+            // 別々のチャンクとして届くように少し待つ
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    }
+}
+
+/// リクエスト行とヘッダー、本文を読む (`Content-Length` のみに対応)
+async fn read_request(socket: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let headers = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let content_length = headers
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse::<usize>().ok())?
+        })
+        .unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        let read = socket.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+
+    Some((headers, buffer[header_end..].to_vec()))
+}
@@ -0,0 +1,431 @@
+// src/modules/agent/tool_parser.rs
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::modules::agent::AiToolCall;
+
+/// ツール呼び出しのつもりで書かれたが、パースまたは検証に失敗したブロック
+#[derive(Debug, Clone, PartialEq)]
+pub struct MalformedToolCall {
+    /// 失敗した理由
+    pub error: String,
+    /// ブロックの内容
+    pub block: String,
+}
+
+/// ツール呼び出しのブロックの内容をパースし、検証する。
+/// `tool_calls` のリスト、`tool_call` キーを持つオブジェクト、持たないオブジェクトの3つの形式に対応する
+pub fn parse_tool_call_block(block_content: &str) -> Result<Vec<AiToolCall>, String> {
+    #[derive(Debug, Deserialize)]
+    struct ToolCallsWrapper {
+        tool_calls: Vec<AiToolCall>,
+    }
+    #[derive(Debug, Deserialize)]
+    struct ToolCallWrapper {
+        tool_call: AiToolCall,
+    }
+
+    let list =
+        serde_yaml::from_str::<ToolCallsWrapper>(block_content).map(|wrapper| wrapper.tool_calls);
+    let single = serde_yaml::from_str::<ToolCallWrapper>(block_content)
+        .map(|wrapper| vec![wrapper.tool_call]);
+    let bare = serde_yaml::from_str::<AiToolCall>(block_content).map(|call| vec![call]);
+    let calls = match (list, single, bare) {
+        (Ok(calls), _, _) | (_, Ok(calls), _) | (_, _, Ok(calls)) => calls,
+        // どの形式も合わない場合は、書こうとしたと思われる形式のエラーを返す
+        (Err(list_error), Err(single_error), Err(bare_error)) => {
+            let error = if block_content.contains("tool_calls:") {
+                list_error
+            } else if block_content.contains("tool_call:") {
+                single_error
+            } else {
+                bare_error
+            };
+            return Err(error.to_string());
+        }
+    };
+
+    if calls.is_empty() {
+        return Err("`tool_calls` is empty.".to_string());
+    }
+    calls
+        .into_iter()
+        .map(|mut call| {
+            if call.tool_name.trim().is_empty() {
+                return Err("`tool_name` is empty.".to_string());
+            }
+            match call.parameters {
+                // 引数のないツールでは `parameters` を省略しても良い
+                Value::Null => call.parameters = Value::Object(Default::default()),
+                Value::Object(_) => {}
+                _ => {
+                    return Err(format!(
+                        "`parameters` of '{}' must be a mapping from parameter names to values.",
+                        call.tool_name
+                    ));
+                }
+            }
+            Ok(call)
+        })
+        .collect()
+}
+
+/// ブロックの開始・終了マーカー
+const MARKERS: [&str; 2] = ["```", "---"];
+
+/// 行がブロックの開始マーカーなら、マーカーと言語指定子を返す
+fn block_opener(line: &str) -> Option<(&'static str, &str)> {
+    let trimmed = line.trim();
+    MARKERS.iter().find_map(|marker| {
+        trimmed
+            .strip_prefix(marker)
+            .map(|lang_specifier| (*marker, lang_specifier.trim()))
+    })
+}
+
+/// 改行がまだ届いていない行が、この後ブロックのマーカーになりうるか
+fn may_become_marker(partial_line: &str) -> bool {
+    let trimmed = partial_line.trim_start();
+    MARKERS
+        .iter()
+        .any(|marker| marker.starts_with(trimmed) || trimmed.starts_with(marker))
+}
+
+/// `yaml tool` や `tool_call` のように、ツール用と明示された言語指定子か
+fn is_marked_as_tool(lang_specifier: &str) -> bool {
+    lang_specifier.starts_with("tool_call")
+        || lang_specifier.split_whitespace().any(|word| word == "tool")
+}
+
+/// ツール呼び出しの書き出しとしてありうる行か (明示されていないブロックを早めに表示するための判定)
+fn looks_like_tool_call_start(line: &str) -> bool {
+    ["tool_call", "tool_name", "parameters", "{", "- tool_name"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+/// ツール呼び出しの可能性があるため、表示を保留しているブロック
+struct HeldBlock {
+    /// 閉じるマーカー
+    marker: &'static str,
+    /// ツール用と明示されているか
+    marked_as_tool: bool,
+    /// 開始マーカーの行
+    opener: String,
+    /// ブロックの本体
+    content: String,
+}
+
+impl HeldBlock {
+    /// 保留していたテキストをそのまま返す
+    fn into_text(self) -> String {
+        self.opener + &self.content
+    }
+}
+
+/// ストリームで届く応答からツール呼び出しのブロックを検出する。
+/// 通常のテキストはすぐに表示用に返し、ブロックの開始マーカーになりうる行以降だけを、
+/// ツール呼び出しではないと分かるまで保留する。
+/// `---` または ` ``` ` で囲まれ、言語指定子が空、`yaml`、`json` またはツール用と明示されたブロックを候補とし、
+/// 明示されていない候補は本体の最初の行がツール呼び出しらしくなければその時点で表示する。
+/// 最初のツール呼び出しより後のテキストは表示しない
+#[derive(Default)]
+pub struct ToolCallParser {
+    /// 改行がまだ届いていない現在の行
+    line: String,
+    /// 現在の行のうち、表示用に返したバイト数
+    line_released: usize,
+    /// 保留しているブロック
+    block: Option<HeldBlock>,
+    /// これまでに表示用に返したテキスト
+    displayed: String,
+    /// 出力待ちの表示用テキスト
+    output: String,
+    calls: Vec<AiToolCall>,
+    malformed: Vec<MalformedToolCall>,
+    /// 最初のツール呼び出しより前に表示したテキスト
+    pre_content: String,
+    /// ツール呼び出しの後に通常のテキストが始まった
+    stopped: bool,
+}
+
+impl ToolCallParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 受信したテキストを渡し、表示してよいテキストを返す
+    pub fn push(&mut self, chunk: &str) -> String {
+        for piece in chunk.split_inclusive('\n') {
+            self.line.push_str(piece);
+            if piece.ends_with('\n') {
+                let line = std::mem::take(&mut self.line);
+                self.handle_line(line);
+            } else {
+                self.handle_partial_line();
+            }
+        }
+        std::mem::take(&mut self.output)
+    }
+
+    /// ストリームの終わりに呼び、保留していたテキストのうち表示してよいものを返す。
+    /// 最後の行は改行がなくても行として扱う (閉じるマーカーで応答が終わることがあるため)
+    pub fn finish(&mut self) -> String {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.handle_line(line);
+        }
+        if let Some(block) = self.block.take() {
+            // 閉じられなかったブロックはツール呼び出しとして扱わない
+            self.release(&block.into_text());
+        }
+        std::mem::take(&mut self.output)
+    }
+
+    /// 応答に書かれた順のツール呼び出し
+    pub fn calls(&self) -> &[AiToolCall] {
+        &self.calls
+    }
+
+    /// ツール呼び出しのつもりで書かれたがパースできなかったブロック
+    pub fn malformed(&self) -> &[MalformedToolCall] {
+        &self.malformed
+    }
+
+    /// 最初のツール呼び出しより前に表示したテキスト (末尾の空白を除く)
+    pub fn pre_content(&self) -> &str {
+        &self.pre_content
+    }
+
+    /// ツール呼び出しの後に通常のテキストが始まったため、受信を止めてよいか
+    /// (ツールの結果を予想して書き始めることがあるため)
+    pub fn should_stop(&self) -> bool {
+        self.stopped
+    }
+
+    fn handle_partial_line(&mut self) {
+        if self.stopped || self.block.is_some() {
+            return;
+        }
+        if self.line_released > 0 {
+            // 通常のテキストと分かっている行の続き
+            let rest = self.line[self.line_released..].to_string();
+            self.release(&rest);
+            self.line_released = self.line.len();
+        } else if !may_become_marker(&self.line) {
+            let line = self.line.clone();
+            self.release(&line);
+            self.line_released = self.line.len();
+        }
+    }
+
+    fn handle_line(&mut self, line: String) {
+        let released = std::mem::take(&mut self.line_released);
+        if self.stopped {
+            return;
+        }
+        if released > 0 {
+            self.release(&line[released..]);
+            return;
+        }
+
+        if let Some(block) = self.block.as_mut() {
+            if line.trim() == block.marker {
+                let block = self.block.take().unwrap();
+                self.close_block(block, &line);
+                return;
+            }
+            let is_first_line = block.content.trim().is_empty();
+            block.content.push_str(&line);
+            if is_first_line
+                && !line.trim().is_empty()
+                && !block.marked_as_tool
+                && !looks_like_tool_call_start(line.trim())
+            {
+                // ツール呼び出しではないと分かったので、保留していたテキストを表示する
+                let block = self.block.take().unwrap();
+                self.release(&block.into_text());
+            }
+            return;
+        }
+
+        let candidate = block_opener(&line).filter(|(_, lang_specifier)| {
+            lang_specifier.is_empty()
+                || *lang_specifier == "yaml"
+                || *lang_specifier == "json"
+                || is_marked_as_tool(lang_specifier)
+        });
+        match candidate {
+            Some((marker, lang_specifier)) => {
+                self.block = Some(HeldBlock {
+                    marker,
+                    marked_as_tool: is_marked_as_tool(lang_specifier),
+                    opener: line.clone(),
+                    content: String::new(),
+                });
+            }
+            None => self.release(&line),
+        }
+    }
+
+    fn close_block(&mut self, block: HeldBlock, closer: &str) {
+        let mentions_tool_call = block.content.contains("tool_name:")
+            || block.content.contains("tool_call:")
+            || block.content.contains("tool_calls:");
+        match parse_tool_call_block(&block.content) {
+            Ok(calls) => {
+                if self.calls.is_empty() {
+                    self.pre_content = self.displayed.trim_end().to_string();
+                }
+                self.calls.extend(calls);
+            }
+            Err(error) => {
+                if block.marked_as_tool || mentions_tool_call {
+                    // ツール呼び出しのつもりで書かれたブロック。モデルに書き直させるために記録する
+                    self.malformed.push(MalformedToolCall {
+                        error,
+                        block: block.content.clone(),
+                    });
+                }
+                let text = block.into_text() + closer;
+                self.release(&text);
+            }
+        }
+    }
+
+    /// 表示用にテキストを返す。
+    /// ツール呼び出しの後のテキストは表示せず、空白以外が書かれたら受信を止める
+    fn release(&mut self, text: &str) {
+        if self.calls.is_empty() {
+            self.displayed.push_str(text);
+            self.output.push_str(text);
+        } else if !text.trim().is_empty() {
+            self.stopped = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// 1文字ずつ渡し、表示されたテキストを返す
+    fn feed_by_char(parser: &mut ToolCallParser, text: &str) -> String {
+        let mut displayed: String = text.chars().map(|c| parser.push(&c.to_string())).collect();
+        displayed.push_str(&parser.finish());
+        displayed
+    }
+
+    #[test]
+    fn extracts_tool_call_blocks_from_text() {
+        let response = "Let me look.\n```yaml\ntool_call:\n  tool_name: file_read\n  parameters:\n    path: Cargo.toml\n```\nignored";
+        let mut parser = ToolCallParser::new();
+        assert_eq!(parser.push(response), "Let me look.\n");
+        assert!(parser.should_stop());
+        assert_eq!(parser.calls().len(), 1);
+        assert_eq!(parser.calls()[0].tool_name, "file_read");
+        assert_eq!(
+            parser.calls()[0].parameters,
+            json!({ "path": "Cargo.toml" })
+        );
+        assert_eq!(parser.pre_content(), "Let me look.");
+
+        // 閉じていないブロックや、ツール呼び出しではないコードブロックはそのまま表示する
+        for text in [
+            "---tool_call\ntool_name: shell\n",
+            "```rust\nfn main() {}\n```",
+            "Intro\n---\nA section after a rule.\n---\nmore",
+        ] {
+            let mut parser = ToolCallParser::new();
+            assert_eq!(feed_by_char(&mut parser, text), text);
+            assert!(parser.calls().is_empty());
+        }
+    }
+
+    #[test]
+    fn holds_back_tool_call_blocks_while_streaming() {
+        let response = "Checking.\n```yaml tool\ntool_call:\n  tool_name: shell\n  parameters: { command_line: ls }\n```";
+        let mut parser = ToolCallParser::new();
+        let mut displayed = String::new();
+        for c in response.chars() {
+            displayed.push_str(&parser.push(&c.to_string()));
+            // 開始マーカーやYAMLの一部は表示されない
+            assert!(!displayed.contains('`'), "leaked: {:?}", displayed);
+        }
+        // 通常のテキストは改行を待たずに表示される
+        assert_eq!(displayed, "Checking.\n");
+        assert!(parser.calls().is_empty());
+        assert_eq!(parser.finish(), "");
+        assert_eq!(
+            parser.calls()[0].parameters,
+            json!({ "command_line": "ls" })
+        );
+        assert_eq!(parser.pre_content(), "Checking.");
+
+        let mut parser = ToolCallParser::new();
+        assert_eq!(parser.push("Hello wor"), "Hello wor");
+        assert_eq!(parser.push("ld\n``"), "ld\n");
+        assert_eq!(
+            parser.push("`yaml\nname: not a tool call\n"),
+            "```yaml\nname: not a tool call\n"
+        );
+    }
+
+    #[test]
+    fn reports_tool_call_blocks_that_cannot_be_parsed() {
+        // `parameters` がマッピングではない
+        let response = "```yaml tool\ntool_call:\n  tool_name: shell\n  parameters: ls -l\n```\n```yaml\nname: not a tool call\n```";
+        let mut parser = ToolCallParser::new();
+        // パースできなかったブロックは通常のテキストとして表示する
+        assert_eq!(feed_by_char(&mut parser, response), response);
+        assert!(parser.calls().is_empty());
+        assert_eq!(parser.malformed().len(), 1);
+        assert!(
+            parser.malformed()[0]
+                .error
+                .contains("`parameters` of 'shell'")
+        );
+        assert!(parser.malformed()[0].block.contains("parameters: ls -l"));
+
+        // YAMLとして壊れている
+        let mut parser = ToolCallParser::new();
+        parser.push("---tool_call\ntool_name: [shell\n---\n");
+        assert_eq!(parser.malformed().len(), 1);
+
+        // 引数のないツールは `parameters` を省略できる
+        let mut parser = ToolCallParser::new();
+        parser.push("```yaml tool\ntool_call:\n  tool_name: system_info\n  parameters:\n```");
+        parser.finish();
+        assert!(parser.malformed().is_empty());
+        assert_eq!(parser.calls()[0].parameters, json!({}));
+    }
+
+    #[test]
+    fn extracts_several_tool_calls_from_one_response() {
+        // 複数のブロック
+        let response = "---tool_call\ntool_name: file_read\nparameters:\n  path: a.txt\n---\n\n```yaml\ntool_call:\n  tool_name: file_read\n  parameters:\n    path: b.txt\n```\n\n``";
+        let mut parser = ToolCallParser::new();
+        assert_eq!(parser.push(response), "");
+        assert!(!parser.should_stop());
+        let paths: Vec<&Value> = parser
+            .calls()
+            .iter()
+            .map(|call| &call.parameters["path"])
+            .collect();
+        assert_eq!(paths, [&json!("a.txt"), &json!("b.txt")]);
+        assert_eq!(parser.pre_content(), "");
+
+        // `tool_calls` のリスト
+        let response = "```yaml\ntool_calls:\n  - tool_name: weather\n    parameters: { city: Tokyo }\n  - tool_name: system_info\n    parameters: {}\n```";
+        let mut parser = ToolCallParser::new();
+        parser.push(response);
+        parser.finish();
+        let names: Vec<&str> = parser
+            .calls()
+            .iter()
+            .map(|call| call.tool_name.as_str())
+            .collect();
+        assert_eq!(names, ["weather", "system_info"]);
+    }
+}
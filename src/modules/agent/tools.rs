@@ -1,11 +1,14 @@
 // src/modules/agent/tools.rs (previously src/modules/tools.rs, assuming it was moved/renamed)
+pub mod delegate;
 pub mod files;
 pub mod shell;
 pub mod utils;
 pub mod www;
 use async_trait::async_trait;
-use std::collections::HashMap;
+use futures_util::future::join_all;
+use std::collections::BTreeMap;
 use std::io::Error as IoError;
+use std::sync::Arc;
 
 // Import ApiError so we can use it in ToolError
 use crate::modules::agent::api::ApiError;
@@ -21,6 +24,8 @@ pub enum ToolError {
     DeserializationError(String),
     Io(IoError),
     Api(ApiError),
+    /// ユーザーまたは設定によって実行を拒否された
+    Denied(String),
 }
 
 // Implement Display for better error messages when printed
@@ -36,6 +41,7 @@ impl std::fmt::Display for ToolError {
             }
             ToolError::Io(e) => write!(f, "Tool IO error: {}", e),
             ToolError::Api(e) => write!(f, "API error in tool context: {}", e),
+            ToolError::Denied(msg) => write!(f, "Tool call denied: {}", msg),
         }
     }
 }
@@ -61,6 +67,30 @@ impl From<ApiError> for ToolError {
     }
 }
 
+/// ツールを実行したときに起こりうることの分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolRisk {
+    /// ローカルのファイルやシステムの情報を読むだけ
+    ReadOnly,
+    /// ネットワークから情報を取得する (ローカルの状態は変更しない)
+    Network,
+    /// ファイルを作成・上書きする
+    WritesFiles,
+    /// 任意のコマンドやプログラムを実行する
+    ExecutesCode,
+}
+
+impl std::fmt::Display for ToolRisk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolRisk::ReadOnly => write!(f, "read-only"),
+            ToolRisk::Network => write!(f, "network"),
+            ToolRisk::WritesFiles => write!(f, "writes files"),
+            ToolRisk::ExecutesCode => write!(f, "executes code"),
+        }
+    }
+}
+
 /// AIが呼び出せる個々のツールを表すトレイト
 #[async_trait]
 pub trait Tool: Send + Sync {
@@ -71,13 +101,25 @@ pub trait Tool: Send + Sync {
     /// ツールの引数のJSONスキーマ（AIが正しい形式で引数を渡せるように）
     fn parameters(&self) -> serde_json::Value;
 
+    /// ツールを実行したときに起こりうること（実行前にユーザーの許可を求めるかの判断に使う）
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::ExecutesCode
+    }
+
+    /// 外部の状態を変更しないツールか（真なら他の読み取り専用のツールと並行に実行する）
+    fn is_read_only(&self) -> bool {
+        matches!(self.risk(), ToolRisk::ReadOnly | ToolRisk::Network)
+    }
+
     /// ツールを実行する非同期メソッド
     async fn execute(&self, args: serde_json::Value) -> Result<serde_json::Value, ToolError>;
 }
 
 /// ツールを管理する構造体
+#[derive(Clone)]
 pub struct ToolManager {
-    tools: HashMap<String, Box<dyn Tool>>,
+    // 名前順に保持し、プロンプトやツール定義の順序を毎回同じにする (記録した応答の照合に必要)
+    tools: BTreeMap<String, Arc<dyn Tool>>,
 }
 
 impl Default for ToolManager {
@@ -89,13 +131,23 @@ impl Default for ToolManager {
 impl ToolManager {
     pub fn new() -> Self {
         ToolManager {
-            tools: HashMap::new(),
+            tools: BTreeMap::new(),
         }
     }
 
     /// ツールを登録する
     pub fn register_tool<T: Tool + 'static>(&mut self, tool: T) {
-        self.tools.insert(tool.name().to_string(), Box::new(tool));
+        self.tools.insert(tool.name().to_string(), Arc::new(tool));
+    }
+
+    /// `keep` が真を返す名前のツールだけを残す (子エージェントが使えるツールを制限するため)
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.tools.retain(|name, _| keep(name));
+    }
+
+    /// 登録されているツールの名前 (名前順)
+    pub fn tool_names(&self) -> Vec<String> {
+        self.tools.keys().cloned().collect()
     }
 
     /// 名前でツールを取得する
@@ -103,21 +155,30 @@ impl ToolManager {
         self.tools.get(name).map(|b| b.as_ref())
     }
 
-    /// ツールをYAMLスキーマ形式で取得する（プロンプトに埋め込むため）
-    pub fn get_tool_yaml_schemas(&self) -> serde_yaml::Value {
-        let tool_definitions: Vec<serde_yaml::Value> = self
-            .tools
+    /// ツール定義を関数呼び出し形式のJSONで取得する（ネイティブのツール呼び出しでAPIに渡すため）
+    pub fn get_tool_definitions(&self) -> Vec<serde_json::Value> {
+        self.tools
             .values()
             .map(|tool| {
-                serde_yaml::to_value(serde_json::json!({
+                serde_json::json!({
                     "type": "function",
                     "function": {
                         "name": tool.name(),
                         "description": tool.description(),
                         "parameters": tool.parameters(),
                     }
-                }))
-                .unwrap_or_else(|_| serde_yaml::Value::Null)
+                })
+            })
+            .collect()
+    }
+
+    /// ツールをYAMLスキーマ形式で取得する（プロンプトに埋め込むため）
+    pub fn get_tool_yaml_schemas(&self) -> serde_yaml::Value {
+        let tool_definitions: Vec<serde_yaml::Value> = self
+            .get_tool_definitions()
+            .into_iter()
+            .map(|definition| {
+                serde_yaml::to_value(definition).unwrap_or_else(|_| serde_yaml::Value::Null)
             })
             .collect();
         serde_yaml::to_value(tool_definitions)
@@ -133,7 +194,7 @@ impl ToolManager {
         if let Some(tool) = self.get_tool(name) {
             tool.execute(args).await
         } else {
-            let available_tools: Vec<String> = self.tools.keys().cloned().collect();
+            let available_tools = self.tool_names();
             let error_message = format!(
                 "Tool '{}' not found. Available tools are: [{}]",
                 name,
@@ -142,4 +203,143 @@ impl ToolManager {
             Err(ToolError::NotFound(error_message))
         }
     }
+
+    /// 複数のツール呼び出しを実行し、呼び出しと同じ順に結果を返す。
+    /// 連続する読み取り専用のツールはまとめて並行に実行し、それ以外のツールは前後の呼び出しを待って1つずつ実行する
+    pub async fn execute_tools(
+        &self,
+        calls: &[(String, serde_json::Value)],
+    ) -> Vec<Result<serde_json::Value, ToolError>> {
+        let mut results = Vec::with_capacity(calls.len());
+        let mut index = 0;
+        while index < calls.len() {
+            let is_read_only = |(name, _): &(String, serde_json::Value)| {
+                self.get_tool(name).is_some_and(|tool| tool.is_read_only())
+            };
+            let batch_len = calls[index..]
+                .iter()
+                .take_while(|call| is_read_only(call))
+                .count()
+                .max(1);
+            let batch = &calls[index..index + batch_len];
+            results.extend(
+                join_all(
+                    batch
+                        .iter()
+                        .map(|(name, args)| self.execute_tool(name, args.clone())),
+                )
+                .await,
+            );
+            index += batch_len;
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Barrier;
+
+    struct EchoTool(&'static str);
+
+    /// 他の呼び出しと同時に実行されるまで待つ読み取り専用のツール
+    struct RendezvousTool(&'static str, Arc<Barrier>);
+
+    #[async_trait]
+    impl Tool for RendezvousTool {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn description(&self) -> &'static str {
+            "Waits for the other call."
+        }
+
+        fn parameters(&self) -> serde_json::Value {
+            json!({ "type": "object" })
+        }
+
+        fn risk(&self) -> ToolRisk {
+            ToolRisk::ReadOnly
+        }
+
+        async fn execute(&self, args: serde_json::Value) -> Result<serde_json::Value, ToolError> {
+            self.1.wait().await;
+            Ok(args)
+        }
+    }
+
+    #[async_trait]
+    impl Tool for EchoTool {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn description(&self) -> &'static str {
+            "Returns its arguments."
+        }
+
+        fn parameters(&self) -> serde_json::Value {
+            json!({ "type": "object" })
+        }
+
+        async fn execute(&self, args: serde_json::Value) -> Result<serde_json::Value, ToolError> {
+            Ok(args)
+        }
+    }
+
+    #[tokio::test]
+    async fn executes_registered_tools_by_name() {
+        let mut manager = ToolManager::new();
+        manager.register_tool(EchoTool("zeta"));
+        manager.register_tool(EchoTool("alpha"));
+
+        // 登録順に関係なく名前順に並ぶ
+        let names: Vec<serde_json::Value> = manager
+            .get_tool_definitions()
+            .into_iter()
+            .map(|definition| definition["function"]["name"].clone())
+            .collect();
+        assert_eq!(names, [json!("alpha"), json!("zeta")]);
+
+        let result = manager.execute_tool("zeta", json!({ "x": 1 })).await;
+        assert_eq!(result.unwrap(), json!({ "x": 1 }));
+
+        match manager.execute_tool("missing", json!({})).await {
+            Err(ToolError::NotFound(message)) => {
+                assert!(message.contains("Available tools are: [alpha, zeta]"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_read_only_tools_concurrently_and_keeps_the_call_order() {
+        let barrier = Arc::new(Barrier::new(2));
+        let mut manager = ToolManager::new();
+        manager.register_tool(RendezvousTool("left", barrier.clone()));
+        manager.register_tool(RendezvousTool("right", barrier));
+        manager.register_tool(EchoTool("write"));
+
+        // 1つずつ実行すると互いを待ち続けて終わらない
+        let calls = [
+            ("right".to_string(), json!(1)),
+            ("left".to_string(), json!(2)),
+            ("write".to_string(), json!(3)),
+            ("missing".to_string(), json!(4)),
+        ];
+        let results = tokio::time::timeout(Duration::from_secs(5), manager.execute_tools(&calls))
+            .await
+            .expect("read-only tools should run concurrently");
+
+        assert_eq!(results.len(), 4);
+        for (result, expected) in results[..3].iter().zip([json!(1), json!(2), json!(3)]) {
+            assert_eq!(result.as_ref().unwrap(), &expected);
+        }
+        assert!(matches!(results[3], Err(ToolError::NotFound(_))));
+    }
 }
@@ -0,0 +1,99 @@
+// src/modules/agent/tools/delegate.rs
+use super::{Tool, ToolError, ToolRisk};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+/// 委任ツールの名前 (エージェントのループがこの名前の呼び出しを子エージェントで実行する)
+pub const DELEGATE_TOOL_NAME: &str = "delegate";
+/// `max_steps` を指定しなかった場合の、子エージェントがツールを実行できる回数
+pub const DEFAULT_MAX_STEPS: usize = 8;
+
+/// 独立した作業を、自分の履歴を持つ子エージェントに任せるツール。
+/// 実行はエージェントのループが行い、子エージェントの進捗を中継して最後の要約だけを結果として返す
+pub struct DelegateTool;
+
+/// `delegate` の引数
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct DelegateRequest {
+    /// 子エージェントに任せる作業
+    pub task: String,
+    /// 子エージェントが使うモデル (省略すると親と同じモデル)
+    #[serde(default)]
+    pub model: Option<String>,
+    /// 子エージェントが使えるツール (省略すると `delegate` 以外のすべて)
+    #[serde(default)]
+    pub tools: Option<Vec<String>>,
+    /// 子エージェントがツールを実行できる回数
+    #[serde(default)]
+    pub max_steps: Option<usize>,
+}
+
+impl DelegateRequest {
+    pub fn from_args(args: &Value) -> Result<Self, ToolError> {
+        let request: DelegateRequest = serde_json::from_value(args.clone())
+            .map_err(|e| ToolError::ExecutionError(format!("Invalid arguments: {}", e)))?;
+        if request.task.trim().is_empty() {
+            return Err(ToolError::ExecutionError("'task' is empty.".to_string()));
+        }
+        Ok(request)
+    }
+
+    /// 子エージェントに送る最初のユーザーメッセージ
+    pub fn prompt(&self) -> String {
+        format!(
+            "{}\n\nYou are working on this task for another assistant. \
+            When you are done, reply with a concise summary of what you did and found. \
+            Only that final reply is passed back, so include every detail the other assistant needs.",
+            self.task.trim()
+        )
+    }
+}
+
+#[async_trait]
+impl Tool for DelegateTool {
+    fn name(&self) -> &'static str {
+        DELEGATE_TOOL_NAME
+    }
+
+    fn description(&self) -> &'static str {
+        "Hand a self-contained subtask to a sub-agent with its own conversation. Only its final summary is returned."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "task": {
+                    "type": "string",
+                    "description": "The subtask with all needed context. The sub-agent cannot see this conversation."
+                },
+                "model": {
+                    "type": "string",
+                    "description": "Defaults to the current model."
+                },
+                "tools": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Tools the sub-agent may use. Defaults to all except delegate."
+                },
+                "max_steps": {
+                    "type": "integer",
+                    "description": "Maximum tool rounds. Defaults to 8."
+                }
+            },
+            "required": ["task"]
+        })
+    }
+
+    // 子エージェントはコマンドの実行やファイルの書き込みを行えるため、他の呼び出しと並行には実行しない
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::ExecutesCode
+    }
+
+    async fn execute(&self, _args: Value) -> Result<Value, ToolError> {
+        Err(ToolError::ExecutionError(
+            "The delegate tool can only be run by the agent loop.".to_string(),
+        ))
+    }
+}
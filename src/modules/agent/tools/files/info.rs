@@ -1,5 +1,5 @@
 // src/modules/agent/tools/files/info.rs
-use crate::modules::agent::tools::{Tool, ToolError};
+use crate::modules::agent::tools::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use std::fs;
@@ -30,6 +30,10 @@ impl Tool for InfoTool {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::ReadOnly
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let path_str = args["path"]
             .as_str()
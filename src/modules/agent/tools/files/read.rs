@@ -1,5 +1,5 @@
 // src/modules/agent/tools/files/read.rs
-use crate::modules::agent::tools::{Tool, ToolError};
+use crate::modules::agent::tools::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use std::fs::File;
@@ -45,6 +45,10 @@ impl Tool for ReadTool {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::ReadOnly
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let path_str = args["path"]
             .as_str()
@@ -1,5 +1,5 @@
 // src/modules/tools/shell.rs
-use super::{Tool, ToolError};
+use super::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use tokio::process::Command; // tokio::process::Command を使用
@@ -29,6 +29,10 @@ impl Tool for ShellTool {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::ExecutesCode
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let command_line = args["command_line"].as_str().ok_or_else(|| {
             ToolError::ExecutionError("Missing 'command_line' argument for shell tool.".to_string())
@@ -1,5 +1,5 @@
 // src/modules/agent/tools/utils.rs
-use super::super::{Tool, ToolError};
+use super::super::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use www_search::browse::fetch_and_markdown;
@@ -28,6 +28,10 @@ impl Tool for WeatherTool {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::Network
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let target_url = format!(
             "https://wttr.in/{}",
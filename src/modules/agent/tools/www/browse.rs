@@ -1,5 +1,5 @@
 // src/modules/agent/tools/utils.rs
-use super::super::{Tool, ToolError};
+use super::super::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use www_search::browse;
@@ -29,6 +29,10 @@ impl Tool for WebPageBrowser {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::Network
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let target_url = args["url"].as_str().ok_or_else(|| {
             ToolError::ExecutionError("Missing 'url' argument for browser tool.".to_string())
@@ -1,5 +1,5 @@
 // src/modules/agent/tools/utils.rs
-use super::super::{Tool, ToolError};
+use super::super::{Tool, ToolError, ToolRisk};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use www_search::{EngineType, SearchData, www_search}; // www-search クレートをインポート
@@ -33,6 +33,10 @@ impl Tool for SearchEngineTool {
         })
     }
 
+    fn risk(&self) -> ToolRisk {
+        ToolRisk::Network
+    }
+
     async fn execute(&self, args: Value) -> Result<Value, ToolError> {
         let search_query = args["query"]
             .as_str()
@@ -1,46 +1,178 @@
 pub mod cli;
 pub mod tui;
 
-use crate::modules::agent::api::{AIProvider, ChatMessage, ChatRole};
+use crate::modules::agent::api::{
+    AIProvider, ApiError, Attachment, ChatMessage, GenerationOptions, ResponseFormat, TokenUsage,
+};
 use crate::modules::agent::{AIAgent, AgentEvent};
+use crate::modules::config::AppConfig;
 use anyhow::Result;
 use futures_util::{TryStreamExt, stream::BoxStream};
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
+/// セッション全体で集計したトークン使用量
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionUsage {
+    /// AIへのリクエスト回数
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// 生成トークン数が報告されたリクエストの生成時間の合計
+    pub completion_duration: Duration,
+    pub total_duration: Duration,
+}
+
+impl SessionUsage {
+    /// 1回分の使用量を加算する
+    pub fn add(&mut self, usage: &TokenUsage) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens.unwrap_or(0);
+        self.completion_tokens += usage.completion_tokens.unwrap_or(0);
+        if usage.completion_tokens.is_some()
+            && let Some(duration) = usage.completion_duration.or(usage.total_duration)
+        {
+            self.completion_duration += duration;
+        }
+        self.total_duration += usage.total_duration.unwrap_or_default();
+    }
+
+    /// セッション全体の平均生成速度 (トークン/秒)
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        (!self.completion_duration.is_zero())
+            .then(|| self.completion_tokens as f64 / self.completion_duration.as_secs_f64())
+    }
+}
+
+impl fmt::Display for SessionUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, prompt: {} tok, completion: {} tok, {:.2}s",
+            self.requests,
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.total_duration.as_secs_f64()
+        )?;
+        if let Some(tps) = self.tokens_per_second() {
+            write!(f, ", {:.1} tok/s", tps)?;
+        }
+        Ok(())
+    }
+}
+
+/// エラーをUIに表示する文字列に変換します。対処法が分かるAPIエラーにはヒントを添えます。
+pub fn describe_error(error: &anyhow::Error) -> String {
+    match error.downcast_ref::<ApiError>().and_then(ApiError::hint) {
+        Some(hint) => format!("{}\nHint: {}", error, hint),
+        None => error.to_string(),
+    }
+}
+
+/// `~/` で始まるパスをホームディレクトリとして展開します。
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// `/api/show` の応答から主要な項目を取り出して表示用に整形します。
+fn format_model_info(name: &str, info: &serde_json::Value) -> String {
+    let mut lines = vec![format!("Model: {}", name)];
+    let details = &info["details"];
+    for (label, key) in [
+        ("Family", "family"),
+        ("Parameters", "parameter_size"),
+        ("Quantization", "quantization_level"),
+        ("Format", "format"),
+    ] {
+        if let Some(value) = details[key].as_str() {
+            lines.push(format!("{}: {}", label, value));
+        }
+    }
+    // コンテキスト長は `<アーキテクチャ>.context_length` というキーで入っている
+    if let Some(context_length) = info["model_info"].as_object().and_then(|model_info| {
+        model_info
+            .iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+    }) {
+        lines.push(format!("Context length: {}", context_length));
+    }
+    if let Some(capabilities) = info["capabilities"].as_array() {
+        let capabilities: Vec<&str> = capabilities.iter().filter_map(|c| c.as_str()).collect();
+        lines.push(format!("Capabilities: {}", capabilities.join(", ")));
+    }
+    if let Some(modified_at) = info["modified_at"].as_str() {
+        lines.push(format!("Modified: {}", modified_at));
+    }
+    lines.join("\n")
+}
+
 /// AIエージェントとの単一のチャットセッションを表します。
 /// この構造体はUIから独立しており、チャットの状態管理とAIとの対話ロジックに責任を持ちます。
 #[derive(Clone)]
 pub struct ChatSession {
     agent: Arc<Mutex<AIAgent>>,
     pub current_model: String,
+    usage: Arc<std::sync::Mutex<SessionUsage>>,
+    /// 次のユーザーメッセージに添付する画像
+    pending_attachments: Vec<Attachment>,
 }
 
 impl ChatSession {
     /// 新しいチャットセッションを作成します。
-    pub fn new(provider: AIProvider, base_url: String, default_model: String) -> Self {
+    pub fn new(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+        config: AppConfig,
+    ) -> Self {
         let agent = Arc::new(Mutex::new(AIAgent::new(
             provider,
             base_url,
             default_model.clone(),
+            &config,
         )));
         ChatSession {
             agent,
             current_model: default_model,
+            usage: Arc::new(std::sync::Mutex::new(SessionUsage::default())),
+            pending_attachments: Vec::new(),
         }
     }
 
     /// ユーザーメッセージをセッション履歴に追加します。
     /// このメソッドはUIに依存せず、内部状態のみを更新します。
+    /// `/attach` で指定された画像はこのメッセージに添付されます。
     pub async fn add_user_message(&mut self, content: String) {
+        let attachments = std::mem::take(&mut self.pending_attachments);
         let mut agent_locked = self.agent.lock().await;
         let user_message = ChatMessage {
-            role: ChatRole::User,
-            content,
+            attachments,
+            ..ChatMessage::user(content)
         };
         agent_locked.add_message_to_history(user_message.clone());
     }
 
+    /// ローカルの画像ファイルを読み込み、次のユーザーメッセージに添付します。
+    /// 添付したファイル名を返します。
+    pub fn attach_file(&mut self, path: &str) -> Result<String> {
+        let attachment = Attachment::from_path(&expand_home(path))?;
+        let name = attachment.name.clone();
+        self.pending_attachments.push(attachment);
+        Ok(name)
+    }
+
+    /// 次のユーザーメッセージに添付される画像を取得します。
+    pub fn pending_attachments(&self) -> &[Attachment] {
+        &self.pending_attachments
+    }
+
     /// ツール実行を伴うリアルタイムチャットセッションを開始および管理します。
     /// このメソッドは、UIイベントのストリームを返します。UI層はこのストリームを消費して表示を更新します。
     pub async fn start_realtime_chat(&mut self) -> Result<BoxStream<'static, Result<AgentEvent>>> {
@@ -51,10 +183,30 @@ impl ChatSession {
 
         let stream =
             AIAgent::chat_with_tools_realtime(agent_arc_clone, current_turn_messages).await?;
-        let stream = stream.map_err(anyhow::Error::from);
+        // 使用量イベントはUIに渡す前にセッションの集計に加える (子エージェントの分も含める)
+        let usage = self.usage.clone();
+        let stream = stream
+            .map_err(anyhow::Error::from)
+            .inspect_ok(move |event| {
+                let event = match event {
+                    AgentEvent::SubAgent(event) => event.as_ref(),
+                    event => event,
+                };
+                if let AgentEvent::Usage(turn_usage) = event
+                    && let Ok(mut usage) = usage.lock()
+                {
+                    usage.add(turn_usage);
+                }
+            });
         Ok(Box::pin(stream))
     }
 
+    /// 上限に達して止めたターンなど、ツールの結果を返したところで止まっているターンを続行できるかを返します。
+    /// 続行する場合は、ユーザーメッセージを追加せずに `start_realtime_chat` を呼び出します。
+    pub async fn can_continue(&self) -> bool {
+        self.agent.lock().await.can_continue()
+    }
+
     /// 最後のユーザーメッセージとその後のAIの応答を履歴から削除します。
     pub async fn revert_last_turn(&mut self) {
         let mut agent_locked = self.agent.lock().await;
@@ -70,6 +222,46 @@ impl ChatSession {
         Ok(())
     }
 
+    /// 生成パラメータを1項目変更します。`key` と `value` は `/set` コマンドの引数です。
+    pub async fn set_generation_option(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut agent_locked = self.agent.lock().await;
+        let mut options = agent_locked.get_generation_options();
+        options.set(key, value).map_err(anyhow::Error::msg)?;
+        agent_locked.set_generation_options(options);
+        Ok(())
+    }
+
+    /// 現在の生成パラメータを取得します。
+    pub async fn get_generation_options(&self) -> GenerationOptions {
+        let agent_locked = self.agent.lock().await;
+        agent_locked.get_generation_options()
+    }
+
+    /// 応答の形式を指定します。`None` で通常のテキストに戻します。
+    pub async fn set_response_format(&mut self, format: Option<ResponseFormat>) {
+        let mut agent_locked = self.agent.lock().await;
+        agent_locked.set_response_format(format);
+    }
+
+    /// 現在指定されている応答の形式を取得します。
+    pub async fn get_response_format(&self) -> Option<ResponseFormat> {
+        let agent_locked = self.agent.lock().await;
+        agent_locked.get_response_format()
+    }
+
+    /// `/json` コマンドの引数から応答の形式を切り替え、切り替え後の形式を返します。
+    /// 引数なしは任意のJSONとの切り替え、`off` は解除、それ以外は JSON Schema ファイルのパスです。
+    pub async fn toggle_response_format(&mut self, arg: &str) -> Result<Option<ResponseFormat>> {
+        let format = match arg {
+            "" if self.get_response_format().await.is_some() => None,
+            "" => Some(ResponseFormat::Json),
+            "off" => None,
+            path => Some(ResponseFormat::from_schema_file(&expand_home(path))?),
+        };
+        self.set_response_format(format.clone()).await;
+        Ok(format)
+    }
+
     /// 利用可能なモデルのリストを取得します。
     pub async fn list_models(&self) -> Result<serde_json::Value> {
         let agent_locked = self.agent.lock().await;
@@ -79,6 +271,46 @@ impl ChatSession {
             .map_err(anyhow::Error::from)
     }
 
+    /// モデルをダウンロードします。進捗は `AgentEvent::PullProgress` のストリームとして返します。
+    pub async fn pull_model(&self, name: &str) -> Result<BoxStream<'static, Result<AgentEvent>>> {
+        let agent_locked = self.agent.lock().await;
+        let stream = agent_locked.pull_model(name).await?;
+        Ok(Box::pin(stream.map_err(anyhow::Error::from)))
+    }
+
+    /// ローカルのモデルを削除します。
+    pub async fn delete_model(&self, name: &str) -> Result<()> {
+        let agent_locked = self.agent.lock().await;
+        agent_locked
+            .delete_model(name)
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// ローカルのモデルを別名で複製します。
+    pub async fn copy_model(&self, source: &str, destination: &str) -> Result<()> {
+        let agent_locked = self.agent.lock().await;
+        agent_locked
+            .copy_model(source, destination)
+            .await
+            .map_err(anyhow::Error::from)
+    }
+
+    /// モデルの詳細を表示用の文字列で取得します。
+    pub async fn model_info(&self, name: &str) -> Result<String> {
+        let agent_locked = self.agent.lock().await;
+        let info = agent_locked.show_model(name).await?;
+        Ok(format_model_info(name, &info))
+    }
+
+    /// セッション開始からのトークン使用量の集計を取得します。
+    pub fn get_usage(&self) -> SessionUsage {
+        self.usage
+            .lock()
+            .map(|usage| usage.clone())
+            .unwrap_or_default()
+    }
+
     /// 現在のセッションメッセージのクローンを取得します。
     pub async fn get_messages(&self) -> Vec<ChatMessage> {
         let agent_locked = self.agent.lock().await;
@@ -1,9 +1,13 @@
+use crate::modules::agent::AgentEvent;
 use crate::modules::agent::api::{AIProvider, ChatMessage, ChatRole};
-use crate::modules::chat::ChatSession;
+use crate::modules::agent::approval::{ApprovalDecision, ApprovalRequest};
+use crate::modules::chat::{ChatSession, describe_error};
+use crate::modules::config::AppConfig;
 use anyhow::Result;
 use colored::*;
 use futures_util::stream::StreamExt;
 use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
 use rustyline::Editor;
 use std::io::{self, Write};
 use syntect::easy::HighlightLines;
@@ -11,8 +15,15 @@ use syntect::highlighting::{Theme, ThemeSet};
 use syntect::parsing::{SyntaxSet};
 use syntect::util::{LinesWithEndings, as_24_bit_terminal_escaped};
 
-pub async fn run_cli(provider: AIProvider, base_url: String, default_model: String) -> Result<()> {
-    let mut chat_session = ChatSession::new(provider, base_url, default_model.clone());
+pub async fn run_cli(
+    provider: AIProvider,
+    base_url: String,
+    default_model: String,
+    config: AppConfig,
+) -> Result<()> {
+    // 思考過程を表示するかどうか (`/reasoning` で切り替え)
+    let mut hide_reasoning = config.hide_reasoning;
+    let mut chat_session = ChatSession::new(provider, base_url, default_model.clone(), config);
 
     let syntax_set = SyntaxSet::load_defaults_newlines();
     let theme = ThemeSet::load_defaults().themes["base16-ocean.dark"].clone();
@@ -59,20 +70,46 @@ pub async fn run_cli(provider: AIProvider, base_url: String, default_model: Stri
             continue;
         }
 
-        if input.starts_with('/') {
-            handle_command(&mut chat_session, &input).await?;
+        if input.starts_with('/') && input != "/continue" {
+            handle_command(&mut chat_session, &input, &mut hide_reasoning).await?;
         } else {
-            chat_session.add_user_message(input.clone()).await;
+            if input == "/continue" {
+                // 止めたターンをユーザーメッセージを追加せずに続ける
+                if !chat_session.can_continue().await {
+                    println!("{}", "Nothing to continue.".yellow());
+                    continue;
+                }
+            } else {
+                chat_session.add_user_message(input.clone()).await;
+            }
             let mut stream = chat_session.start_realtime_chat().await?;
 
             let mut full_ai_response = String::new();
             let mut full_tool_output = String::new();
+            let mut in_reasoning = false;
 
             while let Some(event_result) = stream.next().await {
                 match event_result {
                     Ok(event) => {
                         match event {
+                            crate::modules::agent::AgentEvent::Reasoning(reasoning) => {
+                                if !in_reasoning {
+                                    in_reasoning = true;
+                                    if hide_reasoning {
+                                        print!("{}", "[Thinking...]".dimmed());
+                                    }
+                                }
+                                if !hide_reasoning {
+                                    print!("{}", reasoning.dimmed());
+                                }
+                                io::stdout().flush()?;
+                            }
                             crate::modules::agent::AgentEvent::AiResponseChunk(chunk) => {
+                                if in_reasoning {
+                                    // 思考過程と回答を改行で区切る
+                                    in_reasoning = false;
+                                    println!();
+                                }
                                 full_ai_response.push_str(&chunk);
                                 print!("{}", chunk);
                                 io::stdout().flush()?;
@@ -82,6 +119,13 @@ pub async fn run_cli(provider: AIProvider, base_url: String, default_model: Stri
                                 println!("{}", serde_yaml::to_string(&tool_call.parameters).unwrap_or_default().yellow());
                                 full_tool_output.push_str(&format!("\n--- Tool Call: {} ---\n{}", tool_call.tool_name, serde_yaml::to_string(&tool_call.parameters).unwrap_or_default()));
                             }
+                            AgentEvent::ApprovalRequired(request) => {
+                                let decision = ask_tool_approval(&mut rl, &request);
+                                request.respond(decision);
+                            }
+                            crate::modules::agent::AgentEvent::SubAgent(event) => {
+                                print_sub_agent_event(&mut rl, *event)?;
+                            }
                             crate::modules::agent::AgentEvent::ToolExecuting(name) => {
                                 println!("Executing: {}...", name.green());
                             }
@@ -98,11 +142,32 @@ pub async fn run_cli(provider: AIProvider, base_url: String, default_model: Stri
                             crate::modules::agent::AgentEvent::Thinking(msg) => {
                                 println!("Thinking: {}", msg.blue());
                             }
+                            crate::modules::agent::AgentEvent::BackendSelected(label, true) => {
+                                println!("{}", format!("[Fallback] Primary backend unavailable. Answered by {}", label).yellow());
+                            }
+                            crate::modules::agent::AgentEvent::ResponseFormatMismatch(reason) => {
+                                println!("\n{}", format!("[JSON] {} Asking the model again...", reason).yellow());
+                            }
+                            crate::modules::agent::AgentEvent::ContextCompacted(report) => {
+                                println!("{}", format!("[Context] {}", report).yellow());
+                            }
+                            crate::modules::agent::AgentEvent::Usage(usage) => {
+                                println!("\n{}", format!("[Usage] {}", usage).dimmed());
+                            }
+                            crate::modules::agent::AgentEvent::YamlParseError(error, _) => {
+                                eprintln!("\n{}", format!("[Tool call] {}", error).red());
+                            }
+                            crate::modules::agent::AgentEvent::ToolBlockParseWarning(warning) => {
+                                println!("{}", format!("[Tool call] {}", warning).yellow());
+                            }
+                            crate::modules::agent::AgentEvent::LimitReached(limit) => {
+                                println!("\n{}", format!("[Limit] {} Type /continue to keep going, or send a new message.", limit).yellow());
+                            }
                             _ => {}
                         }
                     }
                     Err(e) => {
-                        eprintln!("Error during stream: {}", e.to_string().red());
+                        eprintln!("Error during stream: {}", describe_error(&e).red());
                         break;
                     }
                 }
@@ -114,7 +179,11 @@ pub async fn run_cli(provider: AIProvider, base_url: String, default_model: Stri
     Ok(())
 }
 
-async fn handle_command(chat_session: &mut ChatSession, command: &str) -> Result<()> {
+async fn handle_command(
+    chat_session: &mut ChatSession,
+    command: &str,
+    hide_reasoning: &mut bool,
+) -> Result<()> {
     let parts: Vec<&str> = command.split_whitespace().collect();
     let command_name = parts.first().unwrap_or(&"");
 
@@ -123,14 +192,34 @@ async fn handle_command(chat_session: &mut ChatSession, command: &str) -> Result
             println!("Exiting.");
             std::process::exit(0);
         }
-        "/model" => {
-            if let Some(model_name) = parts.get(1) {
+        "/model" => match parts[1..] {
+            ["pull", name] => pull_model(chat_session, name).await?,
+            ["rm", name] => match chat_session.delete_model(name).await {
+                Ok(()) => println!("Deleted model: {}", name.green()),
+                Err(e) => eprintln!("Failed to delete {}: {}", name, describe_error(&e).red()),
+            },
+            ["cp", source, destination] => {
+                match chat_session.copy_model(source, destination).await {
+                    Ok(()) => println!("Copied {} to {}", source, destination.green()),
+                    Err(e) => eprintln!("Failed to copy {}: {}", source, describe_error(&e).red()),
+                }
+            }
+            ["info", name] => match chat_session.model_info(name).await {
+                Ok(info) => println!("{}", info),
+                Err(e) => eprintln!("Failed to show {}: {}", name, describe_error(&e).red()),
+            },
+            [model_name] if !["pull", "rm", "cp", "info"].contains(&model_name) => {
                 chat_session.set_model(model_name.to_string()).await?;
                 println!("Model set to: {}", model_name.green());
-            } else {
-                println!("{}", "Usage: /model <model_name>".yellow());
             }
-        }
+            _ => {
+                println!(
+                    "{}",
+                    "Usage: /model <model_name> | pull <name> | rm <name> | cp <source> <destination> | info <name>"
+                        .yellow()
+                );
+            }
+        },
         "/list" if parts.get(1) == Some(&"models") => {
             match chat_session.list_models().await {
                 Ok(models) => {
@@ -146,9 +235,60 @@ async fn handle_command(chat_session: &mut ChatSession, command: &str) -> Result
                     }
                 }
                 Err(e) => {
-                    eprintln!("Error listing models: {}", e.to_string().red());
+                    eprintln!("Error listing models: {}", describe_error(&e).red());
+                }
+            }
+        }
+        "/set" => match (parts.get(1), parts.get(2)) {
+            (None, _) => {
+                println!("{}", "Generation options:".cyan().bold());
+                println!("{}", chat_session.get_generation_options().await);
+            }
+            (Some(key), Some(_)) => {
+                // 値にはスペースを含められるようにする (stop シーケンスなど)
+                let value = parts[2..].join(" ");
+                match chat_session.set_generation_option(key, &value).await {
+                    Ok(()) => println!("{} set to: {}", key, value.green()),
+                    Err(e) => eprintln!("{}", e.to_string().red()),
                 }
             }
+            (Some(_), None) => {
+                println!("{}", "Usage: /set [<option> <value>]".yellow());
+            }
+        },
+        "/attach" => {
+            if parts.len() > 1 {
+                // パスにはスペースを含められるようにする
+                let path = command["/attach".len()..].trim();
+                match chat_session.attach_file(path) {
+                    Ok(name) => println!(
+                        "Attached: {} ({} image(s) will be sent with your next message)",
+                        name.green(),
+                        chat_session.pending_attachments().len()
+                    ),
+                    Err(e) => eprintln!("Failed to attach {}: {}", path, e.to_string().red()),
+                }
+            } else {
+                println!("{}", "Usage: /attach <path>".yellow());
+            }
+        }
+        "/json" => {
+            // スキーマファイルのパスにはスペースを含められるようにする
+            let arg = command["/json".len()..].trim();
+            match chat_session.toggle_response_format(arg).await {
+                Ok(Some(format)) => println!("JSON mode: {}", format.to_string().green()),
+                Ok(None) => println!("{}", "JSON mode: off".green()),
+                Err(e) => eprintln!("Failed to load schema {}: {}", arg, describe_error(&e).red()),
+            }
+        }
+        "/reasoning" => {
+            *hide_reasoning = !*hide_reasoning;
+            let state = if *hide_reasoning { "hidden" } else { "shown" };
+            println!("Reasoning is now {}.", state.green());
+        }
+        "/usage" => {
+            println!("{}", "Session usage:".cyan().bold());
+            println!("{}", chat_session.get_usage());
         }
         "/revert" => {
             chat_session.revert_last_turn().await;
@@ -171,7 +311,18 @@ async fn handle_command(chat_session: &mut ChatSession, command: &str) -> Result
             println!("- /help: Show this help message");
             println!("- /shell <command>: Execute a shell command via the AI");
             println!("- /model <model_name>: Switch AI model");
+            println!("- /model pull <name>: Download a model (Ollama)");
+            println!("- /model rm <name>: Delete a local model (Ollama)");
+            println!("- /model cp <source> <destination>: Copy a local model (Ollama)");
+            println!("- /model info <name>: Show model details (Ollama)");
             println!("- /list models: List available models");
+            println!("- /set: Show generation options");
+            println!("- /set <option> <value>: Change a generation option ('none' to unset)");
+            println!("- /attach <path>: Attach an image to your next message");
+            println!("- /json [<schema-file>|off]: Toggle JSON replies, optionally validated against a JSON Schema");
+            println!("- /reasoning: Show or hide the model's reasoning");
+            println!("- /usage: Show token usage for this session");
+            println!("- /continue: Resume a turn that stopped at a tool loop limit");
             println!("- /revert: Undo your last message and the AI's response");
             println!("- /clear: Clear the chat history");
             println!("- /log: Show the path to the current log file");
@@ -184,6 +335,82 @@ async fn handle_command(chat_session: &mut ChatSession, command: &str) -> Result
     Ok(())
 }
 
+/// ツールを実行してよいかをユーザーに確認する。空の入力や入力の中断は拒否として扱う
+fn ask_tool_approval(rl: &mut Editor<(), DefaultHistory>, request: &ApprovalRequest) -> ApprovalDecision {
+    println!(
+        "{}",
+        format!("[Approval] '{}' ({}) wants to run with the arguments above.", request.call.tool_name, request.risk).yellow()
+    );
+    loop {
+        match rl.readline("Allow? [y]es / [n]o / [a]lways for this session: ") {
+            Ok(answer) => match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return ApprovalDecision::Approve,
+                "a" | "always" => return ApprovalDecision::AlwaysApprove,
+                "" | "n" | "no" => return ApprovalDecision::Deny,
+                _ => println!("Please answer y, n or a."),
+            },
+            Err(_) => return ApprovalDecision::Deny,
+        }
+    }
+}
+
+/// `delegate` の子エージェントの進捗を、親の応答と区別できるように暗い色で表示する
+fn print_sub_agent_event(rl: &mut Editor<(), DefaultHistory>, event: AgentEvent) -> Result<()> {
+    match event {
+        AgentEvent::AiResponseChunk(chunk) => {
+            print!("{}", chunk.dimmed());
+            io::stdout().flush()?;
+        }
+        AgentEvent::ToolCallDetected(tool_call) => {
+            println!("\n{}", format!("  [Sub-agent] Tool Call: {}", tool_call.tool_name).dimmed());
+            println!("{}", serde_yaml::to_string(&tool_call.parameters).unwrap_or_default().dimmed());
+        }
+        AgentEvent::ApprovalRequired(request) => {
+            let decision = ask_tool_approval(rl, &request);
+            request.respond(decision);
+        }
+        AgentEvent::ToolResult(tool_name, _) => {
+            println!("{}", format!("  [Sub-agent] {} finished.", tool_name).dimmed());
+        }
+        AgentEvent::ToolError(tool_name, error_message) => {
+            eprintln!("{}", format!("  [Sub-agent] {} failed: {}", tool_name, error_message).red());
+        }
+        AgentEvent::LimitReached(limit) => {
+            println!("\n{}", format!("  [Sub-agent] {}", limit).yellow());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// モデルをダウンロードし、進捗を同じ行に上書きして表示する
+async fn pull_model(chat_session: &ChatSession, name: &str) -> Result<()> {
+    let mut stream = match chat_session.pull_model(name).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to pull {}: {}", name, describe_error(&e).red());
+            return Ok(());
+        }
+    };
+
+    while let Some(event_result) = stream.next().await {
+        match event_result {
+            Ok(crate::modules::agent::AgentEvent::PullProgress(progress)) => {
+                // \x1b[2K で前の進捗表示を消してから書き直す
+                print!("\r\x1b[2K{}", progress);
+                io::stdout().flush()?;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("\nFailed to pull {}: {}", name, describe_error(&e).red());
+                return Ok(());
+            }
+        }
+    }
+    println!("\nPulled model: {}", name.green());
+    Ok(())
+}
+
 fn print_message(message: &ChatMessage, syntax_set: &SyntaxSet, theme: &Theme) {
     let mut in_code_block = false;
     let mut code_block_lang = "txt";
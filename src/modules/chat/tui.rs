@@ -1,6 +1,8 @@
 use crate::modules::agent::AgentEvent;
+use crate::modules::agent::approval::{ApprovalDecision, ApprovalRequest};
 use crate::modules::agent::api::{AIProvider, ChatMessage, ChatRole};
-use crate::modules::chat::ChatSession;
+use crate::modules::chat::{ChatSession, describe_error};
+use crate::modules::config::AppConfig;
 use anyhow::Result;
 use crossterm::{
     event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
@@ -11,10 +13,10 @@ use futures_util::stream::StreamExt;
 use ratatui::{
     Terminal,
     backend::CrosstermBackend,
-    layout::{Alignment, Constraint, Direction, Layout},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, List, ListItem, ListState, Padding, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Padding, Paragraph, Wrap},
 };
 use std::{
     io::{self, Stdout},
@@ -34,6 +36,8 @@ enum TuiEvent {
     Error(String),
     ModelsListed(serde_json::Value),
     ModelSet,
+    /// `/model pull|rm|cp|info` の結果としてチャットに表示するメッセージ
+    ModelCommandDone(String),
     Reverted,
     StreamComplete,
 }
@@ -49,6 +53,10 @@ pub struct TuiApp {
     event_sender: mpsc::UnboundedSender<TuiEvent>,
     event_receiver: mpsc::UnboundedReceiver<TuiEvent>,
     ai_response_buffer: String,
+    /// 応答中の推論モデルの思考過程 (履歴には含まれないため、応答が終わると消える)
+    reasoning_buffer: String,
+    /// 思考過程を1行に折りたたむかどうか (`/reasoning` で切り替え)
+    hide_reasoning: bool,
     tool_output_buffer: String,
     status_text_color: Color,
     message_list_state: ListState,
@@ -58,11 +66,22 @@ pub struct TuiApp {
     syntax_set: SyntaxSet,
     theme: Theme,
     default_system_prompt: String,
+    usage_text: String,
+    /// ツールループの上限で止まったターンの案内 (ストリームの終了時に表示する)
+    limit_notice: Option<String>,
+    /// 答えを待っているツール実行の確認 (表示中はキー入力をダイアログで受け付ける)
+    pending_approval: Option<ApprovalRequest>,
 }
 
 impl TuiApp {
-    pub fn new(provider: AIProvider, base_url: String, default_model: String) -> Self {
-        let chat_session = ChatSession::new(provider, base_url, default_model.clone());
+    pub fn new(
+        provider: AIProvider,
+        base_url: String,
+        default_model: String,
+        config: AppConfig,
+    ) -> Self {
+        let hide_reasoning = config.hide_reasoning;
+        let chat_session = ChatSession::new(provider, base_url, default_model.clone(), config);
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
         Self {
             chat_session,
@@ -75,6 +94,8 @@ impl TuiApp {
             event_sender,
             event_receiver,
             ai_response_buffer: String::new(),
+            reasoning_buffer: String::new(),
+            hide_reasoning,
             tool_output_buffer: String::new(),
             status_text_color: Color::White,
             message_list_state: ListState::default(),
@@ -84,6 +105,9 @@ impl TuiApp {
             syntax_set: SyntaxSet::load_defaults_newlines(),
             theme: ThemeSet::load_defaults().themes["base16-ocean.dark"].clone(),
             default_system_prompt: include_str!("../default-prompt.md").to_string(),
+            usage_text: String::new(),
+            limit_notice: None,
+            pending_approval: None,
         }
     }
 
@@ -102,6 +126,7 @@ impl TuiApp {
         let event_sender_clone = self.event_sender.clone();
         let reader_task = tokio::spawn(async move {
             loop {
+                #[allow(clippy::collapsible_if)] // 元の入れ子の if を残す
                 if event::poll(Duration::from_millis(100)).unwrap() {
                     if let Event::Key(key) = event::read().unwrap() {
                         if key.kind == KeyEventKind::Press && event_sender_clone.send(TuiEvent::Input(key)).is_err() {
@@ -130,6 +155,7 @@ impl TuiApp {
                     TuiEvent::Error(e) => self.handle_error(e),
                     TuiEvent::ModelsListed(models) => self.handle_models_listed(models),
                     TuiEvent::ModelSet => self.handle_model_set(),
+                    TuiEvent::ModelCommandDone(message) => self.handle_model_command_done(message),
                     TuiEvent::Reverted => self.handle_reverted(),
                     TuiEvent::StreamComplete => self.handle_stream_complete().await,
                 }
@@ -183,9 +209,27 @@ impl TuiApp {
                 },
                 message_area_width,
             ));
+            // 添付画像はファイル名だけを表示する
+            for attachment in &message.attachments {
+                list_items.push(ListItem::new(Line::from(Span::styled(
+                    format!("  [image: {}]", attachment.name),
+                    Style::default().fg(Color::DarkGray),
+                ))));
+            }
+            // ツール呼び出しは名前だけを表示する (結果は続くToolのメッセージに表示される)
+            for call in &message.tool_calls {
+                list_items.push(ListItem::new(Line::from(Span::styled(
+                    format!("  [tool call: {}]", call.name),
+                    Style::default().fg(Color::DarkGray),
+                ))));
+            }
         }
 
         // Display live AI response and tool output
+        if !self.reasoning_buffer.is_empty() {
+            list_items.extend(self.create_reasoning_items(message_area_width));
+        }
+
         if !self.ai_response_buffer.is_empty() {
             list_items.extend(self.create_list_item(
                 &self.ai_response_buffer,
@@ -205,6 +249,7 @@ impl TuiApp {
         }
 
         let list_items_count = list_items.len();
+        #[allow(clippy::collapsible_if)]
         if list_items_count > 0 {
             if !self.is_user_scrolling {
                 self.message_list_state.select(Some(list_items_count - 1));
@@ -253,17 +298,70 @@ impl TuiApp {
 
         let status_bar_layout = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(70), Constraint::Percentage(30)])
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
             .split(main_layout[2]);
 
         let status_text = Paragraph::new(self.status_message.as_str())
             .style(Style::default().fg(self.status_text_color));
         frame.render_widget(status_text, status_bar_layout[0]);
 
-        let help_text = Paragraph::new("Scroll: Up/Down | Quit: Esc | Help: /help")
+        let help_text = if self.usage_text.is_empty() {
+            "Scroll: Up/Down | Quit: Esc | Help: /help".to_string()
+        } else {
+            format!("{} | Help: /help", self.usage_text)
+        };
+        let help_text = Paragraph::new(help_text)
             .style(Style::default().fg(Color::DarkGray))
             .alignment(Alignment::Right);
         frame.render_widget(help_text, status_bar_layout[1]);
+
+        if let Some(request) = &self.pending_approval {
+            render_approval_dialog(frame, request);
+        }
+    }
+
+    /// 思考過程を暗い色で表示する。折りたたみ時は最後の行だけを表示する
+    fn create_reasoning_items(&self, width: u16) -> Vec<ListItem<'static>> {
+        let style = Style::default()
+            .fg(Color::DarkGray)
+            .add_modifier(Modifier::ITALIC);
+        let prefix = "Thinking: ";
+        let prefix_width = Span::from(prefix).width();
+
+        if self.hide_reasoning {
+            let last_line = self
+                .reasoning_buffer
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or("");
+            let max_chars = (width as usize).saturating_sub(40).max(10);
+            let snippet: String = last_line.trim().chars().take(max_chars).collect();
+            let summary = format!(
+                "{}{} ({} chars, /reasoning to expand)",
+                prefix,
+                snippet,
+                self.reasoning_buffer.chars().count()
+            );
+            return vec![ListItem::new(Line::from(Span::styled(summary, style)))];
+        }
+
+        let mut items = Vec::new();
+        for line in self.reasoning_buffer.trim().lines() {
+            let line_width = (width as usize).saturating_sub(prefix_width).max(1);
+            for wrapped_line in textwrap::wrap(line, line_width) {
+                let lead = if items.is_empty() {
+                    prefix.to_string()
+                } else {
+                    " ".repeat(prefix_width)
+                };
+                items.push(ListItem::new(Line::from(Span::styled(
+                    format!("{}{}", lead, wrapped_line),
+                    style,
+                ))));
+            }
+        }
+        items
     }
 
     fn create_list_item<'a>(
@@ -334,6 +432,28 @@ impl TuiApp {
     }
 
     async fn handle_input_event(&mut self, key_event: KeyEvent, terminal_width: u16) -> Result<()> {
+        // ツール実行の確認中は、ダイアログへの答えだけを受け付ける
+        if self.pending_approval.is_some() {
+            let decision = match key_event.code {
+                KeyCode::Char('y') => ApprovalDecision::Approve,
+                KeyCode::Char('a') => ApprovalDecision::AlwaysApprove,
+                KeyCode::Char('n') | KeyCode::Esc => ApprovalDecision::Deny,
+                KeyCode::Char('c') if key_event.modifiers == KeyModifiers::CONTROL => {
+                    self.cancel_ai_response();
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            };
+            if let Some(request) = self.pending_approval.take() {
+                let tool_name = request.call.tool_name.clone();
+                request.respond(decision);
+                if decision == ApprovalDecision::Deny {
+                    self.set_status_message(format!("Denied: {}", tool_name), Color::Yellow);
+                }
+            }
+            return Ok(());
+        }
+
         // AI応答中でもCtrl+CとEscは処理する
         if self.is_ai_replying {
             match key_event.code {
@@ -349,6 +469,7 @@ impl TuiApp {
 
         // AI応答中かどうかに関わらず処理するキー
         match key_event.code {
+            #[allow(clippy::collapsible_match)]
             KeyCode::Enter => {
                 if !self.is_ai_replying {
                     // AI応答中はEnterを無視
@@ -370,6 +491,7 @@ impl TuiApp {
             KeyCode::Right if !self.is_ai_replying => {
                 self.input_scroll = self.input_scroll.saturating_add(1);
             }
+            #[allow(clippy::collapsible_if)]
             KeyCode::Up => {
                 self.is_user_scrolling = true;
                 if let Some(selected) = self.message_list_state.selected() {
@@ -454,8 +576,8 @@ impl TuiApp {
         } else {
             self.is_ai_replying = true;
             self.messages.push(ChatMessage {
-                role: ChatRole::User,
-                content: input_copy.clone(),
+                attachments: self.chat_session.pending_attachments().to_vec(),
+                ..ChatMessage::user(input_copy.clone())
             });
             self.set_status_message("Sending message to AI...".to_string(), Color::Yellow);
             self.chat_session.add_user_message(input_copy).await;
@@ -472,16 +594,16 @@ impl TuiApp {
             "/exit" | "/quit" => self.should_quit = true,
             "/shell" => {
                 self.is_ai_replying = true;
-                self.messages.push(ChatMessage {
-                    role: ChatRole::User,
-                    content: command_copy.clone(),
-                });
+                self.messages.push(ChatMessage::user(command_copy.clone()));
                 self.set_status_message("Executing shell command...".to_string(), Color::Yellow);
                 self.chat_session.add_user_message(command_copy).await;
                 self.start_chat_stream();
             }
-            "/model" => {
-                if let Some(model_name) = parts.get(1) {
+            "/model" => match parts[1..] {
+                ["pull", _] | ["rm", _] | ["cp", _, _] | ["info", _] => {
+                    self.spawn_model_command(parts[1..].iter().map(|s| s.to_string()).collect());
+                }
+                [model_name] if !["pull", "rm", "cp", "info"].contains(&model_name) => {
                     let sender = self.event_sender.clone();
                     let model_name = model_name.to_string();
                     let mut chat_session = self.chat_session.clone();
@@ -490,10 +612,15 @@ impl TuiApp {
                             let _ = sender.send(TuiEvent::ModelSet);
                         }
                     });
-                } else {
-                    self.set_status_message("Usage: /model <model_name>".to_string(), Color::Red);
                 }
-            }
+                _ => {
+                    self.set_status_message(
+                        "Usage: /model <model_name> | pull <name> | rm <name> | cp <source> <destination> | info <name>"
+                            .to_string(),
+                        Color::Red,
+                    );
+                }
+            },
             "/list" if parts.get(1) == Some(&"models") => {
                 let sender = self.event_sender.clone();
                 let chat_session = self.chat_session.clone();
@@ -503,11 +630,89 @@ impl TuiApp {
                             let _ = sender.send(TuiEvent::ModelsListed(models));
                         }
                         Err(e) => {
-                            let _ = sender.send(TuiEvent::Error(e.to_string()));
+                            let _ = sender.send(TuiEvent::Error(describe_error(&e)));
                         }
                     }
                 });
             }
+            "/set" => {
+                if parts.len() == 1 {
+                    let options = self.chat_session.get_generation_options().await;
+                    self.messages.push(ChatMessage::system(format!("Generation options:\n{}", options)));
+                } else if parts.len() >= 3 {
+                    // 値にはスペースを含められるようにする (stop シーケンスなど)
+                    let key = parts[1];
+                    let value = parts[2..].join(" ");
+                    match self.chat_session.set_generation_option(key, &value).await {
+                        Ok(()) => self.set_status_message(
+                            format!("{} set to: {}", key, value),
+                            Color::Green,
+                        ),
+                        Err(e) => self.set_status_message(e.to_string(), Color::Red),
+                    }
+                } else {
+                    self.set_status_message(
+                        "Usage: /set [<option> <value>]".to_string(),
+                        Color::Red,
+                    );
+                }
+            }
+            "/attach" => {
+                if parts.len() > 1 {
+                    // パスにはスペースを含められるようにする
+                    let path = command["/attach".len()..].trim();
+                    match self.chat_session.attach_file(path) {
+                        Ok(name) => {
+                            let count = self.chat_session.pending_attachments().len();
+                            self.set_status_message(
+                                format!(
+                                    "Attached: {} ({} image(s) will be sent with your next message)",
+                                    name, count
+                                ),
+                                Color::Green,
+                            );
+                        }
+                        Err(e) => self.set_status_message(
+                            format!("Failed to attach {}: {}", path, e),
+                            Color::Red,
+                        ),
+                    }
+                } else {
+                    self.set_status_message("Usage: /attach <path>".to_string(), Color::Red);
+                }
+            }
+            "/json" => {
+                // スキーマファイルのパスにはスペースを含められるようにする
+                let arg = command["/json".len()..].trim();
+                match self.chat_session.toggle_response_format(arg).await {
+                    Ok(Some(format)) => {
+                        self.set_status_message(format!("JSON mode: {}", format), Color::Green)
+                    }
+                    Ok(None) => self.set_status_message("JSON mode: off".to_string(), Color::Green),
+                    Err(e) => self.set_status_message(
+                        format!("Failed to load schema {}: {}", arg, describe_error(&e)),
+                        Color::Red,
+                    ),
+                }
+            }
+            "/reasoning" => {
+                self.hide_reasoning = !self.hide_reasoning;
+                let state = if self.hide_reasoning { "collapsed" } else { "expanded" };
+                self.set_status_message(format!("Reasoning is now {}.", state), Color::Green);
+            }
+            "/usage" => {
+                self.messages.push(ChatMessage::system(format!("Session usage:\n{}", self.chat_session.get_usage())));
+            }
+            "/continue" => {
+                // 止めたターンをユーザーメッセージを追加せずに続ける
+                if self.chat_session.can_continue().await {
+                    self.is_ai_replying = true;
+                    self.set_status_message("Continuing...".to_string(), Color::Yellow);
+                    self.start_chat_stream();
+                } else {
+                    self.set_status_message("Nothing to continue.".to_string(), Color::Yellow);
+                }
+            }
             "/revert" => {
                 self.chat_session.revert_last_turn().await;
                 self.messages = self.chat_session.get_messages().await;
@@ -524,10 +729,7 @@ impl TuiApp {
                     Some(path) => format!("Log file is at: {}", path),
                     None => "Logging is not configured.".to_string(),
                 };
-                self.messages.push(ChatMessage {
-                    role: ChatRole::System,
-                    content: message,
-                });
+                self.messages.push(ChatMessage::system(message));
             }
             "/help" => {
                 let help_text = "Available commands:
@@ -538,8 +740,30 @@ impl TuiApp {
 
                 - /model <model_name>: Switch AI model
 
+                - /model pull <name>: Download a model (Ollama)
+
+                - /model rm <name>: Delete a local model (Ollama)
+
+                - /model cp <source> <destination>: Copy a local model (Ollama)
+
+                - /model info <name>: Show model details (Ollama)
+
                 - /list models: List available models
 
+                - /set: Show generation options
+
+                - /set <option> <value>: Change a generation option ('none' to unset)
+
+                - /attach <path>: Attach an image to your next message
+
+                - /json [<schema-file>|off]: Toggle JSON replies, optionally validated against a JSON Schema
+
+                - /reasoning: Expand or collapse the model's reasoning
+
+                - /usage: Show token usage for this session
+
+                - /continue: Resume a turn that stopped at a tool loop limit
+
                 - /revert: Undo your last message and the AI's response
 
                 - /clear: Clear the chat history
@@ -556,10 +780,7 @@ impl TuiApp {
 
                 - Esc: Quit the application"
                     .to_string();
-                self.messages.push(ChatMessage {
-                    role: ChatRole::System,
-                    content: help_text,
-                });
+                self.messages.push(ChatMessage::system(help_text));
             }
             _ => {
                 self.set_status_message(format!("Unknown command: {}", command_name), Color::Red);
@@ -567,6 +788,53 @@ impl TuiApp {
         }
     }
 
+    /// `/model pull|rm|cp|info` をバックグラウンドで実行する。
+    /// ダウンロードの進捗は `AgentEvent::PullProgress` としてステータスバーに表示する
+    fn spawn_model_command(&mut self, args: Vec<String>) {
+        let sender = self.event_sender.clone();
+        let chat_session = self.chat_session.clone();
+        self.set_status_message(format!("Running /model {}...", args.join(" ")), Color::Yellow);
+
+        tokio::spawn(async move {
+            let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+                ["pull", name] => match chat_session.pull_model(name).await {
+                    Ok(mut stream) => {
+                        let mut result = Ok(format!("Pulled model: {}", name));
+                        while let Some(event_result) = stream.next().await {
+                            match event_result {
+                                Ok(event) => {
+                                    if sender.send(TuiEvent::AgentEvent(event)).is_err() {
+                                        return;
+                                    }
+                                }
+                                Err(e) => {
+                                    result = Err(e);
+                                    break;
+                                }
+                            }
+                        }
+                        result
+                    }
+                    Err(e) => Err(e),
+                },
+                ["rm", name] => chat_session
+                    .delete_model(name)
+                    .await
+                    .map(|()| format!("Deleted model: {}", name)),
+                ["cp", source, destination] => chat_session
+                    .copy_model(source, destination)
+                    .await
+                    .map(|()| format!("Copied {} to {}", source, destination)),
+                ["info", name] => chat_session.model_info(name).await,
+                _ => return,
+            };
+            let _ = match result {
+                Ok(message) => sender.send(TuiEvent::ModelCommandDone(message)),
+                Err(e) => sender.send(TuiEvent::Error(describe_error(&e))),
+            };
+        });
+    }
+
     fn start_chat_stream(&mut self) {
         let sender = self.event_sender.clone();
         let mut chat_session = self.chat_session.clone();
@@ -586,14 +854,14 @@ impl TuiApp {
                                 }
                             }
                             Err(e) => {
-                                let _ = sender.send(TuiEvent::Error(e.to_string()));
+                                let _ = sender.send(TuiEvent::Error(describe_error(&e)));
                                 break;
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    let _ = sender.send(TuiEvent::Error(e.to_string()));
+                    let _ = sender.send(TuiEvent::Error(describe_error(&e)));
                 }
             }
             let _ = sender.send(TuiEvent::StreamComplete);
@@ -617,6 +885,14 @@ impl TuiApp {
                 ));
                 self.set_status_message("Tool call detected...".to_string(), Color::Yellow);
             }
+            AgentEvent::SubAgent(event) => self.handle_sub_agent_event(*event),
+            AgentEvent::ApprovalRequired(request) => {
+                self.set_status_message(
+                    format!("Allow '{}'? [y]es / [n]o / [a]lways", request.call.tool_name),
+                    Color::Yellow,
+                );
+                self.pending_approval = Some(request);
+            }
             AgentEvent::ToolExecuting(name) => {
                 self.set_status_message(format!("Executing: {}...", name), Color::Cyan);
             }
@@ -642,16 +918,114 @@ Error: {}
                 self.set_status_message(format!("Tool {} failed.", tool_name), Color::Red);
             }
             AgentEvent::Thinking(msg) => self.set_status_message(msg, Color::LightBlue),
+            AgentEvent::Reasoning(reasoning) => {
+                self.reasoning_buffer.push_str(&reasoning);
+                self.set_status_message("AI is thinking...".to_string(), Color::LightBlue);
+            }
+            AgentEvent::BackendSelected(label, true) => {
+                self.set_status_message(
+                    format!("Primary backend unavailable. Answered by {}", label),
+                    Color::Yellow,
+                );
+            }
+            AgentEvent::ResponseFormatMismatch(reason) => {
+                // 形式に合わなかった応答は履歴に残っているので、表示中のバッファだけ消す
+                self.ai_response_buffer.clear();
+                self.messages = self.chat_session.get_messages().await;
+                self.set_status_message(
+                    format!("{} Asking the model again...", reason),
+                    Color::Yellow,
+                );
+            }
+            AgentEvent::ContextCompacted(report) => {
+                self.messages = self.chat_session.get_messages().await;
+                self.set_status_message(report, Color::Yellow);
+            }
+            AgentEvent::YamlParseError(error, block) => {
+                self.tool_output_buffer.push_str(&format!(
+                    "
+--- Tool Call Parse Error ---
+{}
+{}
+",
+                    error, block
+                ));
+            }
+            AgentEvent::ToolBlockParseWarning(warning) => {
+                self.set_status_message(warning, Color::Yellow);
+            }
+            AgentEvent::LimitReached(limit) => {
+                // ストリームの終了時に履歴を読み直すため、その後で表示する
+                self.limit_notice = Some(format!(
+                    "{} Type /continue to keep going, or send a new message.",
+                    limit
+                ));
+            }
+            AgentEvent::PullProgress(progress) => {
+                self.set_status_message(progress.to_string(), Color::Cyan);
+            }
+            AgentEvent::Usage(usage) => {
+                // 直近の生成速度とセッション全体のトークン数をステータスバーに表示する
+                let session_usage = self.chat_session.get_usage();
+                let mut usage_text = format!(
+                    "Tokens: {} in / {} out",
+                    session_usage.prompt_tokens, session_usage.completion_tokens
+                );
+                if let Some(tps) = usage.tokens_per_second() {
+                    usage_text.push_str(&format!(" | {:.1} tok/s", tps));
+                }
+                self.usage_text = usage_text;
+            }
+            _ => {}
+        }
+    }
+
+    /// `delegate` の子エージェントの進捗をツールの出力欄に表示する
+    fn handle_sub_agent_event(&mut self, event: AgentEvent) {
+        match event {
+            AgentEvent::AiResponseChunk(chunk) => {
+                self.tool_output_buffer.push_str(&chunk);
+                self.set_status_message("Sub-agent is working...".to_string(), Color::LightBlue);
+            }
+            AgentEvent::ToolCallDetected(tool_call) => {
+                self.tool_output_buffer.push_str(&format!(
+                    "
+--- Sub-agent Tool Call: {} ---
+{}",
+                    tool_call.tool_name,
+                    serde_yaml::to_string(&tool_call.parameters).unwrap_or_default()
+                ));
+            }
+            AgentEvent::ApprovalRequired(request) => {
+                self.set_status_message(
+                    format!("Sub-agent: allow '{}'? [y]es / [n]o / [a]lways", request.call.tool_name),
+                    Color::Yellow,
+                );
+                self.pending_approval = Some(request);
+            }
+            AgentEvent::ToolExecuting(name) => {
+                self.set_status_message(format!("Sub-agent executing: {}...", name), Color::Cyan);
+            }
+            AgentEvent::ToolResult(tool_name, _) => {
+                self.tool_output_buffer
+                    .push_str(&format!("\n--- Sub-agent: {} finished ---\n", tool_name));
+            }
+            AgentEvent::ToolError(tool_name, error_message) => {
+                self.tool_output_buffer.push_str(&format!(
+                    "\n--- Sub-agent: {} failed ---\nError: {}\n",
+                    tool_name, error_message
+                ));
+            }
+            AgentEvent::LimitReached(limit) => {
+                self.set_status_message(format!("Sub-agent: {}", limit), Color::Yellow);
+            }
             _ => {}
         }
     }
 
     fn handle_error(&mut self, e: String) {
         self.set_status_message(format!("Error: {}", e), Color::Red);
-        self.messages.push(ChatMessage {
-            role: ChatRole::System,
-            content: format!("Error: {}", e),
-        });
+        self.messages.push(ChatMessage::system(format!("Error: {}", e)));
         self.is_ai_replying = false;
     }
 
@@ -676,10 +1050,7 @@ Error: {}
 ",
             );
         }
-        self.messages.push(ChatMessage {
-            role: ChatRole::System,
-            content: model_list_message,
-        });
+        self.messages.push(ChatMessage::system(model_list_message));
         self.set_status_message("Models listed.".to_string(), Color::Green);
     }
 
@@ -690,19 +1061,30 @@ Error: {}
         );
     }
 
+    fn handle_model_command_done(&mut self, message: String) {
+        self.messages.push(ChatMessage::system(message));
+        self.set_status_message("Done.".to_string(), Color::Green);
+    }
+
     fn handle_reverted(&mut self) {
         self.set_status_message("Last turn reverted.".to_string(), Color::Green);
     }
 
     async fn handle_stream_complete(&mut self) {
         self.ai_response_buffer.clear();
+        self.reasoning_buffer.clear();
         self.tool_output_buffer.clear();
         self.chat_stream_handle = None;
 
         // Refresh messages from chat session to ensure consistency
         self.messages = self.chat_session.get_messages().await;
 
-        self.set_status_message("Ready.".to_string(), Color::Green);
+        if let Some(notice) = self.limit_notice.take() {
+            self.messages.push(ChatMessage::system(notice.clone()));
+            self.set_status_message(notice, Color::Yellow);
+        } else {
+            self.set_status_message("Ready.".to_string(), Color::Green);
+        }
         self.is_ai_replying = false;
         self.is_user_scrolling = false;
     }
@@ -711,12 +1093,11 @@ Error: {}
         if let Some(handle) = self.chat_stream_handle.take() {
             handle.abort();
             self.is_ai_replying = false;
+            self.pending_approval = None;
             self.ai_response_buffer.clear();
+            self.reasoning_buffer.clear();
             self.tool_output_buffer.clear();
-            self.messages.push(ChatMessage {
-                role: ChatRole::System,
-                content: "AI response cancelled by user.".to_string(),
-            });
+            self.messages.push(ChatMessage::system("AI response cancelled by user."));
             self.set_status_message("AI response cancelled.".to_string(), Color::Yellow);
         }
     }
@@ -728,6 +1109,52 @@ Error: {}
     }
 }
 
+/// ツール実行の確認ダイアログを画面の中央に表示する
+fn render_approval_dialog(frame: &mut ratatui::Frame, request: &ApprovalRequest) {
+    let area = frame.area();
+    let width = area.width.saturating_sub(4).min(72);
+    let height = area.height.saturating_sub(2).min(14);
+    let dialog_area = Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    );
+
+    let arguments = serde_yaml::to_string(&request.call.parameters).unwrap_or_default();
+    let mut lines = vec![
+        Line::from(vec![
+            Span::styled(
+                request.call.tool_name.clone(),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::styled(
+                format!(" ({})", request.risk),
+                Style::default().fg(Color::Yellow),
+            ),
+            Span::raw(" wants to run with:"),
+        ]),
+        Line::from(""),
+    ];
+    lines.extend(arguments.lines().map(|line| {
+        Line::from(Span::styled(line.to_string(), Style::default().fg(Color::Cyan)))
+    }));
+    lines.push(Line::from(""));
+    lines.push(Line::from("[y] Yes   [n] No   [a] Always for this session"));
+
+    let dialog = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_type(ratatui::widgets::BorderType::Rounded)
+                .title("Tool approval")
+                .padding(Padding::horizontal(1)),
+        );
+    frame.render_widget(Clear, dialog_area);
+    frame.render_widget(dialog, dialog_area);
+}
+
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     // 既存のraw modeを無効にしてから再度有効にする
     disable_raw_mode()?;
@@ -0,0 +1,80 @@
+// src/modules/config.rs
+use crate::modules::agent::api::fallback::BackendConfig;
+use crate::modules::agent::api::{GenerationOptions, NetworkConfig};
+use crate::modules::agent::approval::ApprovalConfig;
+use crate::modules::agent::context::ContextConfig;
+use crate::modules::agent::limits::LoopLimits;
+use crate::modules::model_selection::ModelSelectionConfig;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// 設定ファイルの場所を上書きする環境変数
+const CONFIG_PATH_ENV: &str = "AI_INTEGRATION_CONFIG";
+
+/// アプリケーション全体の設定。
+/// `~/.config/ai-integration/config.yaml` から読み込み、書かれていない項目は既定値を使う。
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AppConfig {
+    /// セッション開始時の生成パラメータ (`/set` で実行時に変更できる)
+    pub generation: GenerationOptions,
+    /// 主のバックエンドが使えない場合に順に試す予備のバックエンド
+    pub fallback: Vec<BackendConfig>,
+    /// タイムアウトと再試行の設定
+    pub network: NetworkConfig,
+    /// 埋め込みに使うモデル。Ollamaでは省略するとチャットのモデルを使い、OpenAI互換APIでは指定が必要
+    pub embedding_model: Option<String>,
+    /// 長いセッションでコンテキスト長を超えないようにする設定
+    pub context: ContextConfig,
+    /// 1ターンでツールを実行する回数や時間の上限
+    pub limits: LoopLimits,
+    /// ツールを実行する前にユーザーの許可を求めるかどうか (ツールの種類ごと、またはツール名ごとに指定する)
+    pub tool_approval: ApprovalConfig,
+    /// Ollamaの既定モデルの選び方
+    pub model_selection: ModelSelectionConfig,
+    /// チャットのリクエストと応答をフィクスチャとして保存するディレクトリ (`--record=<dir>` でも指定できる)。
+    /// 保存したものは `--provider=replay:<dir>` で再生できる
+    pub record: Option<PathBuf>,
+    /// 推論モデルの思考過程を、CLIでは表示せず、TUIでは折りたたんで表示する (`/reasoning` で切り替え)
+    pub hide_reasoning: bool,
+}
+
+impl AppConfig {
+    /// 設定ファイルのパスを返す。環境変数 `AI_INTEGRATION_CONFIG` が設定されていればそれを優先する
+    pub fn path() -> Option<PathBuf> {
+        if let Ok(path) = std::env::var(CONFIG_PATH_ENV)
+            && !path.trim().is_empty()
+        {
+            return Some(PathBuf::from(path));
+        }
+        dirs::config_dir().map(|dir| dir.join("ai-integration").join("config.yaml"))
+    }
+
+    /// 設定ファイルを読み込む。ファイルが無い場合や読み込めない場合は既定値を返す
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        if !path.exists() {
+            return Self::default();
+        }
+
+        match std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_yaml::from_str::<Self>(&content).map_err(|e| e.to_string()))
+        {
+            Ok(config) => {
+                println!("設定ファイルを読み込みました: {}", path.display());
+                config
+            }
+            Err(e) => {
+                eprintln!(
+                    "設定ファイル {} の読み込みに失敗しました: {}. 既定の設定を使用します。",
+                    path.display(),
+                    e
+                );
+                Self::default()
+            }
+        }
+    }
+}
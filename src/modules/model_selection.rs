@@ -0,0 +1,432 @@
+// src/modules/model_selection.rs
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::modules::agent::api::AIApiTrait;
+use crate::modules::agent::api::ollama::OllamaApi;
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+/// 設定ファイルの `model_selection` セクション。Ollamaの既定モデルの選び方を決める
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ModelSelectionConfig {
+    /// 優先するモデルのファミリー (先頭ほど優先)。モデル名の `:` より前、またはOllamaの `family` と比較する
+    pub preferred_families: Vec<String>,
+    /// モデルに使ってよい空きメモリの割合 (0.0〜1.0)
+    pub memory_fraction: f64,
+    /// 優先する量子化の種類 (先頭ほど優先)。例: `Q4_K_M`, `Q8_0`
+    pub preferred_quantizations: Vec<String>,
+    /// パラメータ数の上限 (10億単位)。指定しない場合はCPUコア数1つあたり20億として決める
+    pub max_parameters_billions: Option<f64>,
+}
+
+impl Default for ModelSelectionConfig {
+    fn default() -> Self {
+        ModelSelectionConfig {
+            preferred_families: vec!["llama3.1".to_string(), "gemma3".to_string()],
+            memory_fraction: 0.4,
+            preferred_quantizations: Vec::new(),
+            max_parameters_billions: None,
+        }
+    }
+}
+
+/// CPUコア1つあたりに許すパラメータ数 (10億単位)
+const BILLIONS_PER_CPU_CORE: f64 = 2.0;
+
+/// モデル選択に使うハードウェアの情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct HardwareInfo {
+    pub total_memory_bytes: u64,
+    pub available_memory_bytes: u64,
+    pub cpu_cores: usize,
+    pub cpu_model: Option<String>,
+}
+
+impl HardwareInfo {
+    /// `/proc/meminfo` と `/proc/cpuinfo` から読み取る
+    pub fn detect() -> Result<Self> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo")
+            .map_err(|e| anyhow!("/proc/meminfo を読み込めません: {}", e))?;
+        let (total_memory_bytes, available_memory_bytes) = parse_meminfo(&meminfo)?;
+        // /proc/cpuinfo が読めない環境では標準ライブラリの値を使う
+        let (cpu_cores, cpu_model) = std::fs::read_to_string("/proc/cpuinfo")
+            .ok()
+            .map(|cpuinfo| parse_cpuinfo(&cpuinfo))
+            .filter(|(cores, _)| *cores > 0)
+            .unwrap_or_else(|| {
+                let cores = std::thread::available_parallelism()
+                    .map(|cores| cores.get())
+                    .unwrap_or(1);
+                (cores, None)
+            });
+        Ok(HardwareInfo {
+            total_memory_bytes,
+            available_memory_bytes,
+            cpu_cores,
+            cpu_model,
+        })
+    }
+}
+
+/// `/proc/meminfo` の内容から (MemTotal, MemAvailable) をバイト単位で取り出す
+fn parse_meminfo(content: &str) -> Result<(u64, u64)> {
+    let field = |name: &str| {
+        content
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+            .map(|kib| kib * 1024)
+    };
+    let total = field("MemTotal").ok_or_else(|| anyhow!("MemTotal が見つかりません。"))?;
+    // 古いカーネルには MemAvailable が無いので、MemFree とキャッシュから概算する
+    let available = field("MemAvailable")
+        .or_else(|| Some(field("MemFree")? + field("Cached").unwrap_or(0)))
+        .ok_or_else(|| anyhow!("MemAvailable が見つかりません。"))?;
+    Ok((total, available))
+}
+
+/// `/proc/cpuinfo` の内容から (論理CPU数, CPUのモデル名) を取り出す
+fn parse_cpuinfo(content: &str) -> (usize, Option<String>) {
+    let value = |line: &str, name: &str| -> Option<String> {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == name).then(|| value.trim().to_string())
+    };
+    let cores = content
+        .lines()
+        .filter(|line| value(line, "processor").is_some())
+        .count();
+    let model = content
+        .lines()
+        .find_map(|line| value(line, "model name"))
+        .filter(|model| !model.is_empty());
+    (cores, model)
+}
+
+/// 選択の候補となるローカルのモデル
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelCandidate {
+    pub name: String,
+    pub size_bytes: u64,
+    pub families: Vec<String>,
+    pub parameters_billions: Option<f64>,
+    pub quantization: Option<String>,
+}
+
+impl ModelCandidate {
+    /// `/api/tags` の1項目と、取得できれば `/api/show` の結果から作る
+    fn from_metadata(tag: &Value, show: Option<&Value>) -> Option<Self> {
+        let name = tag["name"].as_str()?.to_string();
+        let size_bytes = tag["size"].as_u64()?;
+        let details = show
+            .map(|show| &show["details"])
+            .filter(|details| details.is_object())
+            .unwrap_or(&tag["details"]);
+
+        let mut families: Vec<String> = details["families"]
+            .as_array()
+            .map(|families| {
+                families
+                    .iter()
+                    .filter_map(|family| family.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(family) = details["family"].as_str()
+            && !families.iter().any(|known| known == family)
+        {
+            families.insert(0, family.to_string());
+        }
+
+        // 正確な値は model_info にあり、無ければ "8.0B" のような表記から読む
+        let parameters_billions = show
+            .and_then(|show| show["model_info"]["general.parameter_count"].as_u64())
+            .map(|count| count as f64 / 1e9)
+            .or_else(|| {
+                details["parameter_size"]
+                    .as_str()
+                    .and_then(parse_parameter_size)
+            });
+
+        Some(ModelCandidate {
+            name,
+            size_bytes,
+            families,
+            parameters_billions,
+            quantization: details["quantization_level"].as_str().map(str::to_string),
+        })
+    }
+
+    /// モデル名の `:` より前の部分 (例: `llama3.1:8b` → `llama3.1`)
+    fn base_name(&self) -> &str {
+        self.name.split(':').next().unwrap_or(&self.name)
+    }
+
+    fn matches_family(&self, family: &str) -> bool {
+        self.base_name().eq_ignore_ascii_case(family)
+            || self
+                .families
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(family))
+    }
+}
+
+/// "8.0B" や "137M" のようなパラメータ数の表記を10億単位の数値にする
+fn parse_parameter_size(size: &str) -> Option<f64> {
+    let size = size.trim();
+    let (number, scale) = match size.chars().last()?.to_ascii_uppercase() {
+        'T' => (&size[..size.len() - 1], 1e3),
+        'B' => (&size[..size.len() - 1], 1.0),
+        'M' => (&size[..size.len() - 1], 1e-3),
+        'K' => (&size[..size.len() - 1], 1e-6),
+        _ => (size, 1e-9),
+    };
+    number.trim().parse::<f64>().ok().map(|value| value * scale)
+}
+
+/// 選択の結果と、その理由の説明
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelChoice {
+    pub model: String,
+    pub explanation: Vec<String>,
+}
+
+/// ハードウェアと設定に従って候補から1つ選ぶ。
+/// メモリとパラメータ数の上限に収まるモデルを、ファミリー、量子化の優先順、パラメータ数の多さの順に比べる。
+/// 収まるモデルが無い場合は最も小さいモデルを選ぶ
+pub fn choose_model(
+    candidates: &[ModelCandidate],
+    hardware: &HardwareInfo,
+    config: &ModelSelectionConfig,
+) -> Option<ModelChoice> {
+    let memory_budget =
+        (hardware.available_memory_bytes as f64 * config.memory_fraction.clamp(0.0, 1.0)) as u64;
+    let max_parameters = config
+        .max_parameters_billions
+        .unwrap_or(hardware.cpu_cores as f64 * BILLIONS_PER_CPU_CORE);
+
+    let mut explanation = vec![
+        format!(
+            "ハードウェア: 空きメモリ {:.1} GB / 合計 {:.1} GB, CPU {} コア{}",
+            hardware.available_memory_bytes as f64 / GIB,
+            hardware.total_memory_bytes as f64 / GIB,
+            hardware.cpu_cores,
+            hardware
+                .cpu_model
+                .as_ref()
+                .map(|model| format!(" ({})", model))
+                .unwrap_or_default()
+        ),
+        format!(
+            "上限: メモリ {:.1} GB (空きメモリの {:.0}%), パラメータ数 {:.1}B{}",
+            memory_budget as f64 / GIB,
+            config.memory_fraction * 100.0,
+            max_parameters,
+            if config.max_parameters_billions.is_some() {
+                ""
+            } else {
+                " (CPUコア数から算出)"
+            }
+        ),
+    ];
+
+    let rank = |preferences: &[String], matches: &dyn Fn(&str) -> bool| {
+        preferences
+            .iter()
+            .position(|preference| matches(preference))
+            .unwrap_or(preferences.len())
+    };
+
+    let mut fitting = Vec::new();
+    for candidate in candidates {
+        let parameters = candidate
+            .parameters_billions
+            .map(|billions| format!("{:.1}B", billions))
+            .unwrap_or_else(|| "不明".to_string());
+        let summary = format!(
+            "{} ({:.1} GB, {}, {})",
+            candidate.name,
+            candidate.size_bytes as f64 / GIB,
+            parameters,
+            candidate.quantization.as_deref().unwrap_or("量子化不明")
+        );
+        if candidate.size_bytes > memory_budget {
+            explanation.push(format!("  除外: {} メモリの上限を超えています", summary));
+        } else if candidate
+            .parameters_billions
+            .is_some_and(|billions| billions > max_parameters)
+        {
+            explanation.push(format!(
+                "  除外: {} パラメータ数の上限を超えています",
+                summary
+            ));
+        } else {
+            explanation.push(format!("  候補: {}", summary));
+            fitting.push(candidate);
+        }
+    }
+
+    let key = |candidate: &&ModelCandidate| {
+        let family_rank = rank(&config.preferred_families, &|family| {
+            candidate.matches_family(family)
+        });
+        let quantization_rank = rank(&config.preferred_quantizations, &|quantization| {
+            candidate
+                .quantization
+                .as_deref()
+                .is_some_and(|known| known.eq_ignore_ascii_case(quantization))
+        });
+        (family_rank, quantization_rank)
+    };
+    // 同じ優先度なら、上限に収まる範囲で大きいモデルの方が性能が良い
+    let chosen = fitting.iter().min_by(|a, b| {
+        key(a).cmp(&key(b)).then_with(|| {
+            let parameters =
+                |candidate: &&&ModelCandidate| candidate.parameters_billions.unwrap_or(0.0);
+            parameters(b)
+                .total_cmp(&parameters(a))
+                .then(b.size_bytes.cmp(&a.size_bytes))
+        })
+    });
+
+    let model = match chosen {
+        Some(candidate) => {
+            let (family_rank, quantization_rank) = key(candidate);
+            let mut reasons = Vec::new();
+            if let Some(family) = config.preferred_families.get(family_rank) {
+                reasons.push(format!("優先ファミリー '{}'", family));
+            }
+            if let Some(quantization) = config.preferred_quantizations.get(quantization_rank) {
+                reasons.push(format!("優先量子化 '{}'", quantization));
+            }
+            reasons.push("同じ優先度の中で最も大きいモデル".to_string());
+            explanation.push(format!("選択: {} ({})", candidate.name, reasons.join(", ")));
+            candidate.name.clone()
+        }
+        None => {
+            let smallest = candidates
+                .iter()
+                .min_by_key(|candidate| candidate.size_bytes)?;
+            explanation.push(format!(
+                "選択: {} (上限に収まるモデルが無いため、最も小さいモデル)",
+                smallest.name
+            ));
+            smallest.name.clone()
+        }
+    };
+    Some(ModelChoice { model, explanation })
+}
+
+/// ローカルのOllamaモデルの一覧とメタデータを取得し、設定に従って既定のモデルを選ぶ
+pub async fn select_ollama_model(
+    base_url: &str,
+    hardware: &HardwareInfo,
+    config: &ModelSelectionConfig,
+) -> Result<ModelChoice> {
+    let api = OllamaApi::new(base_url.to_string(), String::new());
+    let tags = api.list_models().await?;
+    let models = tags["models"]
+        .as_array()
+        .ok_or_else(|| anyhow!("モデルリストが予期しない形式です。"))?;
+
+    let mut candidates = Vec::new();
+    for tag in models {
+        let Some(name) = tag["name"].as_str() else {
+            continue;
+        };
+        // `/api/show` が失敗しても `/api/tags` の情報だけで判断する
+        let show = api.show_model(name).await.ok();
+        candidates.extend(ModelCandidate::from_metadata(tag, show.as_ref()));
+    }
+
+    choose_model(&candidates, hardware, config)
+        .ok_or_else(|| anyhow!("利用可能なOllamaモデルが見つかりませんでした。"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(name: &str, gib: f64, billions: f64, quantization: &str) -> ModelCandidate {
+        ModelCandidate {
+            name: name.to_string(),
+            size_bytes: (gib * GIB) as u64,
+            families: Vec::new(),
+            parameters_billions: Some(billions),
+            quantization: Some(quantization.to_string()),
+        }
+    }
+
+    fn hardware(available_gib: f64, cpu_cores: usize) -> HardwareInfo {
+        HardwareInfo {
+            total_memory_bytes: (available_gib * 2.0 * GIB) as u64,
+            available_memory_bytes: (available_gib * GIB) as u64,
+            cpu_cores,
+            cpu_model: None,
+        }
+    }
+
+    #[test]
+    fn parses_proc_files() {
+        let meminfo = "MemTotal:       16303428 kB\nMemFree:         1210668 kB\nMemAvailable:    9876544 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo).unwrap(),
+            (16303428 * 1024, 9876544 * 1024)
+        );
+        let cpuinfo = "processor\t: 0\nmodel name\t: Example CPU\n\nprocessor\t: 1\nmodel name\t: Example CPU\n";
+        assert_eq!(parse_cpuinfo(cpuinfo), (2, Some("Example CPU".to_string())));
+        assert_eq!(parse_parameter_size("8.0B"), Some(8.0));
+        assert_eq!(parse_parameter_size("137M"), Some(0.137));
+    }
+
+    #[test]
+    fn prefers_families_then_quantization_within_limits() {
+        let candidates = vec![
+            candidate("qwen3:4b", 2.5, 4.0, "Q4_K_M"),
+            candidate("gemma3:4b-q8", 4.5, 4.0, "Q8_0"),
+            candidate("gemma3:4b", 3.3, 4.0, "Q4_K_M"),
+            candidate("llama3.1:70b", 40.0, 70.0, "Q4_K_M"),
+        ];
+        let mut config = ModelSelectionConfig {
+            preferred_quantizations: vec!["Q4_K_M".to_string()],
+            ..Default::default()
+        };
+        // llama3.1:70b はメモリにもパラメータ数の上限にも収まらない
+        let choice = choose_model(&candidates, &hardware(16.0, 8), &config).unwrap();
+        assert_eq!(choice.model, "gemma3:4b");
+
+        config.preferred_families = vec!["qwen3".to_string()];
+        let choice = choose_model(&candidates, &hardware(16.0, 8), &config).unwrap();
+        assert_eq!(choice.model, "qwen3:4b");
+    }
+
+    #[test]
+    fn limits_parameters_by_cpu_cores_and_falls_back_to_smallest() {
+        let candidates = vec![
+            candidate("llama3.1:8b", 4.9, 8.0, "Q4_K_M"),
+            candidate("gemma3:1b", 0.8, 1.0, "Q4_K_M"),
+        ];
+        let config = ModelSelectionConfig::default();
+        // 2コアでは 4B までに制限される
+        let choice = choose_model(&candidates, &hardware(32.0, 2), &config).unwrap();
+        assert_eq!(choice.model, "gemma3:1b");
+        assert!(
+            choice
+                .explanation
+                .iter()
+                .any(|line| line.contains("除外: llama3.1:8b"))
+        );
+
+        let choice = choose_model(&candidates, &hardware(1.0, 2), &config).unwrap();
+        assert_eq!(choice.model, "gemma3:1b");
+        assert!(
+            choice
+                .explanation
+                .last()
+                .unwrap()
+                .contains("最も小さいモデル")
+        );
+    }
+}